@@ -0,0 +1,524 @@
+use super::codec::{read_var_bytes, write_var_bytes, CompactSize};
+use super::error::HandshakeError;
+use super::handshake::{HandshakeOutcome, HandshakeState};
+use super::messages::{NetworkMessage, Serializable};
+use super::network::TimestampedNetAddr;
+use super::services::ServiceFlags;
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+use sha3::{Digest, Sha3_256};
+use std::fmt;
+use std::io::{Cursor, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+// Most addresses an addr or addrv2 message may carry, same limit as Bitcoin Core
+pub const MAX_ADDR_TO_SEND: usize = 1000;
+// Longest address an addrv2 entry may carry, see BIP155
+pub const MAX_ADDRV2_SIZE: usize = 512;
+
+// Network IDs of the addresses of an addrv2 message, see BIP155
+pub const NETWORK_IPV4: u8 = 0x01;
+pub const NETWORK_IPV6: u8 = 0x02;
+pub const NETWORK_TORV3: u8 = 0x04;
+pub const NETWORK_I2P: u8 = 0x05;
+pub const NETWORK_CJDNS: u8 = 0x06;
+
+// Version byte of Tor v3 onion addresses
+const TORV3_VERSION: u8 = 0x03;
+// Lowercase alphabet of the base32 encoding of RFC 4648, used by onion and I2P names
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Address of a node on any network of BIP155
+/// More information https://github.com/bitcoin/bips/blob/master/bip-0155.mediawiki
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AddrV2 {
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+    // Ed25519 public key of a Tor v3 hidden service
+    TorV3([u8; 32]),
+    // SHA256 hash of an I2P destination
+    I2p([u8; 32]),
+    // CJDNS address, an IPv6 address in fc00::/8
+    Cjdns(Ipv6Addr),
+    // Address of a network this crate does not know, kept as received
+    Unknown { network_id: u8, bytes: Vec<u8> },
+}
+
+impl AddrV2 {
+    /// BIP155 network ID of the address
+    pub fn network_id(&self) -> u8 {
+        match self {
+            AddrV2::Ipv4(_) => NETWORK_IPV4,
+            AddrV2::Ipv6(_) => NETWORK_IPV6,
+            AddrV2::TorV3(_) => NETWORK_TORV3,
+            AddrV2::I2p(_) => NETWORK_I2P,
+            AddrV2::Cjdns(_) => NETWORK_CJDNS,
+            AddrV2::Unknown { network_id, .. } => *network_id,
+        }
+    }
+
+    /// IP address, for the networks reachable over IP
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            AddrV2::Ipv4(ip) => Some(IpAddr::V4(*ip)),
+            AddrV2::Ipv6(ip) => Some(IpAddr::V6(*ip)),
+            _ => None,
+        }
+    }
+
+    /// Write the network ID followed by the address bytes
+    pub fn encode<W: Write>(&self, writer: &mut W) -> Result<(), HandshakeError> {
+        writer.write_u8(self.network_id())?;
+        match self {
+            AddrV2::Ipv4(ip) => write_var_bytes(writer, &ip.octets()),
+            AddrV2::Ipv6(ip) | AddrV2::Cjdns(ip) => write_var_bytes(writer, &ip.octets()),
+            AddrV2::TorV3(key) | AddrV2::I2p(key) => write_var_bytes(writer, key),
+            AddrV2::Unknown { bytes, .. } => write_var_bytes(writer, bytes),
+        }
+    }
+
+    /// Read an address, its length must match the one of its network
+    pub fn decode<R: Read>(reader: &mut R) -> Result<Self, HandshakeError> {
+        let network_id = reader.read_u8()?;
+        let bytes = read_var_bytes(reader, MAX_ADDRV2_SIZE)?;
+        Ok(match network_id {
+            NETWORK_IPV4 => AddrV2::Ipv4(fixed_length::<4>(&bytes)?.into()),
+            NETWORK_IPV6 => AddrV2::Ipv6(fixed_length::<16>(&bytes)?.into()),
+            NETWORK_TORV3 => AddrV2::TorV3(fixed_length(&bytes)?),
+            NETWORK_I2P => AddrV2::I2p(fixed_length(&bytes)?),
+            NETWORK_CJDNS => {
+                let ip = Ipv6Addr::from(fixed_length::<16>(&bytes)?);
+                if ip.octets()[0] != 0xfc {
+                    return Err(HandshakeError::InvalidMessage("Invalid CJDNS address"));
+                }
+                AddrV2::Cjdns(ip)
+            }
+            network_id => AddrV2::Unknown { network_id, bytes },
+        })
+    }
+}
+
+impl From<IpAddr> for AddrV2 {
+    fn from(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => AddrV2::Ipv4(ip),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => AddrV2::Ipv4(ip),
+                None => AddrV2::Ipv6(ip),
+            },
+        }
+    }
+}
+
+impl fmt::Display for AddrV2 {
+    /// Usual text form of the address: IP, `.onion` or `.b32.i2p` name
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddrV2::Ipv4(ip) => write!(f, "{ip}"),
+            AddrV2::Ipv6(ip) | AddrV2::Cjdns(ip) => write!(f, "{ip}"),
+            AddrV2::TorV3(key) => {
+                let mut name = key.to_vec();
+                name.extend_from_slice(&torv3_checksum(key));
+                name.push(TORV3_VERSION);
+                write!(f, "{}.onion", base32_encode(&name))
+            }
+            AddrV2::I2p(hash) => write!(f, "{}.b32.i2p", base32_encode(hash)),
+            AddrV2::Unknown { network_id, bytes } => {
+                write!(f, "unknown-{network_id}:{}", hex::encode(bytes))
+            }
+        }
+    }
+}
+
+impl FromStr for AddrV2 {
+    type Err = HandshakeError;
+
+    /// Parse an IP address, a Tor v3 `.onion` name or an I2P `.b32.i2p` name
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || HandshakeError::InvalidMessage("Invalid address");
+        if let Some(name) = value.strip_suffix(".onion") {
+            let bytes = base32_decode(name).ok_or_else(invalid)?;
+            let (key, rest) = bytes.split_at_checked(32).ok_or_else(invalid)?;
+            let key: [u8; 32] = key.try_into().map_err(|_| invalid())?;
+            if rest.len() != 3 || rest[2] != TORV3_VERSION || rest[..2] != torv3_checksum(&key) {
+                return Err(invalid());
+            }
+            return Ok(AddrV2::TorV3(key));
+        }
+        if let Some(name) = value.strip_suffix(".b32.i2p") {
+            let bytes = base32_decode(name).ok_or_else(invalid)?;
+            return Ok(AddrV2::I2p(bytes.try_into().map_err(|_| invalid())?));
+        }
+        value
+            .parse::<IpAddr>()
+            .map(AddrV2::from)
+            .map_err(|_| invalid())
+    }
+}
+
+/// Address of a node with its services and the last time it was seen, as carried
+/// by addrv2 messages
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddrV2Entry {
+    // UNIX timestamp in seconds of the last time the node was seen
+    pub time: u32,
+    // Services supported by the node
+    pub services: ServiceFlags,
+    // Address of the node on its network
+    pub addr: AddrV2,
+    // Port the node listens on, 0 on networks without ports such as I2P
+    pub port: u16,
+}
+
+impl AddrV2Entry {
+    /// Write the entry, services being a CompactSize unlike in addr messages
+    pub fn encode<W: Write>(&self, writer: &mut W) -> Result<(), HandshakeError> {
+        writer.write_u32::<LittleEndian>(self.time)?;
+        CompactSize(self.services.bits()).encode(writer)?;
+        self.addr.encode(writer)?;
+        writer.write_u16::<BigEndian>(self.port)?;
+        Ok(())
+    }
+
+    pub fn decode<R: Read>(reader: &mut R) -> Result<Self, HandshakeError> {
+        let time = reader.read_u32::<LittleEndian>()?;
+        let services = ServiceFlags::from_bits_retain(CompactSize::decode(reader)?.0);
+        let addr = AddrV2::decode(reader)?;
+        let port = reader.read_u16::<BigEndian>()?;
+        Ok(Self {
+            time,
+            services,
+            addr,
+            port,
+        })
+    }
+
+    /// Socket address of the node, for the networks reachable over IP
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        self.addr.ip().map(|ip| SocketAddr::new(ip, self.port))
+    }
+}
+
+impl From<TimestampedNetAddr> for AddrV2Entry {
+    fn from(addr: TimestampedNetAddr) -> Self {
+        Self {
+            time: addr.time,
+            services: addr.addr.services,
+            addr: addr.addr.ip.into(),
+            port: addr.addr.port,
+        }
+    }
+}
+
+impl fmt::Display for AddrV2Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.addr {
+            AddrV2::Ipv6(_) | AddrV2::Cjdns(_) => write!(f, "[{}]:{}", self.addr, self.port),
+            _ => write!(f, "{}:{}", self.addr, self.port),
+        }
+    }
+}
+
+/// Addresses of other nodes, answering a getaddr or relayed unsolicited
+/// More information https://en.bitcoin.it/wiki/Protocol_documentation#addr
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddrMessage {
+    pub addresses: Vec<TimestampedNetAddr>,
+}
+
+impl Serializable for AddrMessage {
+    fn serialize(&self) -> Result<Vec<u8>, HandshakeError> {
+        let mut message = Vec::new();
+        CompactSize(self.addresses.len() as u64).encode(&mut message)?;
+        for addr in &self.addresses {
+            addr.encode(&mut message)?;
+        }
+        Ok(message)
+    }
+
+    fn deserialize(msg: Vec<u8>) -> Result<Box<Self>, HandshakeError> {
+        let mut cursor = Cursor::new(msg);
+        let count = CompactSize::decode_length(&mut cursor, MAX_ADDR_TO_SEND as u64)?;
+        let addresses = (0..count)
+            .map(|_| TimestampedNetAddr::decode(&mut cursor))
+            .collect::<Result<_, _>>()?;
+        Ok(Box::new(Self { addresses }))
+    }
+}
+
+/// Addresses of other nodes on any network of BIP155
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddrV2Message {
+    pub addresses: Vec<AddrV2Entry>,
+}
+
+impl Serializable for AddrV2Message {
+    fn serialize(&self) -> Result<Vec<u8>, HandshakeError> {
+        let mut message = Vec::new();
+        CompactSize(self.addresses.len() as u64).encode(&mut message)?;
+        for addr in &self.addresses {
+            addr.encode(&mut message)?;
+        }
+        Ok(message)
+    }
+
+    fn deserialize(msg: Vec<u8>) -> Result<Box<Self>, HandshakeError> {
+        let mut cursor = Cursor::new(msg);
+        let count = CompactSize::decode_length(&mut cursor, MAX_ADDR_TO_SEND as u64)?;
+        let addresses = (0..count)
+            .map(|_| AddrV2Entry::decode(&mut cursor))
+            .collect::<Result<_, _>>()?;
+        Ok(Box::new(Self { addresses }))
+    }
+}
+
+impl<S> HandshakeOutcome<S> {
+    /// Whether addresses received are the remote node announcing itself rather than the
+    /// answer to getaddr, as Bitcoin Core does on its own shortly after the handshake
+    pub(crate) fn is_self_announcement(&self, addresses: &[AddrV2Entry]) -> bool {
+        let [entry] = addresses else {
+            return false;
+        };
+        [
+            self.remote_addr().ip(),
+            self.peer_version().sender().socket_addr().ip(),
+        ]
+        .into_iter()
+        .any(|ip| AddrV2::from(ip) == entry.addr)
+    }
+}
+
+impl HandshakeOutcome<TcpStream> {
+    /// Ask the remote node for the addresses it knows and wait for its answer, skipping
+    /// other messages and the remote node announcing its own address. Bitcoin Core
+    /// answers a single getaddr per connection, and only to nodes that connected to it
+    pub fn request_addresses(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Vec<AddrV2Entry>, HandshakeError> {
+        let stage = HandshakeState::Established;
+        let deadline = Instant::now() + timeout;
+        self.send(&NetworkMessage::GetAddr)
+            .map_err(|e| e.at_stage(stage))?;
+        loop {
+            let addresses: Vec<AddrV2Entry> = match self.receive_until(deadline)? {
+                NetworkMessage::Addr(addr) => addr.addresses.into_iter().map(Into::into).collect(),
+                NetworkMessage::AddrV2(addr) => addr.addresses,
+                _ => continue,
+            };
+            if !self.is_self_announcement(&addresses) {
+                return Ok(addresses);
+            }
+        }
+    }
+}
+
+/// Address bytes of a network whose addresses have a fixed length
+fn fixed_length<const N: usize>(bytes: &[u8]) -> Result<[u8; N], HandshakeError> {
+    bytes
+        .try_into()
+        .map_err(|_| HandshakeError::InvalidMessage("Invalid address length"))
+}
+
+/// Checksum of a Tor v3 onion name, see Tor's rend-spec-v3
+fn torv3_checksum(key: &[u8; 32]) -> [u8; 2] {
+    let mut data = b".onion checksum".to_vec();
+    data.extend_from_slice(key);
+    data.push(TORV3_VERSION);
+    let digest = Sha3_256::digest(&data);
+    [digest[0], digest[1]]
+}
+
+/// Base32 of RFC 4648 in lowercase and without padding
+fn base32_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(5) * 8);
+    let (mut buffer, mut bits) = (0u32, 0u32);
+    for &byte in data {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+        buffer &= (1 << bits) - 1;
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    encoded
+}
+
+/// Decode base32 without padding, in any case
+/// Leftover bits must be zero so that every name has a single form
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0u32);
+    for c in encoded.bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|&b| b == c.to_ascii_lowercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+        buffer &= (1 << bits) - 1;
+    }
+    (bits < 5 && buffer == 0).then_some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::NetAddr;
+
+    // Tor v3 address and its public key, from Bitcoin Core's net tests
+    const ONION: &str = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion";
+    const ONION_KEY: &str = "79bcc625184b05194975c28b66b66b0469f7f6556fb1ac3189a79b40dda32f1f";
+    // I2P address and its destination hash, from Bitcoin Core's net tests
+    const I2P: &str = "ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnkdq.b32.i2p";
+    const I2P_HASH: &str = "a2894dabaec08c0051a481a6dac88b64f98232ae42d4b6fd2fa81952dfe36a87";
+
+    fn bytes32(hex_bytes: &str) -> [u8; 32] {
+        hex::decode(hex_bytes)
+            .expect("Valid hex")
+            .try_into()
+            .expect("32 bytes")
+    }
+
+    #[test]
+    fn test_addrv2_names_ok() {
+        let onion: AddrV2 = ONION.parse().expect("Failed to parse onion address");
+        assert_eq!(onion, AddrV2::TorV3(bytes32(ONION_KEY)));
+        assert_eq!(onion.to_string(), ONION);
+        assert_eq!(
+            ONION
+                .to_uppercase()
+                .replace(".ONION", ".onion")
+                .parse()
+                .ok(),
+            Some(onion)
+        );
+
+        let i2p: AddrV2 = I2P.parse().expect("Failed to parse I2P address");
+        assert_eq!(i2p, AddrV2::I2p(bytes32(I2P_HASH)));
+        assert_eq!(i2p.to_string(), I2P);
+
+        assert_eq!(
+            "::ffff:1.2.3.4".parse().ok(),
+            Some(AddrV2::Ipv4(Ipv4Addr::new(1, 2, 3, 4)))
+        );
+    }
+
+    #[test]
+    fn test_addrv2_invalid_names_error() {
+        // Wrong checksum
+        let tampered = ONION.replacen('p', "q", 1);
+        assert!(tampered.parse::<AddrV2>().is_err());
+        // Wrong length
+        assert!("abcd.onion".parse::<AddrV2>().is_err());
+        assert!("ukeu3k5oycgaauneqgtnvselmt4yemvo.b32.i2p"
+            .parse::<AddrV2>()
+            .is_err());
+        // Non zero leftover bits
+        let i2p = I2P.replacen("dq.", "dr.", 1);
+        assert!(i2p.parse::<AddrV2>().is_err());
+    }
+
+    #[test]
+    fn test_addrv2_entry_round_trip_ok() {
+        let cjdns = Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 1);
+        for (addr, port) in [
+            (AddrV2::Ipv4(Ipv4Addr::new(1, 2, 3, 4)), 8333),
+            (AddrV2::Ipv6("2001:db8::1".parse().unwrap()), 8333),
+            (AddrV2::TorV3(bytes32(ONION_KEY)), 8333),
+            (AddrV2::I2p(bytes32(I2P_HASH)), 0),
+            (AddrV2::Cjdns(cjdns), 8333),
+            (
+                AddrV2::Unknown {
+                    network_id: 0x42,
+                    bytes: vec![1, 2, 3],
+                },
+                8333,
+            ),
+        ] {
+            let entry = AddrV2Entry {
+                time: 1_700_000_000,
+                services: ServiceFlags::NODE_NETWORK | ServiceFlags::NODE_WITNESS,
+                addr,
+                port,
+            };
+            let message = AddrV2Message {
+                addresses: vec![entry],
+            };
+            let bytes = message.serialize().expect("Failed to serialize addrv2");
+            let decoded = AddrV2Message::deserialize(bytes).expect("Failed to decode addrv2");
+            assert_eq!(*decoded, message);
+        }
+    }
+
+    #[test]
+    fn test_addrv2_entry_encoding_ok() {
+        // Entry of BIP155's test vectors: 1.2.3.4:8333 with NODE_NETWORK | NODE_WITNESS
+        let entry = AddrV2Entry {
+            time: 0x5f45_b2c6,
+            services: ServiceFlags::from_bits_retain(9),
+            addr: AddrV2::Ipv4(Ipv4Addr::new(1, 2, 3, 4)),
+            port: 8333,
+        };
+        let mut bytes = Vec::new();
+        entry.encode(&mut bytes).expect("Failed to encode entry");
+        assert_eq!(hex::encode(&bytes), "c6b2455f09010401020304208d");
+    }
+
+    #[test]
+    fn test_addrv2_wrong_length_error() {
+        // IPv4 address announced with 5 bytes
+        let bytes = [NETWORK_IPV4, 5, 1, 2, 3, 4, 5];
+        assert!(AddrV2::decode(&mut Cursor::new(bytes)).is_err());
+        // Tor v3 key announced with 31 bytes
+        let mut bytes = vec![NETWORK_TORV3, 31];
+        bytes.extend([0u8; 31]);
+        assert!(AddrV2::decode(&mut Cursor::new(bytes)).is_err());
+        // CJDNS addresses must be in fc00::/8
+        let mut bytes = vec![NETWORK_CJDNS, 16];
+        bytes.extend([0u8; 16]);
+        assert!(AddrV2::decode(&mut Cursor::new(bytes)).is_err());
+    }
+
+    #[test]
+    fn test_addr_message_round_trip_ok() {
+        let addr = TimestampedNetAddr {
+            time: 1_700_000_000,
+            addr: NetAddr::new(
+                ServiceFlags::NODE_NETWORK,
+                "10.0.0.1:8333".parse().expect("Valid socket address"),
+            ),
+        };
+        let message = AddrMessage {
+            addresses: vec![addr; 3],
+        };
+        let bytes = message.serialize().expect("Failed to serialize addr");
+        assert_eq!(bytes.len(), 1 + 3 * TimestampedNetAddr::ENCODED_SIZE);
+        let decoded = AddrMessage::deserialize(bytes).expect("Failed to decode addr");
+        assert_eq!(*decoded, message);
+        assert_eq!(
+            AddrV2Entry::from(addr).to_string(),
+            "10.0.0.1:8333".to_string()
+        );
+    }
+
+    #[test]
+    fn test_addr_message_too_many_error() {
+        let mut bytes = Vec::new();
+        CompactSize(MAX_ADDR_TO_SEND as u64 + 1)
+            .encode(&mut bytes)
+            .expect("Failed to encode count");
+        assert!(matches!(
+            AddrMessage::deserialize(bytes),
+            Err(HandshakeError::FieldTooLong { .. })
+        ));
+    }
+}
@@ -0,0 +1,476 @@
+use super::addr::AddrV2Entry;
+use super::block::{block_getdata, Block};
+use super::broadcast::{Broadcast, BroadcastReport};
+use super::chain::HeaderChain;
+use super::error::HandshakeError;
+use super::framing::MessageHeader;
+use super::handshake::{
+    Handshake, HandshakeConfig, HandshakeOutcome, HandshakeState, MESSAGE_TIMEOUT,
+};
+use super::headers::{hash_to_hex, MAX_HEADERS_RESULTS};
+use super::keepalive::{Keepalive, LatencyStats};
+use super::messages::{
+    BitcoinMessage, NetworkMessage, Serializable, HEADER_SIZE, MAX_PAYLOAD_SIZE,
+};
+use super::network::BitcoinNetwork;
+use super::ping::PongMessage;
+use super::transaction::Transaction;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, timeout_at};
+
+/// Async counterpart of `MessageReader` for any tokio byte source
+#[derive(Debug)]
+pub struct AsyncMessageReader<R: AsyncRead + Unpin> {
+    // Byte source holding the messages, usually a TCP stream
+    reader: R,
+    // Network the messages must belong to
+    network: BitcoinNetwork,
+    // Largest payload accepted from the byte source
+    max_payload_size: u32,
+}
+
+impl<R: AsyncRead + Unpin> AsyncMessageReader<R> {
+    /// Create a reader accepting payloads up to the protocol's maximum size
+    pub fn new(reader: R, network: BitcoinNetwork) -> Self {
+        Self {
+            reader,
+            network,
+            max_payload_size: MAX_PAYLOAD_SIZE,
+        }
+    }
+
+    /// Lower or raise the largest payload accepted by the reader
+    pub fn with_max_payload_size(mut self, max_payload_size: u32) -> Self {
+        self.max_payload_size = max_payload_size;
+        self
+    }
+
+    /// Read the next whole message, header first then exactly its payload
+    pub async fn read_message(&mut self) -> Result<BitcoinMessage, HandshakeError> {
+        let mut header = [0u8; HEADER_SIZE];
+        self.reader.read_exact(&mut header).await?;
+        let header = MessageHeader::parse(&header, self.network, self.max_payload_size)?;
+
+        let mut payload = vec![0u8; header.length as usize];
+        self.reader.read_exact(&mut payload).await?;
+
+        header.into_message(payload)
+    }
+
+    /// Take back the underlying byte source
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+/// Async counterpart of `MessageWriter` for any tokio byte sink
+#[derive(Debug)]
+pub struct AsyncMessageWriter<W: AsyncWrite + Unpin> {
+    // Byte sink receiving the messages, usually a TCP stream
+    writer: W,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncMessageWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    /// Write a whole message, header and payload, and flush it
+    pub async fn write_message(&mut self, message: &BitcoinMessage) -> Result<(), HandshakeError> {
+        self.writer.write_all(&message.serialize()?).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    /// Take back the underlying byte sink
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl HandshakeOutcome<TcpStream> {
+    /// Async counterpart of `send` over an established tokio connection
+    pub async fn send(&mut self, message: &NetworkMessage) -> Result<(), HandshakeError> {
+        let message = message.to_bitcoin_message(self.network())?;
+        AsyncMessageWriter::new(self.stream())
+            .write_message(&message)
+            .await
+    }
+
+    /// Async counterpart of `receive` over an established tokio connection
+    pub async fn receive(&mut self) -> Result<NetworkMessage, HandshakeError> {
+        let network = self.network();
+        let message = AsyncMessageReader::new(self.stream(), network)
+            .read_message()
+            .await?;
+        let message = NetworkMessage::from_bitcoin_message(&message)?;
+        self.on_received(&message)?;
+        Ok(message)
+    }
+
+    /// Async counterpart of `receive_until`
+    pub async fn receive_until(
+        &mut self,
+        deadline: Instant,
+    ) -> Result<NetworkMessage, HandshakeError> {
+        self.try_receive_until(deadline)
+            .await?
+            .ok_or(HandshakeError::Timeout {
+                stage: HandshakeState::Established,
+            })
+    }
+
+    /// Async counterpart of `try_receive_until`
+    pub(crate) async fn try_receive_until(
+        &mut self,
+        deadline: Instant,
+    ) -> Result<Option<NetworkMessage>, HandshakeError> {
+        let stage = HandshakeState::Established;
+        while self.wait_until(deadline).await? {
+            let message = with_timeout(MESSAGE_TIMEOUT, self.receive())
+                .await
+                .map_err(|e| e.at_stage(stage))?;
+            match message {
+                NetworkMessage::Ping(ping) => {
+                    if let Some(nonce) = ping.nonce() {
+                        self.send(&NetworkMessage::Pong(PongMessage::new(nonce)))
+                            .await
+                            .map_err(|e| e.at_stage(stage))?;
+                    }
+                }
+                message => return Ok(Some(message)),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Async counterpart of `wait_until`
+    pub(crate) async fn wait_until(&mut self, deadline: Instant) -> Result<bool, HandshakeError> {
+        let stage = HandshakeState::Established;
+        if Instant::now() >= deadline {
+            return Ok(false);
+        }
+        // Peeking is cancel safe, unlike reading a message that may be cut in half
+        let deadline = tokio::time::Instant::from_std(deadline);
+        match timeout_at(deadline, self.stream().peek(&mut [0u8; 1])).await {
+            Err(_) => Ok(false),
+            Ok(Ok(0)) => Err(HandshakeError::PeerDisconnected { stage }),
+            Ok(Ok(_)) => Ok(true),
+            Ok(Err(e)) => Err(HandshakeError::from_io(e, stage)),
+        }
+    }
+
+    /// Async counterpart of `keep_alive`, meant to run as its own task
+    pub async fn keep_alive(
+        &mut self,
+        keepalive: &mut Keepalive,
+        mut on_message: impl FnMut(&NetworkMessage, &LatencyStats) -> bool,
+    ) -> Result<(), HandshakeError> {
+        let stage = HandshakeState::Established;
+        loop {
+            if let Some(ping) = keepalive.poll(Instant::now())? {
+                self.send(&ping).await.map_err(|e| e.at_stage(stage))?;
+            }
+
+            // Time to ping again or to give up on a ping before anything arrived
+            if !self.wait_until(keepalive.deadline()).await? {
+                continue;
+            }
+
+            // The rest of the message must follow within the ping timeout
+            let message = with_timeout(keepalive.config().timeout, self.receive())
+                .await
+                .map_err(|e| e.at_stage(stage))?;
+            if let Some(pong) = keepalive.on_message(&message, Instant::now()) {
+                self.send(&pong).await.map_err(|e| e.at_stage(stage))?;
+            }
+            if !on_message(&message, keepalive.stats()) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Async counterpart of `request_addresses`
+    pub async fn request_addresses(
+        &mut self,
+        duration: Duration,
+    ) -> Result<Vec<AddrV2Entry>, HandshakeError> {
+        let stage = HandshakeState::Established;
+        let deadline = Instant::now() + duration;
+        self.send(&NetworkMessage::GetAddr)
+            .await
+            .map_err(|e| e.at_stage(stage))?;
+        loop {
+            let addresses: Vec<AddrV2Entry> = match self.receive_until(deadline).await? {
+                NetworkMessage::Addr(addr) => addr.addresses.into_iter().map(Into::into).collect(),
+                NetworkMessage::AddrV2(addr) => addr.addresses,
+                _ => continue,
+            };
+            if !self.is_self_announcement(&addresses) {
+                return Ok(addresses);
+            }
+        }
+    }
+
+    /// Async counterpart of `sync_headers`
+    pub async fn sync_headers(
+        &mut self,
+        chain: &mut HeaderChain,
+        duration: Duration,
+    ) -> Result<u32, HandshakeError> {
+        let stage = HandshakeState::Established;
+        let start_height = self.peer_version().start_height().max(0) as u32;
+        while chain.height() < start_height {
+            let getheaders = chain.getheaders(self.negotiated_version() as u32);
+            self.send(&NetworkMessage::GetHeaders(getheaders))
+                .await
+                .map_err(|e| e.at_stage(stage))?;
+            let deadline = Instant::now() + duration;
+            let headers = loop {
+                if let NetworkMessage::Headers(headers) = self.receive_until(deadline).await? {
+                    break headers.headers;
+                }
+            };
+            // A partial batch, or one we learnt nothing from, ends the download
+            if chain.connect(&headers)? == 0 || headers.len() < MAX_HEADERS_RESULTS {
+                break;
+            }
+        }
+        Ok(chain.height())
+    }
+
+    /// Async counterpart of `get_block`
+    pub async fn get_block(
+        &mut self,
+        hash: [u8; 32],
+        duration: Duration,
+    ) -> Result<Block, HandshakeError> {
+        let stage = HandshakeState::Established;
+        let deadline = Instant::now() + duration;
+        self.send(&block_getdata(self.peer_version().services(), hash))
+            .await
+            .map_err(|e| e.at_stage(stage))?;
+        loop {
+            match self.receive_until(deadline).await? {
+                NetworkMessage::Block(block) if block.block_hash() == hash => {
+                    block.check()?;
+                    return Ok(block);
+                }
+                NetworkMessage::NotFound(notfound)
+                    if notfound.inventory.iter().any(|inv| *inv.hash() == hash) =>
+                {
+                    return Err(HandshakeError::NotFound(hash_to_hex(&hash)));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Async counterpart of `broadcast`
+    pub async fn broadcast(
+        &mut self,
+        transaction: &Transaction,
+        wait: Duration,
+    ) -> Result<BroadcastReport, HandshakeError> {
+        let stage = HandshakeState::Established;
+        let deadline = Instant::now() + wait;
+        let mut broadcast = Broadcast::new(transaction, self.negotiated().wtxid_relay);
+        self.send(&broadcast.announcement())
+            .await
+            .map_err(|e| e.at_stage(stage))?;
+        while !broadcast.is_settled() {
+            let Some(message) = self.try_receive_until(deadline).await? else {
+                break;
+            };
+            if let Some(reply) = broadcast.on_message(&message) {
+                self.send(&reply).await.map_err(|e| e.at_stage(stage))?;
+            }
+        }
+        Ok(broadcast.into_report())
+    }
+}
+
+/// Async version of `perform_handshake_with_config` running on a tokio runtime
+/// Every stage is bounded by its own timeout from the config
+pub async fn perform_handshake(
+    config: &HandshakeConfig,
+    sender: SocketAddr,
+    receiver: SocketAddr,
+) -> Result<HandshakeOutcome<TcpStream>, HandshakeError> {
+    let mut stream = match timeout(config.timeouts.connect, TcpStream::connect(sender)).await {
+        Ok(stream) => stream.map_err(HandshakeError::ConnectionFailed)?,
+        Err(_) => {
+            return Err(HandshakeError::ConnectionFailed(
+                std::io::ErrorKind::TimedOut.into(),
+            ))
+        }
+    };
+    let local_addr = stream.local_addr()?;
+    let remote_addr = stream.peer_addr()?;
+
+    let mut handshake = Handshake::outbound(config, sender, receiver);
+    {
+        let (read_half, write_half) = stream.split();
+        let mut reader = AsyncMessageReader::new(read_half, config.network);
+        let mut writer = AsyncMessageWriter::new(write_half);
+        let write_timeout = config.timeouts.verack;
+
+        // Send the version message to initialize handshake
+        for message in handshake.start()? {
+            let stage = handshake.state();
+            with_timeout(write_timeout, writer.write_message(&message))
+                .await
+                .map_err(|e| e.at_stage(stage))?;
+        }
+
+        while !handshake.is_established() {
+            let stage = handshake.state();
+            let message = with_timeout(handshake.remaining()?, reader.read_message())
+                .await
+                .map_err(|e| e.at_stage(stage))?;
+            for reply in handshake.on_message(&message)? {
+                with_timeout(write_timeout, writer.write_message(&reply))
+                    .await
+                    .map_err(|e| e.at_stage(stage))?;
+            }
+        }
+    }
+
+    handshake.finish(stream, local_addr, remote_addr)
+}
+
+/// Bound a message operation in time, elapsed time is reported as a timed out I/O error
+async fn with_timeout<T>(
+    duration: Duration,
+    operation: impl Future<Output = Result<T, HandshakeError>>,
+) -> Result<T, HandshakeError> {
+    timeout(duration, operation)
+        .await
+        .unwrap_or_else(|_| Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::HandshakeTimeouts;
+    use crate::keepalive::KeepaliveConfig;
+    use crate::services::ServiceFlags;
+    use crate::vv::{Command, VersionMessage, PROTOCOL_VERSION};
+    use tokio::net::TcpListener;
+
+    // Answer a single handshake on the listener like a well behaved node would
+    async fn answer_handshake(listener: TcpListener, network: BitcoinNetwork) -> TcpStream {
+        let (mut stream, addr) = listener.accept().await.expect("Failed to accept");
+        let (read_half, write_half) = stream.split();
+        let mut reader = AsyncMessageReader::new(read_half, network);
+        let mut writer = AsyncMessageWriter::new(write_half);
+
+        reader.read_message().await.expect("Failed to read version");
+        let version = VersionMessage::new(
+            PROTOCOL_VERSION,
+            addr,
+            addr,
+            ServiceFlags::NODE_NETWORK,
+            "/mock:0.1.0/".to_string(),
+            0,
+            false,
+        )
+        .serialize()
+        .expect("Failed to serialize version");
+        for message in [
+            BitcoinMessage::new(Command::Version, version, network),
+            BitcoinMessage::new(Command::Verack, Vec::new(), network),
+        ] {
+            writer
+                .write_message(&message)
+                .await
+                .expect("Failed to write message");
+        }
+        // Our negotiation messages come before our verack
+        let mut command = Command::SendAddrV2;
+        while command != Command::Verack {
+            let message = reader.read_message().await.expect("Failed to read verack");
+            command = Command::from_fixed_length(message.command()).expect("Valid command");
+        }
+        stream
+    }
+
+    #[tokio::test]
+    async fn test_async_handshake_ok() {
+        let network = BitcoinNetwork::Regtest;
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind");
+        let addr = listener.local_addr().expect("Failed to get address");
+        let peer = tokio::spawn(answer_handshake(listener, network));
+
+        let outcome = perform_handshake(&HandshakeConfig::new(network), addr, addr)
+            .await
+            .expect("Handshake should succeed");
+        assert_eq!(outcome.remote_addr(), addr);
+        peer.await.expect("Mock peer failed");
+    }
+
+    #[tokio::test]
+    async fn test_async_keep_alive_ok() {
+        let network = BitcoinNetwork::Regtest;
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind");
+        let addr = listener.local_addr().expect("Failed to get address");
+        let peer = tokio::spawn(async move {
+            let mut stream = answer_handshake(listener, network).await;
+            let (read_half, write_half) = stream.split();
+            let mut reader = AsyncMessageReader::new(read_half, network);
+            let mut writer = AsyncMessageWriter::new(write_half);
+            let ping = reader.read_message().await.expect("Failed to read ping");
+            let pong = BitcoinMessage::new(Command::Pong, ping.payload().to_vec(), network);
+            writer
+                .write_message(&pong)
+                .await
+                .expect("Failed to write pong");
+        });
+
+        let mut outcome = perform_handshake(&HandshakeConfig::new(network), addr, addr)
+            .await
+            .expect("Handshake should succeed");
+        let mut keepalive = Keepalive::new(KeepaliveConfig::default(), outcome.features());
+        outcome
+            .keep_alive(&mut keepalive, |message, _| {
+                !matches!(message, NetworkMessage::Pong(_))
+            })
+            .await
+            .expect("Keepalive should succeed");
+        assert_eq!(keepalive.stats().pongs_received, 1);
+        peer.await.expect("Mock peer failed");
+    }
+
+    #[tokio::test]
+    async fn test_async_handshake_version_timeout_error() {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind");
+        let addr = listener.local_addr().expect("Failed to get address");
+
+        // Connection is accepted by the backlog but never answered
+        let config = HandshakeConfig {
+            timeouts: HandshakeTimeouts {
+                version: Duration::from_millis(100),
+                ..HandshakeTimeouts::default()
+            },
+            ..HandshakeConfig::new(BitcoinNetwork::Regtest)
+        };
+        let result = perform_handshake(&config, addr, addr).await;
+        assert!(matches!(
+            result,
+            Err(HandshakeError::Timeout {
+                stage: HandshakeState::SentVersion
+            })
+        ));
+        drop(listener);
+    }
+}
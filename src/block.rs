@@ -0,0 +1,333 @@
+use super::codec::CompactSize;
+use super::error::HandshakeError;
+use super::handshake::{HandshakeOutcome, HandshakeState};
+use super::headers::{hash_to_hex, BlockHeader};
+use super::inventory::{Inventory, InventoryMessage};
+use super::messages::{NetworkMessage, Serializable, MAX_PAYLOAD_SIZE};
+use super::services::ServiceFlags;
+use super::transaction::Transaction;
+use super::utils::sha256d;
+use std::io::{Cursor, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+// Start of the coinbase output committing to the witness merkle root, see BIP141
+const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+/// Block with its transactions, the first one being the coinbase
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub header: BlockHeader,
+    pub transactions: Vec<Transaction>,
+}
+
+impl Block {
+    pub fn block_hash(&self) -> [u8; 32] {
+        self.header.block_hash()
+    }
+
+    pub fn encode<W: Write>(
+        &self,
+        writer: &mut W,
+        with_witness: bool,
+    ) -> Result<(), HandshakeError> {
+        self.header.encode(writer)?;
+        CompactSize(self.transactions.len() as u64).encode(writer)?;
+        for transaction in &self.transactions {
+            transaction.encode(writer, with_witness)?;
+        }
+        Ok(())
+    }
+
+    pub fn decode<R: Read>(reader: &mut R) -> Result<Self, HandshakeError> {
+        let header = BlockHeader::decode(reader)?;
+        let count = CompactSize::decode_length(reader, MAX_PAYLOAD_SIZE as u64)?;
+        let mut transactions = Vec::new();
+        for _ in 0..count {
+            transactions.push(Transaction::decode(reader)?);
+        }
+        Ok(Self {
+            header,
+            transactions,
+        })
+    }
+
+    /// Merkle root of the txids, and whether duplicated transactions could have been
+    /// removed without changing it, see CVE-2012-2459
+    pub fn merkle_root(&self) -> ([u8; 32], bool) {
+        merkle_root(self.transactions.iter().map(Transaction::txid).collect())
+    }
+
+    /// Merkle root of the wtxids, where the coinbase counts as all zeros
+    pub fn witness_root(&self) -> [u8; 32] {
+        let wtxids = self
+            .transactions
+            .iter()
+            .enumerate()
+            .map(|(i, transaction)| match i {
+                0 => [0u8; 32],
+                _ => transaction.wtxid(),
+            })
+            .collect();
+        merkle_root(wtxids).0
+    }
+
+    /// Check the transactions are those committed to by the header
+    /// The merkle root must match without duplicated transactions, and witness data is
+    /// only allowed with a matching commitment in the coinbase, as Bitcoin Core checks
+    pub fn check(&self) -> Result<(), HandshakeError> {
+        let hash = self.block_hash();
+        let Some(coinbase) = self.transactions.first().filter(|tx| tx.is_coinbase()) else {
+            return Err(HandshakeError::invalid_block(
+                &hash,
+                "First transaction is not a coinbase",
+            ));
+        };
+        let (merkle_root, mutated) = self.merkle_root();
+        if merkle_root != self.header.merkle_root {
+            return Err(HandshakeError::invalid_block(&hash, "Merkle root mismatch"));
+        }
+        if mutated {
+            return Err(HandshakeError::invalid_block(
+                &hash,
+                "Duplicate transactions",
+            ));
+        }
+
+        // Like Bitcoin Core, the last matching output is the commitment
+        let commitment = coinbase.outputs.iter().rev().find_map(|output| {
+            let script = &output.script_pubkey;
+            (script.len() >= 38 && script.starts_with(&WITNESS_COMMITMENT_HEADER))
+                .then(|| &script[6..38])
+        });
+        match commitment {
+            Some(commitment) => {
+                let reserved = match coinbase.inputs[0].witness.as_slice() {
+                    [reserved] if reserved.len() == 32 => reserved,
+                    _ => {
+                        return Err(HandshakeError::invalid_block(
+                            &hash,
+                            "Invalid witness reserved value",
+                        ))
+                    }
+                };
+                let expected = sha256d(&[self.witness_root().as_slice(), reserved].concat());
+                if commitment != expected {
+                    return Err(HandshakeError::invalid_block(
+                        &hash,
+                        "Witness commitment mismatch",
+                    ));
+                }
+            }
+            None if self.transactions.iter().any(Transaction::has_witness) => {
+                return Err(HandshakeError::invalid_block(
+                    &hash,
+                    "Witness data without commitment",
+                ));
+            }
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+impl Serializable for Block {
+    fn serialize(&self) -> Result<Vec<u8>, HandshakeError> {
+        let mut message = Vec::new();
+        self.encode(&mut message, true)?;
+        Ok(message)
+    }
+
+    fn deserialize(msg: Vec<u8>) -> Result<Box<Self>, HandshakeError> {
+        let mut cursor = Cursor::new(msg);
+        Ok(Box::new(Self::decode(&mut cursor)?))
+    }
+}
+
+/// Root of the merkle tree of the given hashes, the last one of a level being paired
+/// with itself when their number is odd
+/// Also tells whether two identical hashes were paired, as such trees have the same
+/// root as the tree without the duplicates, like Bitcoin Core's ComputeMerkleRoot
+pub fn merkle_root(mut hashes: Vec<[u8; 32]>) -> ([u8; 32], bool) {
+    let mut mutated = false;
+    if hashes.is_empty() {
+        return ([0u8; 32], mutated);
+    }
+    while hashes.len() > 1 {
+        mutated |= hashes.chunks_exact(2).any(|pair| pair[0] == pair[1]);
+        if !hashes.len().is_multiple_of(2) {
+            hashes.push(hashes[hashes.len() - 1]);
+        }
+        hashes = hashes
+            .chunks_exact(2)
+            .map(|pair| sha256d(&[pair[0], pair[1]].concat()))
+            .collect();
+    }
+    (hashes[0], mutated)
+}
+
+/// Request for a block, with its witness data from nodes offering it
+pub(crate) fn block_getdata(services: ServiceFlags, hash: [u8; 32]) -> NetworkMessage {
+    let inventory = if services.contains(ServiceFlags::NODE_WITNESS) {
+        Inventory::WitnessBlock(hash)
+    } else {
+        Inventory::Block(hash)
+    };
+    NetworkMessage::GetData(InventoryMessage::new(vec![inventory]))
+}
+
+impl HandshakeOutcome<TcpStream> {
+    /// Download a block from the remote node and check its transactions against its header
+    /// Other messages received meanwhile are skipped
+    pub fn get_block(
+        &mut self,
+        hash: [u8; 32],
+        timeout: Duration,
+    ) -> Result<Block, HandshakeError> {
+        let stage = HandshakeState::Established;
+        let deadline = Instant::now() + timeout;
+        self.send(&block_getdata(self.peer_version().services(), hash))
+            .map_err(|e| e.at_stage(stage))?;
+        loop {
+            match self.receive_until(deadline)? {
+                NetworkMessage::Block(block) if block.block_hash() == hash => {
+                    block.check()?;
+                    return Ok(block);
+                }
+                NetworkMessage::NotFound(notfound)
+                    if notfound.inventory.iter().any(|inv| *inv.hash() == hash) =>
+                {
+                    return Err(HandshakeError::NotFound(hash_to_hex(&hash)));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::BitcoinNetwork;
+    use crate::transaction::{OutPoint, TxIn, TxOut, GENESIS_COINBASE};
+
+    fn genesis_block() -> Block {
+        let coinbase = hex::decode(GENESIS_COINBASE).expect("Invalid hex");
+        Block {
+            header: BitcoinNetwork::Mainnet
+                .params()
+                .genesis_header
+                .expect("Mainnet genesis header is known"),
+            transactions: vec![*Transaction::deserialize(coinbase).expect("Failed to decode")],
+        }
+    }
+
+    fn spend(vout: u32, witness: Vec<Vec<u8>>) -> Transaction {
+        Transaction {
+            version: 2,
+            inputs: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: [9u8; 32],
+                    vout,
+                },
+                script_sig: Vec::new(),
+                sequence: u32::MAX,
+                witness,
+            }],
+            outputs: vec![TxOut {
+                value: 1000,
+                script_pubkey: vec![0x51],
+            }],
+            lock_time: 0,
+        }
+    }
+
+    // Segwit block committing to its witness data
+    fn segwit_block() -> Block {
+        let mut block = genesis_block();
+        block.transactions[0].inputs[0].witness = vec![vec![0u8; 32]];
+        block.transactions.push(spend(0, vec![vec![0x01; 72]]));
+        block.transactions.push(spend(1, Vec::new()));
+        let commitment = sha256d(&[block.witness_root(), [0u8; 32]].concat());
+        block.transactions[0].outputs.push(TxOut {
+            value: 0,
+            script_pubkey: [WITNESS_COMMITMENT_HEADER.as_slice(), &commitment].concat(),
+        });
+        block.header.merkle_root = block.merkle_root().0;
+        block
+    }
+
+    #[test]
+    fn test_genesis_block_ok() {
+        let block = genesis_block();
+        assert_eq!(block.block_hash(), BitcoinNetwork::Mainnet.genesis_hash());
+        assert_eq!(block.merkle_root(), (block.header.merkle_root, false));
+        assert!(block.check().is_ok());
+
+        let bytes = block.serialize().expect("Failed to serialize");
+        assert_eq!(bytes.len(), 285);
+        assert_eq!(*Block::deserialize(bytes).unwrap(), block);
+    }
+
+    #[test]
+    fn test_segwit_block_ok() {
+        let block = segwit_block();
+        assert!(block.check().is_ok());
+        let bytes = block.serialize().expect("Failed to serialize");
+        assert_eq!(*Block::deserialize(bytes).unwrap(), block);
+    }
+
+    #[test]
+    fn test_block_merkle_root_error() {
+        let mut tampered = genesis_block();
+        tampered.transactions[0].outputs[0].value += 1;
+        assert!(matches!(
+            tampered.check(),
+            Err(HandshakeError::InvalidBlock {
+                reason: "Merkle root mismatch",
+                ..
+            })
+        ));
+
+        // Duplicating the last transaction of an odd number keeps the merkle root
+        let mut block = genesis_block();
+        block.transactions.push(spend(0, Vec::new()));
+        block.transactions.push(spend(1, Vec::new()));
+        block.header.merkle_root = block.merkle_root().0;
+        let mut duplicated = block.clone();
+        duplicated.transactions.push(spend(1, Vec::new()));
+        assert_eq!(duplicated.merkle_root(), (block.header.merkle_root, true));
+        assert!(matches!(
+            duplicated.check(),
+            Err(HandshakeError::InvalidBlock {
+                reason: "Duplicate transactions",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_block_witness_commitment_error() {
+        let mut block = segwit_block();
+        block.transactions[1].inputs[0].witness[0][0] = 0x02;
+        assert!(matches!(
+            block.check(),
+            Err(HandshakeError::InvalidBlock {
+                reason: "Witness commitment mismatch",
+                ..
+            })
+        ));
+
+        // Witness data is not allowed without a commitment
+        let mut block = genesis_block();
+        block.transactions.push(spend(0, vec![vec![0x01; 72]]));
+        block.header.merkle_root = block.merkle_root().0;
+        assert!(matches!(
+            block.check(),
+            Err(HandshakeError::InvalidBlock {
+                reason: "Witness data without commitment",
+                ..
+            })
+        ));
+    }
+}
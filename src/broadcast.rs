@@ -0,0 +1,363 @@
+use super::codec::VarStr;
+use super::error::HandshakeError;
+use super::handshake::{
+    perform_handshake_with_config, HandshakeConfig, HandshakeOutcome, HandshakeState,
+};
+use super::inventory::{Inventory, InventoryMessage};
+use super::messages::{NetworkMessage, Serializable};
+use super::transaction::Transaction;
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use std::io::{Cursor, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+// Reject codes, see BIP61
+pub const REJECT_MALFORMED: u8 = 0x01;
+pub const REJECT_INVALID: u8 = 0x10;
+pub const REJECT_OBSOLETE: u8 = 0x11;
+pub const REJECT_DUPLICATE: u8 = 0x12;
+pub const REJECT_NONSTANDARD: u8 = 0x40;
+pub const REJECT_DUST: u8 = 0x41;
+pub const REJECT_INSUFFICIENTFEE: u8 = 0x42;
+pub const REJECT_CHECKPOINT: u8 = 0x43;
+// Longest message name or reason accepted in a reject message
+pub const MAX_REJECT_MESSAGE_LENGTH: usize = 111;
+
+/// Refusal of a message by the remote node, see BIP61
+/// Only sent by nodes older than Bitcoin Core 0.20, newer ones silently drop what they refuse
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectMessage {
+    // Command of the refused message, e.g. tx
+    pub message: String,
+    // Reject code, one of the REJECT_ constants
+    pub code: u8,
+    // Human readable reason, e.g. bad-txns-inputs-missingorspent
+    pub reason: String,
+    // Hash of the refused transaction or block, in internal byte order
+    pub hash: Option<[u8; 32]>,
+}
+
+impl Serializable for RejectMessage {
+    fn serialize(&self) -> Result<Vec<u8>, HandshakeError> {
+        let mut message = Vec::new();
+        VarStr(self.message.clone()).encode(&mut message)?;
+        message.write_u8(self.code)?;
+        VarStr(self.reason.clone()).encode(&mut message)?;
+        if let Some(hash) = &self.hash {
+            message.write_all(hash)?;
+        }
+        Ok(message)
+    }
+
+    fn deserialize(msg: Vec<u8>) -> Result<Box<Self>, HandshakeError> {
+        let mut cursor = Cursor::new(msg);
+        let message = VarStr::decode(&mut cursor, MAX_REJECT_MESSAGE_LENGTH)?.0;
+        let code = cursor.read_u8()?;
+        let reason = VarStr::decode(&mut cursor, MAX_REJECT_MESSAGE_LENGTH)?.0;
+        // Only rejected transactions and blocks are followed by their hash
+        let mut hash = [0u8; 32];
+        let hash = cursor.read_exact(&mut hash).ok().map(|_| hash);
+        Ok(Box::new(Self {
+            message,
+            code,
+            reason,
+            hash,
+        }))
+    }
+}
+
+/// What the remote node did with a transaction we announced
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BroadcastStatus {
+    // The remote node never asked for the transaction, it may already have it
+    Ignored,
+    // The remote node downloaded the transaction without telling what it made of it
+    Requested,
+    // The remote node refused the transaction with a reject message
+    Rejected { code: u8, reason: String },
+    // The remote node announced the transaction back, so it accepted it
+    Relayed,
+}
+
+impl BroadcastStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BroadcastStatus::Ignored => "ignored",
+            BroadcastStatus::Requested => "requested",
+            BroadcastStatus::Rejected { .. } => "rejected",
+            BroadcastStatus::Relayed => "relayed",
+        }
+    }
+}
+
+/// Report of a transaction broadcast to a remote node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BroadcastReport {
+    pub txid: [u8; 32],
+    pub wtxid: [u8; 32],
+    // The transaction was announced by wtxid, both nodes having sent wtxidrelay
+    pub by_wtxid: bool,
+    pub status: BroadcastStatus,
+}
+
+/// Announcement of a transaction to the remote node, independent of any I/O
+/// The drivers send the inventory, then hand every received message over until
+/// the outcome is settled or their time is up
+#[derive(Debug)]
+pub(crate) struct Broadcast<'a> {
+    transaction: &'a Transaction,
+    report: BroadcastReport,
+}
+
+impl<'a> Broadcast<'a> {
+    pub(crate) fn new(transaction: &'a Transaction, by_wtxid: bool) -> Self {
+        Self {
+            transaction,
+            report: BroadcastReport {
+                txid: transaction.txid(),
+                wtxid: transaction.wtxid(),
+                by_wtxid,
+                status: BroadcastStatus::Ignored,
+            },
+        }
+    }
+
+    /// Inventory announcing the transaction, by wtxid once wtxidrelay is negotiated
+    pub(crate) fn announcement(&self) -> NetworkMessage {
+        let inventory = if self.report.by_wtxid {
+            Inventory::WTx(self.report.wtxid)
+        } else {
+            Inventory::Tx(self.report.txid)
+        };
+        NetworkMessage::Inv(InventoryMessage::new(vec![inventory]))
+    }
+
+    /// Handle a message from the remote node, returning the transaction when asked for
+    /// it, without its witness data if requested so
+    pub(crate) fn on_message(&mut self, message: &NetworkMessage) -> Option<NetworkMessage> {
+        match message {
+            NetworkMessage::GetData(getdata) => {
+                let with_witness = getdata.inventory.iter().find_map(|inv| match inv {
+                    Inventory::Tx(hash) if *hash == self.report.txid => Some(false),
+                    Inventory::WitnessTx(hash) if *hash == self.report.txid => Some(true),
+                    Inventory::WTx(hash) if *hash == self.report.wtxid => Some(true),
+                    _ => None,
+                })?;
+                if self.report.status == BroadcastStatus::Ignored {
+                    self.report.status = BroadcastStatus::Requested;
+                }
+                Some(NetworkMessage::Tx(if with_witness {
+                    self.transaction.clone()
+                } else {
+                    self.transaction.without_witness()
+                }))
+            }
+            NetworkMessage::Reject(reject)
+                if reject.hash == Some(self.report.txid)
+                    || reject.hash == Some(self.report.wtxid) =>
+            {
+                self.report.status = BroadcastStatus::Rejected {
+                    code: reject.code,
+                    reason: reject.reason.clone(),
+                };
+                None
+            }
+            NetworkMessage::Inv(inv) if inv.inventory.iter().any(|inv| self.is_ours(inv)) => {
+                self.report.status = BroadcastStatus::Relayed;
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the remote node told what it made of the transaction
+    pub(crate) fn is_settled(&self) -> bool {
+        matches!(
+            self.report.status,
+            BroadcastStatus::Rejected { .. } | BroadcastStatus::Relayed
+        )
+    }
+
+    pub(crate) fn into_report(self) -> BroadcastReport {
+        self.report
+    }
+
+    fn is_ours(&self, inventory: &Inventory) -> bool {
+        match inventory {
+            Inventory::Tx(hash) | Inventory::WitnessTx(hash) => *hash == self.report.txid,
+            Inventory::WTx(hash) => *hash == self.report.wtxid,
+            _ => false,
+        }
+    }
+}
+
+impl HandshakeOutcome<TcpStream> {
+    /// Announce a transaction to the remote node and serve it when asked for
+    /// Waits until the remote node rejects it or announces it back, or `wait` elapses.
+    /// Nodes only announce transactions to peers that asked for relay in their version
+    pub fn broadcast(
+        &mut self,
+        transaction: &Transaction,
+        wait: Duration,
+    ) -> Result<BroadcastReport, HandshakeError> {
+        let stage = HandshakeState::Established;
+        let deadline = Instant::now() + wait;
+        let mut broadcast = Broadcast::new(transaction, self.negotiated().wtxid_relay);
+        self.send(&broadcast.announcement())
+            .map_err(|e| e.at_stage(stage))?;
+        while !broadcast.is_settled() {
+            // Running out of time between messages is how a silent node is told apart,
+            // not a failure
+            let Some(message) = self.try_receive_until(deadline)? else {
+                break;
+            };
+            if let Some(reply) = broadcast.on_message(&message) {
+                self.send(&reply).map_err(|e| e.at_stage(stage))?;
+            }
+        }
+        Ok(broadcast.into_report())
+    }
+}
+
+/// Push a raw transaction to a node without RPC access
+/// Performs a handshake with the node then announces the transaction to it, see
+/// `HandshakeOutcome::broadcast`
+pub fn broadcast_tx(
+    config: &HandshakeConfig,
+    peer: SocketAddr,
+    raw_tx: &[u8],
+    wait: Duration,
+) -> Result<BroadcastReport, HandshakeError> {
+    let mut cursor = Cursor::new(raw_tx);
+    let transaction = Transaction::decode(&mut cursor).map_err(|e| match e {
+        HandshakeError::Io(_) => HandshakeError::InvalidMessage("Truncated transaction"),
+        e => e,
+    })?;
+    if cursor.position() != raw_tx.len() as u64 {
+        return Err(HandshakeError::InvalidMessage(
+            "Unexpected data after the transaction",
+        ));
+    }
+    let mut outcome = perform_handshake_with_config(config, peer, peer)?;
+    outcome.broadcast(&transaction, wait)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{OutPoint, TxIn, TxOut};
+
+    fn segwit_transaction() -> Transaction {
+        Transaction {
+            version: 2,
+            inputs: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: [7u8; 32],
+                    vout: 0,
+                },
+                script_sig: Vec::new(),
+                sequence: u32::MAX,
+                witness: vec![vec![0x30; 71]],
+            }],
+            outputs: vec![TxOut {
+                value: 1000,
+                script_pubkey: vec![0x51],
+            }],
+            lock_time: 0,
+        }
+    }
+
+    #[test]
+    fn test_reject_message_round_trip_ok() {
+        let reject = RejectMessage {
+            message: "tx".to_string(),
+            code: REJECT_INSUFFICIENTFEE,
+            reason: "min relay fee not met".to_string(),
+            hash: Some([3u8; 32]),
+        };
+        let bytes = reject.serialize().expect("Failed to serialize");
+        assert_eq!(bytes.len(), 1 + 2 + 1 + 1 + 21 + 32);
+        assert_eq!(*RejectMessage::deserialize(bytes).unwrap(), reject);
+
+        // Rejected messages other than tx and block carry no hash
+        let reject = RejectMessage {
+            message: "version".to_string(),
+            code: REJECT_OBSOLETE,
+            reason: "Version must be 31800 or greater".to_string(),
+            hash: None,
+        };
+        let bytes = reject.serialize().expect("Failed to serialize");
+        assert_eq!(*RejectMessage::deserialize(bytes).unwrap(), reject);
+    }
+
+    #[test]
+    fn test_broadcast_served_ok() {
+        let transaction = segwit_transaction();
+        let mut broadcast = Broadcast::new(&transaction, true);
+        assert_eq!(
+            broadcast.announcement(),
+            NetworkMessage::Inv(InventoryMessage::new(vec![Inventory::WTx(
+                transaction.wtxid()
+            )]))
+        );
+
+        // Requests for other transactions are left to the caller
+        let other = InventoryMessage::new(vec![Inventory::WTx([1u8; 32])]);
+        assert_eq!(broadcast.on_message(&NetworkMessage::GetData(other)), None);
+        assert_eq!(broadcast.report.status, BroadcastStatus::Ignored);
+
+        let getdata = InventoryMessage::new(vec![Inventory::Tx(transaction.txid())]);
+        assert_eq!(
+            broadcast.on_message(&NetworkMessage::GetData(getdata)),
+            Some(NetworkMessage::Tx(transaction.without_witness()))
+        );
+        assert_eq!(broadcast.report.status, BroadcastStatus::Requested);
+        assert!(!broadcast.is_settled());
+
+        let inv = InventoryMessage::new(vec![Inventory::WTx(transaction.wtxid())]);
+        assert_eq!(broadcast.on_message(&NetworkMessage::Inv(inv)), None);
+        assert!(broadcast.is_settled());
+        assert_eq!(broadcast.into_report().status, BroadcastStatus::Relayed);
+    }
+
+    #[test]
+    fn test_broadcast_rejected_error() {
+        let transaction = segwit_transaction();
+        let mut broadcast = Broadcast::new(&transaction, false);
+        assert_eq!(
+            broadcast.announcement(),
+            NetworkMessage::Inv(InventoryMessage::new(vec![Inventory::Tx(
+                transaction.txid()
+            )]))
+        );
+        broadcast.on_message(&NetworkMessage::Reject(RejectMessage {
+            message: "tx".to_string(),
+            code: REJECT_DUST,
+            reason: "dust".to_string(),
+            hash: Some(transaction.txid()),
+        }));
+        assert!(broadcast.is_settled());
+        assert_eq!(
+            broadcast.into_report().status,
+            BroadcastStatus::Rejected {
+                code: REJECT_DUST,
+                reason: "dust".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_broadcast_tx_trailing_data_error() {
+        let mut raw_tx = segwit_transaction()
+            .serialize()
+            .expect("Failed to serialize");
+        raw_tx.push(0x00);
+        // The transaction is checked before connecting
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let config = HandshakeConfig::new(crate::network::BitcoinNetwork::Regtest);
+        assert!(matches!(
+            broadcast_tx(&config, addr, &raw_tx, Duration::from_secs(1)),
+            Err(HandshakeError::InvalidMessage(_))
+        ));
+    }
+}
@@ -0,0 +1,467 @@
+use super::error::HandshakeError;
+use super::handshake::{HandshakeOutcome, HandshakeState};
+use super::headers::{BlockHeader, GetHeadersMessage, MAX_HEADERS_RESULTS};
+use super::messages::NetworkMessage;
+use super::network::BitcoinNetwork;
+use super::params::NetworkParams;
+use super::uint::U256;
+use std::collections::HashMap;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+/// Best chain of block headers, checked against the consensus rules of its network
+/// Only headers are kept, enough to follow the chain without downloading blocks
+#[derive(Debug, Clone)]
+pub struct HeaderChain {
+    // Network whose rules the headers follow
+    network: BitcoinNetwork,
+    // Headers of the best chain by height, starting with the genesis header
+    headers: Vec<BlockHeader>,
+    // Hashes of the headers of the best chain by height
+    hashes: Vec<[u8; 32]>,
+    // Height of every header of the best chain by hash
+    heights: HashMap<[u8; 32], u32>,
+    // Total work of the best chain up to each height
+    chain_work: Vec<U256>,
+}
+
+impl HeaderChain {
+    /// Chain made of the genesis header of a network
+    pub fn new(network: BitcoinNetwork) -> Result<Self, HandshakeError> {
+        let params = network.params();
+        let genesis = params
+            .genesis_header
+            .filter(|genesis| genesis.block_hash() == params.genesis_hash)
+            .ok_or_else(|| HandshakeError::MissingGenesisHeader(network.to_string()))?;
+        Ok(Self {
+            network,
+            headers: vec![genesis],
+            hashes: vec![params.genesis_hash],
+            heights: HashMap::from([(params.genesis_hash, 0)]),
+            chain_work: vec![genesis.work()],
+        })
+    }
+
+    pub fn network(&self) -> BitcoinNetwork {
+        self.network
+    }
+
+    /// Height of the tip, 0 when only the genesis header is known
+    pub fn height(&self) -> u32 {
+        (self.headers.len() - 1) as u32
+    }
+
+    pub fn tip(&self) -> &BlockHeader {
+        self.headers
+            .last()
+            .expect("The genesis header is always there")
+    }
+
+    pub fn tip_hash(&self) -> [u8; 32] {
+        *self
+            .hashes
+            .last()
+            .expect("The genesis header is always there")
+    }
+
+    /// Header of the best chain at a given height
+    pub fn header_at(&self, height: u32) -> Option<&BlockHeader> {
+        self.headers.get(height as usize)
+    }
+
+    /// Height of a header of the best chain
+    pub fn height_of(&self, hash: &[u8; 32]) -> Option<u32> {
+        self.heights.get(hash).copied()
+    }
+
+    /// Total work of the best chain, the expected number of hashes needed to build it
+    pub fn chain_work(&self) -> U256 {
+        *self
+            .chain_work
+            .last()
+            .expect("The genesis header is always there")
+    }
+
+    /// Hashes of the best chain from the tip back to the genesis header
+    /// The 10 most recent are consecutive, then the step doubles, like Bitcoin Core
+    pub fn locator(&self) -> Vec<[u8; 32]> {
+        let mut locator = Vec::new();
+        let mut height = self.height();
+        let mut step = 1;
+        loop {
+            locator.push(self.hashes[height as usize]);
+            if height == 0 {
+                return locator;
+            }
+            height = height.saturating_sub(step);
+            if locator.len() > 10 {
+                step *= 2;
+            }
+        }
+    }
+
+    /// Request for as many headers following our tip as the remote node sends at once
+    pub fn getheaders(&self, version: u32) -> GetHeadersMessage {
+        GetHeadersMessage {
+            version,
+            locator: self.locator(),
+            stop_hash: [0u8; 32],
+        }
+    }
+
+    /// Extend the chain with consecutive headers received from a remote node
+    /// Headers forking from an earlier block replace the end of the best chain only if
+    /// they bring more work. Returns the number of headers added to the best chain
+    pub fn connect(&mut self, headers: &[BlockHeader]) -> Result<usize, HandshakeError> {
+        let Some(first) = headers.first() else {
+            return Ok(0);
+        };
+        let mut base = self.height_of(&first.prev_blockhash).ok_or_else(|| {
+            HandshakeError::invalid_header(&first.block_hash(), "Previous header unknown")
+        })?;
+        // Headers we already have are skipped
+        let mut headers = headers;
+        while let Some((header, rest)) = headers.split_first() {
+            if self.hashes.get(base as usize + 1) != Some(&header.block_hash()) {
+                break;
+            }
+            base += 1;
+            headers = rest;
+        }
+
+        let params = self.network.params();
+        let mut branch: Vec<(BlockHeader, [u8; 32])> = Vec::with_capacity(headers.len());
+        let mut work = self.chain_work[base as usize];
+        for header in headers {
+            let hash = header.block_hash();
+            let prev_hash = branch
+                .last()
+                .map_or(self.hashes[base as usize], |(_, hash)| *hash);
+            if header.prev_blockhash != prev_hash {
+                return Err(HandshakeError::invalid_header(
+                    &hash,
+                    "Headers do not form a chain",
+                ));
+            }
+            let ancestor = |height: u32| match height.checked_sub(base + 1) {
+                Some(index) => &branch[index as usize].0,
+                None => &self.headers[height as usize],
+            };
+            let bits =
+                next_work_required(params, base + branch.len() as u32, header.time, ancestor);
+            if header.bits != bits {
+                return Err(HandshakeError::invalid_header(&hash, "Wrong difficulty"));
+            }
+            header.check_proof_of_work(&params.pow_limit)?;
+            work = work + header.work();
+            branch.push((*header, hash));
+        }
+
+        // Like Bitcoin Core, the first chain seen wins between chains of equal work
+        if work <= self.chain_work() {
+            return Ok(0);
+        }
+        for hash in self.hashes.drain(base as usize + 1..) {
+            self.heights.remove(&hash);
+        }
+        self.headers.truncate(base as usize + 1);
+        self.chain_work.truncate(base as usize + 1);
+        for (header, hash) in &branch {
+            let work = self.chain_work() + header.work();
+            self.heights.insert(*hash, self.headers.len() as u32);
+            self.headers.push(*header);
+            self.hashes.push(*hash);
+            self.chain_work.push(work);
+        }
+        Ok(branch.len())
+    }
+}
+
+/// Bits required of the header following the one at `prev_height`, given its time
+/// Same rules as Bitcoin Core's GetNextWorkRequired, `ancestor` returning the header
+/// of the chain being extended at any height up to `prev_height`
+pub(crate) fn next_work_required<'a>(
+    params: &NetworkParams,
+    prev_height: u32,
+    time: u32,
+    ancestor: impl Fn(u32) -> &'a BlockHeader,
+) -> u32 {
+    let prev = ancestor(prev_height);
+    let pow_limit_bits = U256::from_le_bytes(&params.pow_limit).to_compact();
+    let interval = params.difficulty_adjustment_interval();
+
+    if !(prev_height + 1).is_multiple_of(interval) {
+        if !params.pow_allow_min_difficulty_blocks {
+            return prev.bits;
+        }
+        // Test networks allow a block at the lowest difficulty after 2 spacings without one
+        if time as i64 > prev.time as i64 + 2 * params.pow_target_spacing as i64 {
+            return pow_limit_bits;
+        }
+        // Otherwise the difficulty is the last one not lowered that way
+        let mut height = prev_height;
+        while !height.is_multiple_of(interval) && ancestor(height).bits == pow_limit_bits {
+            height -= 1;
+        }
+        return ancestor(height).bits;
+    }
+
+    if params.pow_no_retargeting {
+        return prev.bits;
+    }
+    let first = ancestor(prev_height + 1 - interval);
+    // BIP94 retargets from the first block, which cannot be a lowest difficulty one
+    let bits = if params.enforce_bip94 {
+        first.bits
+    } else {
+        prev.bits
+    };
+    calculate_next_work_required(params, bits, first.time, prev.time)
+}
+
+/// Target of a new difficulty period scaled by the time the previous one took
+/// The adjustment is bounded to a factor of 4 in both directions
+pub(crate) fn calculate_next_work_required(
+    params: &NetworkParams,
+    bits: u32,
+    first_time: u32,
+    last_time: u32,
+) -> u32 {
+    let timespan = params.pow_target_timespan as i64;
+    let actual_timespan = (last_time as i64 - first_time as i64).clamp(timespan / 4, timespan * 4);
+    let pow_limit = U256::from_le_bytes(&params.pow_limit);
+    let target = U256::from_compact(bits).unwrap_or(pow_limit);
+    let target = target.mul_u64(actual_timespan as u64) / U256::from_u64(timespan as u64);
+    target.min(pow_limit).to_compact()
+}
+
+impl HandshakeOutcome<TcpStream> {
+    /// Download headers from the remote node until our tip reaches the height it
+    /// advertised in its version message, or it has no more to send
+    /// Other messages are skipped, and each headers message must arrive within the
+    /// timeout. Returns the height of the tip
+    pub fn sync_headers(
+        &mut self,
+        chain: &mut HeaderChain,
+        timeout: Duration,
+    ) -> Result<u32, HandshakeError> {
+        let stage = HandshakeState::Established;
+        let start_height = self.peer_version().start_height().max(0) as u32;
+        while chain.height() < start_height {
+            let getheaders = chain.getheaders(self.negotiated_version() as u32);
+            self.send(&NetworkMessage::GetHeaders(getheaders))
+                .map_err(|e| e.at_stage(stage))?;
+            let deadline = Instant::now() + timeout;
+            let headers = loop {
+                if let NetworkMessage::Headers(headers) = self.receive_until(deadline)? {
+                    break headers.headers;
+                }
+            };
+            // A partial batch, or one we learnt nothing from, ends the download
+            if chain.connect(&headers)? == 0 || headers.len() < MAX_HEADERS_RESULTS {
+                break;
+            }
+        }
+        Ok(chain.height())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Mine a regtest header on top of another, where almost any hash meets the target
+    fn mine(prev: &BlockHeader, time: u32) -> BlockHeader {
+        let pow_limit = BitcoinNetwork::Regtest.params().pow_limit;
+        let mut header = BlockHeader {
+            version: 4,
+            prev_blockhash: prev.block_hash(),
+            merkle_root: [0u8; 32],
+            time,
+            bits: 0x207fffff,
+            nonce: 0,
+        };
+        while header.check_proof_of_work(&pow_limit).is_err() {
+            header.nonce += 1;
+        }
+        header
+    }
+
+    // Headers following a given one, 10 minutes apart
+    fn mine_chain(from: &BlockHeader, count: u32) -> Vec<BlockHeader> {
+        let mut headers: Vec<BlockHeader> = Vec::new();
+        for i in 1..=count {
+            let prev = headers.last().unwrap_or(from);
+            headers.push(mine(prev, from.time + 600 * i));
+        }
+        headers
+    }
+
+    // Synthetic headers at given bits and times, linked by height only
+    fn synthetic(blocks: &[(u32, u32)]) -> Vec<BlockHeader> {
+        blocks
+            .iter()
+            .map(|&(bits, time)| BlockHeader {
+                version: 1,
+                prev_blockhash: [0u8; 32],
+                merkle_root: [0u8; 32],
+                time,
+                bits,
+                nonce: 0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_calculate_next_work_required_ok() {
+        // Vectors of Bitcoin Core's pow_tests
+        let params = BitcoinNetwork::Mainnet.params();
+        for (bits, first_time, last_time, expected) in [
+            (0x1d00ffff, 1261130161, 1262152739, 0x1d00d86a),
+            // Never easier than the limit
+            (0x1d00ffff, 1231006505, 1233061996, 0x1d00ffff),
+            // At most 4 times harder
+            (0x1c05a3f4, 1279008237, 1279297671, 0x1c0168fd),
+            // At most 4 times easier
+            (0x1c387f6f, 1263163443, 1269211443, 0x1d00e1fd),
+        ] {
+            assert_eq!(
+                calculate_next_work_required(params, bits, first_time, last_time),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_min_difficulty_blocks_ok() {
+        let hard = 0x1c00ffff;
+        let chain = synthetic(&[(hard, 0), (hard, 600), (0x1d00ffff, 1801)]);
+        let ancestor = |height: u32| &chain[height as usize];
+
+        // 20 minutes after a block, the lowest difficulty is allowed on testnet only
+        let testnet = BitcoinNetwork::Testnet3.params();
+        assert_eq!(next_work_required(testnet, 1, 1801, ancestor), 0x1d00ffff);
+        let mainnet = BitcoinNetwork::Mainnet.params();
+        assert_eq!(next_work_required(mainnet, 1, 1801, ancestor), hard);
+        // The next block goes back to the last difficulty not lowered
+        assert_eq!(next_work_required(testnet, 2, 2400, ancestor), hard);
+    }
+
+    #[test]
+    fn test_bip94_retarget_ok() {
+        // A period starting at a real difficulty and ending with a lowest difficulty block
+        let mut blocks = vec![(0x1c00ffff, 0); 2016];
+        for (height, block) in blocks.iter_mut().enumerate() {
+            block.1 = 600 * height as u32;
+        }
+        blocks[2015].0 = 0x1d00ffff;
+        let chain = synthetic(&blocks);
+        let ancestor = |height: u32| &chain[height as usize];
+
+        let testnet4 = BitcoinNetwork::Testnet4.params();
+        let testnet3 = BitcoinNetwork::Testnet3.params();
+        let last_time = 600 * 2015;
+        assert_eq!(
+            next_work_required(testnet4, 2015, last_time + 600, ancestor),
+            calculate_next_work_required(testnet4, 0x1c00ffff, 0, last_time)
+        );
+        assert_eq!(
+            next_work_required(testnet3, 2015, last_time + 600, ancestor),
+            calculate_next_work_required(testnet3, 0x1d00ffff, 0, last_time)
+        );
+    }
+
+    #[test]
+    fn test_header_chain_connect_ok() {
+        let block_1 = hex::decode(concat!(
+            "010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000",
+            "982051fd1e4ba744bbbe680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e",
+            "61bc6649ffff001d01e36299",
+        ))
+        .expect("Invalid hex");
+        let block_1 = BlockHeader::decode(&mut block_1.as_slice()).expect("Failed to decode");
+
+        let mut chain = HeaderChain::new(BitcoinNetwork::Mainnet).expect("Failed to create chain");
+        assert_eq!(chain.connect(&[block_1]).expect("Valid header"), 1);
+        assert_eq!(chain.height(), 1);
+        assert_eq!(chain.tip_hash(), block_1.block_hash());
+        assert_eq!(chain.height_of(&block_1.block_hash()), Some(1));
+        assert_eq!(chain.chain_work(), U256::from_u64(0x2_0002_0002));
+        // Known headers are skipped
+        assert_eq!(chain.connect(&[block_1]).expect("Valid header"), 0);
+    }
+
+    #[test]
+    fn test_header_chain_connect_error() {
+        let mut chain = HeaderChain::new(BitcoinNetwork::Regtest).expect("Failed to create chain");
+        let headers = mine_chain(chain.tip(), 3);
+
+        let unknown_prev = &headers[1..];
+        let mut wrong_bits = headers.clone();
+        wrong_bits[2].bits = 0x207ffffe;
+        let mut unlinked = headers.clone();
+        unlinked.swap(1, 2);
+        for headers in [unknown_prev, &wrong_bits, &unlinked] {
+            assert!(matches!(
+                chain.connect(headers),
+                Err(HandshakeError::InvalidHeader { .. })
+            ));
+        }
+        assert_eq!(chain.height(), 0);
+    }
+
+    #[test]
+    fn test_header_chain_fork_ok() {
+        let mut chain = HeaderChain::new(BitcoinNetwork::Regtest).expect("Failed to create chain");
+        let genesis = *chain.tip();
+        let headers = mine_chain(&genesis, 3);
+        assert_eq!(chain.connect(&headers).expect("Valid headers"), 3);
+
+        // A fork with as much work as the best chain is ignored
+        let first = mine(&headers[0], headers[0].time + 601);
+        let fork = [vec![first], mine_chain(&first, 1)].concat();
+        assert_eq!(chain.connect(&fork).expect("Valid headers"), 0);
+        assert_eq!(chain.tip_hash(), headers[2].block_hash());
+
+        // A longer one replaces it
+        let longer = [fork.clone(), mine_chain(&fork[1], 1)].concat();
+        assert_eq!(chain.connect(&longer).expect("Valid headers"), 3);
+        assert_eq!(chain.height(), 4);
+        assert_eq!(chain.tip_hash(), longer[2].block_hash());
+        assert_eq!(chain.height_of(&headers[2].block_hash()), None);
+        assert_eq!(chain.height_of(&headers[0].block_hash()), Some(1));
+    }
+
+    #[test]
+    fn test_locator_ok() {
+        let mut chain = HeaderChain::new(BitcoinNetwork::Regtest).expect("Failed to create chain");
+        let headers = mine_chain(chain.tip(), 20);
+        chain.connect(&headers).expect("Valid headers");
+
+        let heights: Vec<u32> = chain
+            .locator()
+            .iter()
+            .map(|hash| {
+                chain
+                    .height_of(hash)
+                    .expect("Locator hashes are in the chain")
+            })
+            .collect();
+        assert_eq!(
+            heights,
+            [20, 19, 18, 17, 16, 15, 14, 13, 12, 11, 10, 9, 7, 3, 0]
+        );
+    }
+
+    #[test]
+    fn test_header_chain_missing_genesis_error() {
+        let params = NetworkParams {
+            genesis_header: None,
+            ..NetworkParams::regtest()
+        };
+        assert!(matches!(
+            HeaderChain::new(BitcoinNetwork::custom(params)),
+            Err(HandshakeError::MissingGenesisHeader(_))
+        ));
+    }
+}
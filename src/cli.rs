@@ -0,0 +1,295 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use node_handshake::error::HandshakeError;
+use node_handshake::headers::hex_to_hash;
+use node_handshake::network::BitcoinNetwork;
+use node_handshake::params::NetworkParams;
+use node_handshake::services::ServiceFlags;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Exit codes telling scripts why a command failed, 2 is the usage error code of clap
+pub const EXIT_FAILURE: u8 = 1;
+pub const EXIT_USAGE: u8 = 2;
+pub const EXIT_CONNECTION_REFUSED: u8 = 3;
+pub const EXIT_TIMEOUT: u8 = 4;
+pub const EXIT_WRONG_NETWORK: u8 = 5;
+pub const EXIT_BAD_REPLY: u8 = 6;
+pub const EXIT_PEER_DISCONNECTED: u8 = 7;
+pub const EXIT_MISSING_SERVICES: u8 = 8;
+pub const EXIT_TX_REJECTED: u8 = 9;
+pub const EXIT_RESOLUTION_FAILED: u8 = 10;
+
+/// Bitcoin P2P handshake tool
+#[derive(Debug, Parser)]
+#[command(name = "node-handshake", version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Perform a version/verack handshake with a node and report what it advertised
+    Connect(ConnectArgs),
+    /// Find which network a node belongs to by trying the magic of every known network
+    Detect(DetectArgs),
+    /// Announce a raw transaction to a node and report what the node made of it
+    Broadcast(BroadcastArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct NetworkArgs {
+    /// Network the node belongs to
+    #[arg(long, default_value = "mainnet", value_parser = parse_network)]
+    pub network: BitcoinNetwork,
+    /// Challenge script in hex of a custom signet, implies --network signet
+    #[arg(long)]
+    pub signet_challenge: Option<String>,
+    /// TOML file describing another network, overrides --network
+    #[arg(long, value_name = "FILE", conflicts_with = "signet_challenge")]
+    pub network_params: Option<PathBuf>,
+}
+
+impl NetworkArgs {
+    /// Network picked by the arguments, custom ones taking precedence
+    pub fn resolve(&self) -> Result<BitcoinNetwork, HandshakeError> {
+        match (&self.signet_challenge, &self.network_params) {
+            (Some(challenge), _) => BitcoinNetwork::custom_signet(challenge),
+            (_, Some(path)) => Ok(BitcoinNetwork::custom(NetworkParams::from_toml_file(path)?)),
+            (None, None) => Ok(self.network),
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ConnectArgs {
+    /// Node to connect to as host[:port], the network's default port is used if missing
+    pub target: String,
+    #[command(flatten)]
+    pub network: NetworkArgs,
+    /// User agent advertised in our version message
+    #[arg(long, default_value = node_handshake::handshake::DEFAULT_USER_AGENT)]
+    pub user_agent: String,
+    /// Highest block advertised in our version message
+    #[arg(long, default_value_t = 0)]
+    pub start_height: i32,
+    /// Protocol version advertised in our version message
+    #[arg(long, default_value_t = node_handshake::vv::PROTOCOL_VERSION)]
+    pub protocol_version: i32,
+    /// Ask the node to relay transactions to us
+    #[arg(long)]
+    pub relay: bool,
+    /// Services advertised in our version message, e.g. network,witness or 0x409
+    #[arg(long, default_value = "network", value_parser = parse_services)]
+    pub services: ServiceFlags,
+    /// Services the node must offer for the handshake to succeed
+    #[arg(long, default_value = "none", value_parser = parse_services)]
+    pub required_services: ServiceFlags,
+    /// Time limit for each stage of the handshake, e.g. 500ms, 5s or 1m
+    #[arg(long, default_value = "10s", value_parser = parse_duration)]
+    pub timeout: Duration,
+    /// Pings to send once connected to measure the latency, each must be answered in time
+    #[arg(long, default_value_t = 0)]
+    pub pings: u64,
+    /// Time between two pings
+    #[arg(long, default_value = "1s", value_parser = parse_duration)]
+    pub ping_interval: Duration,
+    /// Ask the node for the addresses it knows and list them, waiting up to --timeout
+    #[arg(long)]
+    pub getaddr: bool,
+    /// Download and check the headers of the node's best chain up to its advertised height
+    #[arg(long)]
+    pub sync_headers: bool,
+    /// Download the block with the given hash and check its merkle root
+    #[arg(long, value_name = "HASH", value_parser = parse_hash)]
+    pub get_block: Option<[u8; 32]>,
+    /// Format of the report
+    #[arg(long, value_enum, default_value_t = Output::Text)]
+    pub output: Output,
+}
+
+#[derive(Debug, Args)]
+pub struct DetectArgs {
+    /// Node to probe as host[:port], port 8333 is used if missing
+    pub target: String,
+    /// User agent advertised in our version messages
+    #[arg(long, default_value = node_handshake::handshake::DEFAULT_USER_AGENT)]
+    pub user_agent: String,
+    /// Time limit for each stage of every attempted handshake, e.g. 500ms, 5s or 1m
+    #[arg(long, default_value = "5s", value_parser = parse_duration)]
+    pub timeout: Duration,
+    /// Format of the report
+    #[arg(long, value_enum, default_value_t = Output::Text)]
+    pub output: Output,
+}
+
+#[derive(Debug, Args)]
+pub struct BroadcastArgs {
+    /// Raw transaction in hex, with or without witness data
+    #[arg(value_name = "HEX")]
+    pub raw_tx: String,
+    /// Node to announce the transaction to as host[:port], the network's default port is
+    /// used if missing
+    #[arg(long)]
+    pub peer: String,
+    #[command(flatten)]
+    pub network: NetworkArgs,
+    /// User agent advertised in our version message
+    #[arg(long, default_value = node_handshake::handshake::DEFAULT_USER_AGENT)]
+    pub user_agent: String,
+    /// Time limit for each stage of the handshake, e.g. 500ms, 5s or 1m
+    #[arg(long, default_value = "10s", value_parser = parse_duration)]
+    pub timeout: Duration,
+    /// Time left to the node to ask for the transaction, reject it or announce it back
+    #[arg(long, default_value = "10s", value_parser = parse_duration)]
+    pub wait: Duration,
+    /// Format of the report
+    #[arg(long, value_enum, default_value_t = Output::Text)]
+    pub output: Output,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Output {
+    Text,
+    Json,
+}
+
+fn parse_network(name: &str) -> Result<BitcoinNetwork, String> {
+    name.parse().map_err(|e: HandshakeError| e.to_string())
+}
+
+fn parse_hash(hash: &str) -> Result<[u8; 32], String> {
+    hex_to_hash(hash).map_err(|e| e.to_string())
+}
+
+fn parse_services(names: &str) -> Result<ServiceFlags, String> {
+    names.parse().map_err(|e: HandshakeError| e.to_string())
+}
+
+/// Parse a duration made of a number and a unit among ms, s and m, seconds by default
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("Invalid duration {value}"))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(amount)),
+        "" | "s" => Ok(Duration::from_secs(amount)),
+        "m" => Ok(Duration::from_secs(
+            amount
+                .checked_mul(60)
+                .ok_or_else(|| format!("Invalid duration {value}"))?,
+        )),
+        _ => Err(format!("Invalid duration unit {unit}, expected ms, s or m")),
+    }
+}
+
+/// Resolve a host[:port] target, falling back to the network's default port
+pub fn resolve_target(target: &str, default_port: u16) -> Result<SocketAddr, HandshakeError> {
+    if let Ok(addr) = target.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    // Bare IPv6 addresses contain colons, with or without brackets
+    let bare = target.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = bare.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, default_port));
+    }
+
+    let (host, port) = match target.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse::<u16>()
+                .map_err(|_| HandshakeError::InvalidTarget(target.to_string()))?,
+        ),
+        None => (target, default_port),
+    };
+    let resolution_failed = |source| HandshakeError::ResolutionFailed {
+        host: host.to_string(),
+        source,
+    };
+    (host, port)
+        .to_socket_addrs()
+        .map_err(resolution_failed)?
+        .next()
+        .ok_or_else(|| resolution_failed(Error::new(ErrorKind::NotFound, "No address found")))
+}
+
+/// Exit code matching the cause of a failure
+pub fn exit_code(error: &HandshakeError) -> u8 {
+    match error {
+        HandshakeError::ConnectionFailed(e) if e.kind() == ErrorKind::TimedOut => EXIT_TIMEOUT,
+        HandshakeError::ConnectionFailed(_) => EXIT_CONNECTION_REFUSED,
+        HandshakeError::InvalidTarget(_) => EXIT_USAGE,
+        HandshakeError::ResolutionFailed { .. } => EXIT_RESOLUTION_FAILED,
+        HandshakeError::Timeout { .. } | HandshakeError::PingTimeout(_) => EXIT_TIMEOUT,
+        HandshakeError::WrongNetworkMagic { .. } => EXIT_WRONG_NETWORK,
+        HandshakeError::BadChecksum
+        | HandshakeError::UnexpectedCommand { .. }
+        | HandshakeError::PayloadTooLarge { .. }
+        | HandshakeError::FieldTooLong { .. }
+        | HandshakeError::UnsupportedVersion(_)
+        | HandshakeError::InvalidMessage(_)
+        | HandshakeError::InvalidHeader { .. }
+        | HandshakeError::InvalidBlock { .. } => EXIT_BAD_REPLY,
+        HandshakeError::PeerDisconnected { .. } => EXIT_PEER_DISCONNECTED,
+        HandshakeError::MissingServices { .. } => EXIT_MISSING_SERVICES,
+        _ => EXIT_FAILURE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration_ok() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("5s"), Ok(Duration::from_secs(5)));
+        assert_eq!(parse_duration("5"), Ok(Duration::from_secs(5)));
+        assert_eq!(parse_duration("1m"), Ok(Duration::from_secs(60)));
+        assert!(parse_duration("5h").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("999999999999999999m").is_err());
+    }
+
+    #[test]
+    fn test_resolve_target_default_port_ok() {
+        let expected: SocketAddr = "127.0.0.1:18444".parse().unwrap();
+        assert_eq!(resolve_target("127.0.0.1", 18444).unwrap(), expected);
+        assert_eq!(resolve_target("127.0.0.1:18444", 8333).unwrap(), expected);
+
+        let expected: SocketAddr = "[::1]:8333".parse().unwrap();
+        assert_eq!(resolve_target("::1", 8333).unwrap(), expected);
+        assert_eq!(resolve_target("[::1]", 8333).unwrap(), expected);
+        assert_eq!(resolve_target("localhost:8333", 1).unwrap().port(), 8333);
+    }
+
+    #[test]
+    fn test_exit_code_ok() {
+        let refused = HandshakeError::ConnectionFailed(Error::from(ErrorKind::ConnectionRefused));
+        assert_eq!(exit_code(&refused), EXIT_CONNECTION_REFUSED);
+        let timeout = HandshakeError::ConnectionFailed(Error::from(ErrorKind::TimedOut));
+        assert_eq!(exit_code(&timeout), EXIT_TIMEOUT);
+        assert_eq!(exit_code(&HandshakeError::BadChecksum), EXIT_BAD_REPLY);
+    }
+
+    #[test]
+    fn test_resolve_target_error() {
+        let invalid_port = resolve_target("127.0.0.1:port", 8333).unwrap_err();
+        assert!(matches!(invalid_port, HandshakeError::InvalidTarget(_)));
+        assert_eq!(exit_code(&invalid_port), EXIT_USAGE);
+        // Reserved for documentation, so it never resolves
+        let unresolved = resolve_target("node.invalid", 8333).unwrap_err();
+        assert!(matches!(
+            unresolved,
+            HandshakeError::ResolutionFailed { .. }
+        ));
+        assert_eq!(exit_code(&unresolved), EXIT_RESOLUTION_FAILED);
+    }
+}
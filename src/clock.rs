@@ -0,0 +1,107 @@
+use rand::rngs::{OsRng, StdRng};
+use rand::{RngCore, SeedableRng};
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Source of the timestamps written in our messages
+pub trait Clock: Debug + Send + Sync {
+    /// Current UNIX timestamp in seconds
+    fn now(&self) -> i64;
+}
+
+/// Clock reading the system time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Back to the past")
+            .as_secs() as i64
+    }
+}
+
+/// Clock stopped at a given UNIX timestamp, for reproducible messages
+#[derive(Debug, Clone, Copy)]
+pub struct FixedClock(pub i64);
+
+impl Clock for FixedClock {
+    fn now(&self) -> i64 {
+        self.0
+    }
+}
+
+/// Source of the nonces written in our version and ping messages
+pub trait NonceSource: Debug + Send + Sync {
+    fn next_nonce(&self) -> u64;
+}
+
+/// Nonces drawn from the cryptographically secure random generator of the OS
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OsNonceSource;
+
+impl NonceSource for OsNonceSource {
+    fn next_nonce(&self) -> u64 {
+        OsRng.next_u64()
+    }
+}
+
+/// Always the same nonce, for reproducible messages
+/// Two handshakes of the same node must not share it or they look like a self connection
+#[derive(Debug, Clone, Copy)]
+pub struct FixedNonceSource(pub u64);
+
+impl NonceSource for FixedNonceSource {
+    fn next_nonce(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Reproducible sequence of nonces drawn from a seeded generator, for simulations
+#[derive(Debug)]
+pub struct SeededNonceSource {
+    rng: Mutex<StdRng>,
+}
+
+impl SeededNonceSource {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+        }
+    }
+}
+
+impl NonceSource for SeededNonceSource {
+    fn next_nonce(&self) -> u64 {
+        self.rng
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .next_u64()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_sources_ok() {
+        assert_eq!(FixedClock(1_700_000_000).now(), 1_700_000_000);
+        assert_eq!(FixedNonceSource(42).next_nonce(), 42);
+        assert!(SystemClock.now() > 1_700_000_000);
+    }
+
+    #[test]
+    fn test_seeded_nonce_source_ok() {
+        let first = SeededNonceSource::new(7);
+        let second = SeededNonceSource::new(7);
+        let nonces: Vec<u64> = (0..3).map(|_| first.next_nonce()).collect();
+        assert_eq!(
+            nonces,
+            (0..3).map(|_| second.next_nonce()).collect::<Vec<_>>()
+        );
+        assert_ne!(nonces[0], nonces[1]);
+    }
+}
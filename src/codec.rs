@@ -0,0 +1,171 @@
+use super::error::HandshakeError;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Read, Write};
+
+// Largest length a CompactSize may announce, same limit as Bitcoin Core's MAX_SIZE
+pub const MAX_COMPACT_SIZE: u64 = 0x0200_0000;
+// Largest user agent accepted from a remote node, same limit as Bitcoin Core
+pub const MAX_USER_AGENT_LENGTH: usize = 256;
+
+/// Variable length integer used to prefix every variable length field
+/// Values are encoded on 1, 3, 5 or 9 bytes depending on their size
+/// More information https://en.bitcoin.it/wiki/Protocol_documentation#Variable_length_integer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactSize(pub u64);
+
+impl CompactSize {
+    /// Number of bytes needed to encode the value
+    pub fn encoded_len(&self) -> usize {
+        match self.0 {
+            0..=0xfc => 1,
+            0xfd..=0xffff => 3,
+            0x1_0000..=0xffff_ffff => 5,
+            _ => 9,
+        }
+    }
+
+    /// Write the value with its shortest encoding
+    pub fn encode<W: Write>(&self, writer: &mut W) -> Result<(), HandshakeError> {
+        match self.0 {
+            0..=0xfc => writer.write_u8(self.0 as u8)?,
+            0xfd..=0xffff => {
+                writer.write_u8(0xfd)?;
+                writer.write_u16::<LittleEndian>(self.0 as u16)?;
+            }
+            0x1_0000..=0xffff_ffff => {
+                writer.write_u8(0xfe)?;
+                writer.write_u32::<LittleEndian>(self.0 as u32)?;
+            }
+            _ => {
+                writer.write_u8(0xff)?;
+                writer.write_u64::<LittleEndian>(self.0)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read a value, only its shortest encoding is accepted
+    pub fn decode<R: Read>(reader: &mut R) -> Result<Self, HandshakeError> {
+        let (value, min) = match reader.read_u8()? {
+            0xfd => (reader.read_u16::<LittleEndian>()? as u64, 0xfd),
+            0xfe => (reader.read_u32::<LittleEndian>()? as u64, 0x1_0000),
+            0xff => (reader.read_u64::<LittleEndian>()?, 0x1_0000_0000),
+            byte => (byte as u64, 0),
+        };
+        if value < min {
+            return Err(HandshakeError::InvalidMessage("Non canonical CompactSize"));
+        }
+        Ok(CompactSize(value))
+    }
+
+    /// Read a value used as the length of the following field, bounded by `max`
+    pub fn decode_length<R: Read>(reader: &mut R, max: u64) -> Result<usize, HandshakeError> {
+        let length = Self::decode(reader)?.0;
+        let max = max.min(MAX_COMPACT_SIZE);
+        if length > max {
+            return Err(HandshakeError::FieldTooLong { length, max });
+        }
+        Ok(length as usize)
+    }
+}
+
+/// Variable length string prefixed by its length as a CompactSize
+/// More information https://en.bitcoin.it/wiki/Protocol_documentation#Variable_length_string
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VarStr(pub String);
+
+impl VarStr {
+    /// Write the length followed by the string bytes
+    pub fn encode<W: Write>(&self, writer: &mut W) -> Result<(), HandshakeError> {
+        write_var_bytes(writer, self.0.as_bytes())
+    }
+
+    /// Read a string of at most `max_length` bytes
+    /// Invalid UTF-8 sequences are replaced, the string is informative only
+    pub fn decode<R: Read>(reader: &mut R, max_length: usize) -> Result<Self, HandshakeError> {
+        let bytes = read_var_bytes(reader, max_length)?;
+        Ok(VarStr(String::from_utf8_lossy(&bytes).into_owned()))
+    }
+}
+
+/// Write bytes prefixed by their length as a CompactSize
+pub fn write_var_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<(), HandshakeError> {
+    CompactSize(bytes.len() as u64).encode(writer)?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+/// Read bytes prefixed by their length as a CompactSize, at most `max_length` of them
+pub fn read_var_bytes<R: Read>(
+    reader: &mut R,
+    max_length: usize,
+) -> Result<Vec<u8>, HandshakeError> {
+    let length = CompactSize::decode_length(reader, max_length as u64)?;
+    let mut bytes = vec![0u8; length];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_compact_size_round_trip_ok() {
+        for (value, encoded) in [
+            (0u64, vec![0x00]),
+            (0xfc, vec![0xfc]),
+            (0xfd, vec![0xfd, 0xfd, 0x00]),
+            (0xffff, vec![0xfd, 0xff, 0xff]),
+            (0x1_0000, vec![0xfe, 0x00, 0x00, 0x01, 0x00]),
+            (0xffff_ffff, vec![0xfe, 0xff, 0xff, 0xff, 0xff]),
+            (
+                0x1_0000_0000,
+                vec![0xff, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00],
+            ),
+        ] {
+            let mut bytes = Vec::new();
+            CompactSize(value)
+                .encode(&mut bytes)
+                .expect("Failed to encode CompactSize");
+            assert_eq!(bytes, encoded);
+            assert_eq!(CompactSize(value).encoded_len(), encoded.len());
+
+            let decoded =
+                CompactSize::decode(&mut Cursor::new(bytes)).expect("Failed to decode CompactSize");
+            assert_eq!(decoded, CompactSize(value));
+        }
+    }
+
+    #[test]
+    fn test_compact_size_non_canonical_error() {
+        let result = CompactSize::decode(&mut Cursor::new(vec![0xfd, 0xfc, 0x00]));
+        assert!(matches!(result, Err(HandshakeError::InvalidMessage(_))));
+    }
+
+    #[test]
+    fn test_var_str_round_trip_ok() {
+        let user_agent = VarStr("/Satoshi:27.0.0/".to_string());
+        let mut bytes = Vec::new();
+        user_agent
+            .encode(&mut bytes)
+            .expect("Failed to encode VarStr");
+        assert_eq!(bytes[0] as usize, user_agent.0.len());
+
+        let decoded = VarStr::decode(&mut Cursor::new(bytes), MAX_USER_AGENT_LENGTH)
+            .expect("Failed to decode VarStr");
+        assert_eq!(decoded, user_agent);
+    }
+
+    #[test]
+    fn test_var_str_too_long_error() {
+        let mut bytes = Vec::new();
+        VarStr("a".repeat(MAX_USER_AGENT_LENGTH + 1))
+            .encode(&mut bytes)
+            .expect("Failed to encode VarStr");
+
+        let result = VarStr::decode(&mut Cursor::new(bytes), MAX_USER_AGENT_LENGTH);
+        assert!(matches!(result, Err(HandshakeError::FieldTooLong { .. })));
+    }
+}
@@ -0,0 +1,145 @@
+use super::error::HandshakeError;
+use super::handshake::{
+    perform_handshake_with_config, HandshakeConfig, HandshakeTimeouts, DEFAULT_USER_AGENT,
+};
+use super::network::{BitcoinNetwork, BUILTIN_NETWORKS};
+use super::vv::VersionMessage;
+use std::net::SocketAddr;
+
+/// Way the network of a remote node was found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetectionMethod {
+    // A full handshake succeeded with the magic of the detected network
+    Handshake,
+    // The remote node answered our version with the magic of another network
+    ReplyMagic,
+}
+
+impl DetectionMethod {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            DetectionMethod::Handshake => "handshake",
+            DetectionMethod::ReplyMagic => "reply-magic",
+        }
+    }
+}
+
+/// Settings of a network detection
+#[derive(Debug, Clone)]
+pub struct DetectionConfig {
+    // Networks tried in turn, those whose default port matches the remote one go first
+    pub candidates: Vec<BitcoinNetwork>,
+    // Software advertised in our version messages
+    pub user_agent: String,
+    // Time limits of every attempted handshake
+    pub timeouts: HandshakeTimeouts,
+}
+
+impl DetectionConfig {
+    /// Try every built-in network with the default settings
+    pub fn new() -> Self {
+        Self {
+            candidates: BUILTIN_NETWORKS.to_vec(),
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            timeouts: HandshakeTimeouts::default(),
+        }
+    }
+}
+
+impl Default for DetectionConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Result of a network detection
+#[derive(Debug, Clone)]
+pub struct Detection {
+    // Network of the remote node, unknown if no candidate matched
+    pub network: Option<BitcoinNetwork>,
+    // Magic the remote node was seen using, unknown if it dropped every attempt
+    pub magic: Option<[u8; 4]>,
+    // Way the network was found
+    pub method: Option<DetectionMethod>,
+    // Networks whose magic was sent, in order
+    pub attempts: Vec<BitcoinNetwork>,
+    // Version message of the remote node when a handshake succeeded
+    pub peer_version: Option<VersionMessage>,
+}
+
+/// Find which network a remote node belongs to
+/// A version message is sent with the magic of each candidate in turn. The detection
+/// stops at the first successful handshake or as soon as the remote node answers with a
+/// magic of its own, a dropped connection moves on to the next candidate
+pub fn detect_network(
+    addr: SocketAddr,
+    config: &DetectionConfig,
+) -> Result<Detection, HandshakeError> {
+    let mut candidates = config.candidates.clone();
+    // Stable sort keeps the configured order among networks sharing a port match
+    candidates.sort_by_key(|network| network.default_port() != addr.port());
+
+    let mut detection = Detection {
+        network: None,
+        magic: None,
+        method: None,
+        attempts: Vec::new(),
+        peer_version: None,
+    };
+    for candidate in candidates {
+        detection.attempts.push(candidate);
+        let handshake_config = HandshakeConfig {
+            user_agent: config.user_agent.clone(),
+            timeouts: config.timeouts,
+            ..HandshakeConfig::new(candidate)
+        };
+
+        match perform_handshake_with_config(&handshake_config, addr, addr) {
+            Ok(outcome) => {
+                detection.network = Some(candidate);
+                detection.magic = Some(candidate.magic());
+                detection.method = Some(DetectionMethod::Handshake);
+                detection.peer_version = Some(outcome.peer_version().clone());
+                return Ok(detection);
+            }
+            Err(HandshakeError::WrongNetworkMagic { got, .. }) => {
+                detection.network = config
+                    .candidates
+                    .iter()
+                    .chain(BUILTIN_NETWORKS.iter())
+                    .find(|network| network.as_u32() == got)
+                    .copied();
+                detection.magic = Some(got.to_le_bytes());
+                detection.method = Some(DetectionMethod::ReplyMagic);
+                return Ok(detection);
+            }
+            // Bitcoin Core drops connections opened with another network's magic
+            Err(HandshakeError::PeerDisconnected { .. }) | Err(HandshakeError::Timeout { .. }) => {
+                continue
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(detection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detection_config_default_ok() {
+        let config = DetectionConfig::default();
+        assert_eq!(config.candidates, BUILTIN_NETWORKS.to_vec());
+        assert_eq!(config.user_agent, DEFAULT_USER_AGENT);
+    }
+
+    #[test]
+    fn test_detect_network_connection_refused_error() {
+        let addr = "127.0.0.1:1899".parse().expect("Invalid socket address");
+        assert!(matches!(
+            detect_network(addr, &DetectionConfig::new()),
+            Err(HandshakeError::ConnectionFailed(_))
+        ));
+    }
+}
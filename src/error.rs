@@ -0,0 +1,128 @@
+use super::handshake::HandshakeState;
+use super::headers::hash_to_hex;
+use super::services::ServiceFlags;
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Errors raised while exchanging messages with a remote node
+#[derive(Debug, Error)]
+pub enum HandshakeError {
+    // The TCP connection to the remote node could not be opened
+    #[error("Failed to connect to the remote node: {0}")]
+    ConnectionFailed(#[source] Error),
+    // The host[:port] target given to connect to cannot be parsed
+    #[error("Invalid target {0}")]
+    InvalidTarget(String),
+    // The host name of the target does not resolve to any address
+    #[error("Failed to resolve {host}: {source}")]
+    ResolutionFailed {
+        host: String,
+        #[source]
+        source: Error,
+    },
+    // The remote node did not answer in time
+    #[error("Timed out waiting for the remote node at stage {stage:?}")]
+    Timeout { stage: HandshakeState },
+    // The received message belongs to another network
+    #[error("Wrong network magic: expected {expected:#010x}, got {got:#010x}")]
+    WrongNetworkMagic { expected: u32, got: u32 },
+    // The payload does not match the checksum of its header
+    #[error("Invalid checksum for the message payload")]
+    BadChecksum,
+    // The message is not allowed at this stage of the handshake
+    #[error("Unexpected {command} message at stage {stage:?}")]
+    UnexpectedCommand {
+        command: String,
+        stage: HandshakeState,
+    },
+    // The announced payload length exceeds the maximum allowed
+    #[error("Payload of {length} bytes exceeds the maximum of {max} bytes")]
+    PayloadTooLarge { length: u32, max: u32 },
+    // A variable length field announces more bytes than allowed
+    #[error("Variable length field of {length} bytes exceeds the maximum of {max} bytes")]
+    FieldTooLong { length: u64, max: u64 },
+    // The remote node's protocol version is too old
+    #[error("Unsupported protocol version {0}")]
+    UnsupportedVersion(i32),
+    // The remote node is ourselves, detected thanks to the version nonce
+    #[error("Connected to self")]
+    SelfConnection,
+    // The remote node did not answer our ping in time
+    #[error("No pong received within {0:?}")]
+    PingTimeout(Duration),
+    // The remote node closed the connection
+    #[error("Remote node disconnected at stage {stage:?}")]
+    PeerDisconnected { stage: HandshakeState },
+    // The network name does not match any supported network
+    #[error("Unknown network {0}")]
+    UnknownNetwork(String),
+    // The service name does not match any known service
+    #[error("Unknown service {0}")]
+    UnknownService(String),
+    // The remote node does not offer every service we require
+    #[error("Remote node lacks the required services {missing}")]
+    MissingServices { missing: ServiceFlags },
+    // A hex encoded argument cannot be decoded
+    #[error("Invalid hex: {0}")]
+    InvalidHex(#[from] hex::FromHexError),
+    // A network parameters file cannot be read
+    #[error("Invalid network parameters: {0}")]
+    InvalidNetworkParams(#[from] toml::de::Error),
+    // A block header breaks the consensus rules of the network
+    #[error("Invalid block header {hash}: {reason}")]
+    InvalidHeader { hash: String, reason: &'static str },
+    // A block does not match its header or breaks the consensus rules of the network
+    #[error("Invalid block {hash}: {reason}")]
+    InvalidBlock { hash: String, reason: &'static str },
+    // The remote node does not have the requested object
+    #[error("Remote node does not have {0}")]
+    NotFound(String),
+    // Header chains cannot start without the genesis header of the network
+    #[error("No genesis header known for network {0}")]
+    MissingGenesisHeader(String),
+    // The message content cannot be decoded
+    #[error("Invalid message: {0}")]
+    InvalidMessage(&'static str),
+    // Any other I/O failure
+    #[error(transparent)]
+    Io(#[from] Error),
+}
+
+impl HandshakeError {
+    /// Classify an I/O error raised on an open connection at a given stage of the handshake
+    pub fn from_io(error: Error, stage: HandshakeState) -> Self {
+        match error.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => HandshakeError::Timeout { stage },
+            ErrorKind::UnexpectedEof
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe => HandshakeError::PeerDisconnected { stage },
+            _ => HandshakeError::Io(error),
+        }
+    }
+
+    /// Error for a header breaking a consensus rule, naming it by its usual hex hash
+    pub fn invalid_header(hash: &[u8; 32], reason: &'static str) -> Self {
+        HandshakeError::InvalidHeader {
+            hash: hash_to_hex(hash),
+            reason,
+        }
+    }
+
+    /// Error for a block breaking a consensus rule, naming it by its usual hex hash
+    pub fn invalid_block(hash: &[u8; 32], reason: &'static str) -> Self {
+        HandshakeError::InvalidBlock {
+            hash: hash_to_hex(hash),
+            reason,
+        }
+    }
+
+    /// Attach the stage of the handshake to an error raised by the underlying connection
+    pub fn at_stage(self, stage: HandshakeState) -> Self {
+        match self {
+            HandshakeError::Io(error) => HandshakeError::from_io(error, stage),
+            error => error,
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use super::error::HandshakeError;
+use super::messages::{
+    BitcoinMessage, Serializable, CHECKSUM_SIZE, COMMAND_SIZE, HEADER_SIZE, MAX_PAYLOAD_SIZE,
+};
+use super::network::BitcoinNetwork;
+use std::io::{Read, Write};
+
+/// Read framed Bitcoin messages one by one from any byte source
+/// Each message is checked against the expected network magic, the maximum payload
+/// size before anything gets allocated and finally its checksum
+#[derive(Debug)]
+pub struct MessageReader<R: Read> {
+    // Byte source holding the messages, usually a TCP stream
+    reader: R,
+    // Network the messages must belong to
+    network: BitcoinNetwork,
+    // Largest payload accepted from the byte source
+    max_payload_size: u32,
+}
+
+impl<R: Read> MessageReader<R> {
+    /// Create a reader accepting payloads up to the protocol's maximum size
+    pub fn new(reader: R, network: BitcoinNetwork) -> Self {
+        Self {
+            reader,
+            network,
+            max_payload_size: MAX_PAYLOAD_SIZE,
+        }
+    }
+
+    /// Lower or raise the largest payload accepted by the reader
+    pub fn with_max_payload_size(mut self, max_payload_size: u32) -> Self {
+        self.max_payload_size = max_payload_size;
+        self
+    }
+
+    /// Read the next whole message, header first then exactly its payload
+    pub fn read_message(&mut self) -> Result<BitcoinMessage, HandshakeError> {
+        let mut header = [0u8; HEADER_SIZE];
+        self.reader.read_exact(&mut header)?;
+        let header = MessageHeader::parse(&header, self.network, self.max_payload_size)?;
+
+        let mut payload = vec![0u8; header.length as usize];
+        self.reader.read_exact(&mut payload)?;
+
+        header.into_message(payload)
+    }
+
+    /// Reference to the underlying byte source
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    /// Mutable reference to the underlying byte source
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Take back the underlying byte source
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+/// Header fields of a message whose payload is still to be read
+#[derive(Debug)]
+pub(crate) struct MessageHeader {
+    // Magic Key for the Bitcoin network
+    magic: u32,
+    // ASCII string identifying the packet content
+    command: [u8; COMMAND_SIZE],
+    // Payload Length
+    pub(crate) length: u32,
+    // First 4 bytes of double Hash of payload
+    checksum: [u8; CHECKSUM_SIZE],
+}
+
+impl MessageHeader {
+    /// Decode a header and check it before its payload gets allocated
+    pub(crate) fn parse(
+        header: &[u8; HEADER_SIZE],
+        network: BitcoinNetwork,
+        max_payload_size: u32,
+    ) -> Result<Self, HandshakeError> {
+        // Check the magic before going further into a message from another network
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        if magic != network.as_u32() {
+            return Err(HandshakeError::WrongNetworkMagic {
+                expected: network.as_u32(),
+                got: magic,
+            });
+        }
+
+        let mut command = [0u8; COMMAND_SIZE];
+        command.copy_from_slice(&header[4..16]);
+
+        // Refuse oversized payloads before allocating any memory for them
+        let length = u32::from_le_bytes([header[16], header[17], header[18], header[19]]);
+        if length > max_payload_size {
+            return Err(HandshakeError::PayloadTooLarge {
+                length,
+                max: max_payload_size,
+            });
+        }
+
+        let mut checksum = [0u8; CHECKSUM_SIZE];
+        checksum.copy_from_slice(&header[20..HEADER_SIZE]);
+
+        Ok(Self {
+            magic,
+            command,
+            length,
+            checksum,
+        })
+    }
+
+    /// Complete the message with its payload, which must match the checksum
+    pub(crate) fn into_message(self, payload: Vec<u8>) -> Result<BitcoinMessage, HandshakeError> {
+        BitcoinMessage::from_parts(self.magic, self.command, self.checksum, payload)
+    }
+}
+
+/// Write framed Bitcoin messages to any byte sink
+#[derive(Debug)]
+pub struct MessageWriter<W: Write> {
+    // Byte sink receiving the messages, usually a TCP stream
+    writer: W,
+}
+
+impl<W: Write> MessageWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    /// Write a whole message, header and payload, and flush it
+    pub fn write_message(&mut self, message: &BitcoinMessage) -> Result<(), HandshakeError> {
+        self.writer.write_all(&message.serialize()?)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Reference to the underlying byte sink
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// Mutable reference to the underlying byte sink
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// Take back the underlying byte sink
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vv::Command;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_write_and_read_messages_ok() {
+        let network = BitcoinNetwork::Regtest;
+        let mut writer = MessageWriter::new(Vec::new());
+        writer
+            .write_message(&BitcoinMessage::new(
+                Command::Version,
+                vec![0x01, 0x02],
+                network,
+            ))
+            .expect("Failed to write version message");
+        writer
+            .write_message(&BitcoinMessage::new(Command::Verack, Vec::new(), network))
+            .expect("Failed to write verack message");
+
+        let mut reader = MessageReader::new(Cursor::new(writer.into_inner()), network);
+        let version = reader.read_message().expect("Failed to read version");
+        assert_eq!(
+            Command::from_fixed_length(version.command()).ok(),
+            Some(Command::Version)
+        );
+        assert_eq!(version.payload(), &[0x01, 0x02]);
+
+        let verack = reader.read_message().expect("Failed to read verack");
+        assert_eq!(
+            Command::from_fixed_length(verack.command()).ok(),
+            Some(Command::Verack)
+        );
+        assert!(verack.payload().is_empty());
+    }
+
+    #[test]
+    fn test_read_wrong_magic_error() {
+        let message = BitcoinMessage::new(Command::Verack, Vec::new(), BitcoinNetwork::Mainnet)
+            .serialize()
+            .expect("Bitcoin Message could not be serialized");
+
+        let mut reader = MessageReader::new(Cursor::new(message), BitcoinNetwork::Regtest);
+        assert!(matches!(
+            reader.read_message(),
+            Err(HandshakeError::WrongNetworkMagic { .. })
+        ));
+    }
+
+    #[test]
+    fn test_read_payload_too_large_error() {
+        // Only the header is available, the payload must not be waited for
+        let message = BitcoinMessage::new(Command::Version, vec![0; 16], BitcoinNetwork::Regtest)
+            .serialize()
+            .expect("Bitcoin Message could not be serialized");
+
+        let mut reader = MessageReader::new(
+            Cursor::new(message[..HEADER_SIZE].to_vec()),
+            BitcoinNetwork::Regtest,
+        )
+        .with_max_payload_size(8);
+        assert!(matches!(
+            reader.read_message(),
+            Err(HandshakeError::PayloadTooLarge { length: 16, max: 8 })
+        ));
+    }
+}
@@ -1,17 +1,674 @@
-use super::messages::{BitcoinMessage, Serializable};
+use super::clock::{Clock, NonceSource, OsNonceSource, SystemClock};
+use super::error::HandshakeError;
+use super::framing::{MessageReader, MessageWriter};
+use super::messages::{BitcoinMessage, NetworkMessage, Serializable};
+use super::negotiation::{
+    FeeFilterMessage, NegotiatedFeatures, SendCmpctMessage, SendTxRcnclMessage,
+    TXRECONCILIATION_VERSION,
+};
 use super::network::BitcoinNetwork;
-use super::vv::{Command, VerackMessage, VersionMessage};
-use std::io::{Error, ErrorKind, Read, Write};
-use std::net::{Shutdown, SocketAddr, TcpStream};
+use super::nonce::{NonceGuard, NonceRegistry};
+use super::ping::PongMessage;
+use super::services::ServiceFlags;
+use super::vv::{Command, ProtocolFeatures, VersionMessage, PROTOCOL_VERSION};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Maximum time allowed by default for each stage of the handshake
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+// Software advertised by default in our version message
+pub const DEFAULT_USER_AGENT: &str = "/node-handshake:0.1.0/";
+// Time the rest of a message has to arrive once its first byte did, same as the
+// inactivity timeout of Bitcoin Core
+pub const MESSAGE_TIMEOUT: Duration = Duration::from_secs(20 * 60);
+// Messages the remote node may send before its verack that the handshake ignores
+const MAX_IGNORED_MESSAGES: usize = 100;
+
+/// Progress of a handshake with a remote node
+/// States are ordered, each one implies all the previous steps were completed
+/// An inbound connection goes straight from Connected to GotVersion as our version
+/// is only sent in reply to the remote node's one
+/// More information https://en.bitcoin.it/wiki/Version_Handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HandshakeState {
+    // The TCP connection is open, no message was exchanged yet
+    Connected,
+    // Our version message has been sent to the remote node
+    SentVersion,
+    // The remote node's version message has been received
+    GotVersion,
+    // Our verack has been sent in response to the remote node's version
+    SentVerack,
+    // The remote node acknowledged our version with a verack
+    GotVerack,
+    // Both sides exchanged their version and verack messages
+    Established,
+}
+
+impl HandshakeState {
+    /// Move to the next state after receiving a message from the remote node
+    /// Only a single version followed by a single verack is accepted,
+    /// other messages are ignored once the remote version is known
+    pub fn on_received(self, command: Command) -> Result<Self, HandshakeError> {
+        match (self, command) {
+            (HandshakeState::Connected | HandshakeState::SentVersion, Command::Version) => {
+                Ok(HandshakeState::GotVersion)
+            }
+            (HandshakeState::SentVerack, Command::Verack) => Ok(HandshakeState::GotVerack),
+            // Nothing but a version is expected before the remote version
+            (HandshakeState::Connected | HandshakeState::SentVersion, _)
+            // Duplicate version or verack, or verack before ours was sent
+            | (_, Command::Version | Command::Verack) => Err(HandshakeError::UnexpectedCommand {
+                command: command.as_str().to_string(),
+                stage: self,
+            }),
+            (state, _) => Ok(state),
+        }
+    }
+
+    /// Move to the next state after sending a message to the remote node
+    /// Answering the remote version with ours keeps the state unchanged
+    pub fn on_sent(self, command: Command) -> Result<Self, HandshakeError> {
+        match (self, command) {
+            (HandshakeState::Connected, Command::Version) => Ok(HandshakeState::SentVersion),
+            (HandshakeState::GotVersion, Command::Version) => Ok(HandshakeState::GotVersion),
+            (HandshakeState::GotVersion, Command::Verack) => Ok(HandshakeState::SentVerack),
+            _ => Err(HandshakeError::UnexpectedCommand {
+                command: command.as_str().to_string(),
+                stage: self,
+            }),
+        }
+    }
+
+    /// Complete the handshake once both verack messages were exchanged
+    pub fn complete(self) -> Self {
+        match self {
+            HandshakeState::GotVerack => HandshakeState::Established,
+            state => state,
+        }
+    }
+}
+
+/// Time limits applied to each stage of a handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeTimeouts {
+    // Maximum time to open the TCP connection
+    pub connect: Duration,
+    // Maximum time to wait for the remote node's version after sending ours
+    pub version: Duration,
+    // Maximum time to wait for the remote node's verack after sending ours
+    pub verack: Duration,
+}
+
+impl HandshakeTimeouts {
+    /// Time allowed for the remote node's next message at a given stage
+    pub fn for_stage(&self, stage: HandshakeState) -> Duration {
+        match stage {
+            HandshakeState::Connected | HandshakeState::SentVersion => self.version,
+            _ => self.verack,
+        }
+    }
+}
+
+impl Default for HandshakeTimeouts {
+    fn default() -> Self {
+        Self {
+            connect: DEFAULT_TIMEOUT,
+            version: DEFAULT_TIMEOUT,
+            verack: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+/// Settings of our node advertised and applied during a handshake
+#[derive(Debug, Clone)]
+pub struct HandshakeConfig {
+    // Network both nodes must belong to
+    pub network: BitcoinNetwork,
+    // Protocol version advertised in our version message
+    pub protocol_version: i32,
+    // Software advertised in our version message
+    pub user_agent: String,
+    // Highest block known by our node
+    pub start_height: i32,
+    // Whether the remote node should relay transactions to us
+    pub relay: bool,
+    // Services advertised in our version message
+    pub services: ServiceFlags,
+    // Services the remote node must offer for the handshake to succeed
+    pub required_services: ServiceFlags,
+    // Offer transaction reconciliation when both nodes relay transactions, see BIP330
+    pub tx_reconciliation: bool,
+    // Ask for new blocks to be announced with headers once connected, see BIP130
+    pub send_headers: bool,
+    // Compact blocks preference sent once connected, see BIP152
+    pub compact_blocks: Option<SendCmpctMessage>,
+    // Lowest fee rate in sat/kvB of the transactions announced to us, sent once connected
+    pub fee_filter: Option<i64>,
+    // Nonces of our outbound handshakes, share it between configs to detect self connections
+    pub nonces: NonceRegistry,
+    // Source of the timestamp of our version message
+    pub clock: Arc<dyn Clock>,
+    // Source of the nonce of our version message
+    pub nonce_source: Arc<dyn NonceSource>,
+    // Time limits for each stage of the handshake
+    pub timeouts: HandshakeTimeouts,
+}
+
+impl HandshakeConfig {
+    /// Default settings for a handshake on a given network
+    pub fn new(network: BitcoinNetwork) -> Self {
+        Self {
+            network,
+            protocol_version: PROTOCOL_VERSION,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            start_height: 0,
+            relay: false,
+            services: ServiceFlags::NODE_NETWORK,
+            required_services: ServiceFlags::NONE,
+            tx_reconciliation: false,
+            send_headers: false,
+            compact_blocks: None,
+            fee_filter: None,
+            nonces: NonceRegistry::new(),
+            clock: Arc::new(SystemClock),
+            nonce_source: Arc::new(OsNonceSource),
+            timeouts: HandshakeTimeouts::default(),
+        }
+    }
+}
+
+/// Result of a successful handshake with a remote node
+/// Holds what the remote node advertised along with the still open connection
+#[derive(Debug)]
+pub struct HandshakeOutcome<S = TcpStream> {
+    // Network both nodes belong to
+    network: BitcoinNetwork,
+    // Version message received from the remote node
+    peer_version: VersionMessage,
+    // Protocol version both nodes can use, lowest of the two advertised versions
+    negotiated_version: i32,
+    // Features agreed through the negotiation messages of both nodes
+    negotiated: NegotiatedFeatures,
+    // Time between sending our version and receiving the remote node's verack
+    round_trip_time: Duration,
+    // Socket address of our side of the connection
+    local_addr: SocketAddr,
+    // Socket address of the remote node
+    remote_addr: SocketAddr,
+    // Connection to the remote node, ready for further messages
+    stream: S,
+}
+
+impl<S> HandshakeOutcome<S> {
+    /// Network both nodes belong to
+    pub fn network(&self) -> BitcoinNetwork {
+        self.network
+    }
+
+    /// Version message received from the remote node
+    pub fn peer_version(&self) -> &VersionMessage {
+        &self.peer_version
+    }
+
+    /// Protocol version both nodes can use
+    pub fn negotiated_version(&self) -> i32 {
+        self.negotiated_version
+    }
+
+    /// Features both nodes can use at the negotiated version
+    pub fn features(&self) -> ProtocolFeatures {
+        ProtocolFeatures::for_version(self.negotiated_version)
+    }
+
+    /// Features agreed through negotiation messages, those the remote node sends after
+    /// verack are recorded as messages are received
+    pub fn negotiated(&self) -> &NegotiatedFeatures {
+        &self.negotiated
+    }
+
+    /// Time between sending our version and receiving the remote node's verack
+    pub fn round_trip_time(&self) -> Duration {
+        self.round_trip_time
+    }
+
+    /// Socket address of our side of the connection
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Socket address of the remote node
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    /// Connection to the remote node
+    pub fn stream(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Take ownership of the connection to the remote node
+    pub fn into_stream(self) -> S {
+        self.stream
+    }
+
+    /// Check a message received on the established connection and record the
+    /// preferences it carries
+    pub(crate) fn on_received(&mut self, message: &NetworkMessage) -> Result<(), HandshakeError> {
+        self.negotiated.on_message_after_verack(message)
+    }
+}
+
+impl HandshakeOutcome<TcpStream> {
+    /// Send a message to the remote node over the established connection
+    pub fn send(&mut self, message: &NetworkMessage) -> Result<(), HandshakeError> {
+        let message = message.to_bitcoin_message(self.network)?;
+        MessageWriter::new(&mut self.stream).write_message(&message)
+    }
+
+    /// Wait for the next message from the remote node and decode it
+    pub fn receive(&mut self) -> Result<NetworkMessage, HandshakeError> {
+        let message = MessageReader::new(&mut self.stream, self.network).read_message()?;
+        let message = NetworkMessage::from_bitcoin_message(&message)?;
+        self.on_received(&message)?;
+        Ok(message)
+    }
+
+    /// Wait for the next message from the remote node other than a ping, until the deadline
+    /// Pings received meanwhile are answered. The deadline only bounds the wait for a
+    /// message to start, one already arriving is read whole within `MESSAGE_TIMEOUT` so
+    /// the connection stays usable. The read timeout of the stream is restored before
+    /// returning, whatever the outcome
+    pub fn receive_until(&mut self, deadline: Instant) -> Result<NetworkMessage, HandshakeError> {
+        self.try_receive_until(deadline)?
+            .ok_or(HandshakeError::Timeout {
+                stage: HandshakeState::Established,
+            })
+    }
+
+    /// Same as `receive_until`, with `None` when the deadline passes before the remote
+    /// node starts another message, which leaves the connection in sync
+    pub(crate) fn try_receive_until(
+        &mut self,
+        deadline: Instant,
+    ) -> Result<Option<NetworkMessage>, HandshakeError> {
+        let previous = self.stream.read_timeout()?;
+        let result = self.receive_pinged_until(deadline);
+        self.stream.set_read_timeout(previous)?;
+        result.map_err(|e| e.at_stage(HandshakeState::Established))
+    }
+
+    fn receive_pinged_until(
+        &mut self,
+        deadline: Instant,
+    ) -> Result<Option<NetworkMessage>, HandshakeError> {
+        while self.wait_until(deadline)? {
+            self.stream.set_read_timeout(Some(MESSAGE_TIMEOUT))?;
+            match self.receive()? {
+                NetworkMessage::Ping(ping) => {
+                    if let Some(nonce) = ping.nonce() {
+                        self.send(&NetworkMessage::Pong(PongMessage::new(nonce)))?;
+                    }
+                }
+                message => return Ok(Some(message)),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Wait for the remote node to start its next message without reading any of it,
+    /// so reaching the deadline never leaves half a message behind
+    /// Returns false once the deadline passed, the read timeout is left for the caller
+    pub(crate) fn wait_until(&mut self, deadline: Instant) -> Result<bool, HandshakeError> {
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(false);
+            }
+            self.stream.set_read_timeout(Some(remaining))?;
+            match self.stream.peek(&mut [0u8; 1]) {
+                Ok(0) => {
+                    return Err(HandshakeError::PeerDisconnected {
+                        stage: HandshakeState::Established,
+                    })
+                }
+                Ok(_) => return Ok(true),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// Transport independent side of a handshake, either outbound or inbound
+/// Drivers write the messages it produces and feed it the messages they read,
+/// so blocking and async connections share the same protocol rules
+#[derive(Debug)]
+pub(crate) struct Handshake {
+    // Network both nodes must belong to
+    network: BitcoinNetwork,
+    // Whether the remote node opened the connection
+    inbound: bool,
+    // Services the remote node must offer
+    required_services: ServiceFlags,
+    // Progress of the handshake
+    state: HandshakeState,
+    // Our version message, kept to check the remote nonce
+    version_message: VersionMessage,
+    // Nonces of our outbound handshakes in progress
+    nonces: NonceRegistry,
+    // Keeps the nonce of an outbound handshake registered until it ends
+    _nonce_guard: Option<NonceGuard>,
+    // Version message received from the remote node
+    peer_version: Option<VersionMessage>,
+    // Salt of our sendtxrcncl, when we offer transaction reconciliation
+    tx_reconciliation_salt: Option<u64>,
+    // Preferences sent once the handshake completes
+    send_headers: bool,
+    compact_blocks: Option<SendCmpctMessage>,
+    fee_filter: Option<i64>,
+    // Whether we sent wtxidrelay and sendtxrcncl before our verack
+    sent_wtxid_relay: bool,
+    sent_tx_reconciliation: bool,
+    // Features agreed so far through negotiation messages
+    negotiated: NegotiatedFeatures,
+    // Moment our version message was sent
+    sent_at: Instant,
+    // Time between sending our version and receiving the remote node's verack
+    round_trip_time: Duration,
+    // Time limits for each stage of the handshake
+    timeouts: HandshakeTimeouts,
+    // Moment the current stage times out, set when the stage is entered
+    stage_deadline: Instant,
+    // Messages ignored so far while waiting for the handshake to complete
+    ignored_messages: usize,
+}
+
+impl Handshake {
+    /// Handshake of a connection we opened, we speak first
+    pub(crate) fn outbound(
+        config: &HandshakeConfig,
+        sender: SocketAddr,
+        receiver: SocketAddr,
+    ) -> Self {
+        Self::new(config, false, receiver, sender)
+    }
+
+    /// Handshake of a connection the remote node opened, it speaks first
+    pub(crate) fn inbound(
+        config: &HandshakeConfig,
+        local_addr: SocketAddr,
+        remote_addr: SocketAddr,
+    ) -> Self {
+        Self::new(config, true, remote_addr, local_addr)
+    }
+
+    fn new(
+        config: &HandshakeConfig,
+        inbound: bool,
+        receiver: SocketAddr,
+        sender: SocketAddr,
+    ) -> Self {
+        let version_message = VersionMessage::with_sources(
+            config.protocol_version,
+            receiver,
+            sender,
+            config.services,
+            config.user_agent.clone(),
+            config.start_height,
+            config.relay,
+            config.clock.as_ref(),
+            config.nonce_source.as_ref(),
+        );
+        // Only our outbound nonces can come back to us through another connection
+        let nonce_guard = (!inbound).then(|| config.nonces.register(version_message.nonce()));
+        Self {
+            network: config.network,
+            inbound,
+            required_services: config.required_services,
+            state: HandshakeState::Connected,
+            version_message,
+            nonces: config.nonces.clone(),
+            _nonce_guard: nonce_guard,
+            peer_version: None,
+            tx_reconciliation_salt: config
+                .tx_reconciliation
+                .then(|| config.nonce_source.next_nonce()),
+            send_headers: config.send_headers,
+            compact_blocks: config.compact_blocks,
+            fee_filter: config.fee_filter,
+            sent_wtxid_relay: false,
+            sent_tx_reconciliation: false,
+            negotiated: NegotiatedFeatures::default(),
+            sent_at: Instant::now(),
+            round_trip_time: Duration::ZERO,
+            timeouts: config.timeouts,
+            stage_deadline: Instant::now() + config.timeouts.for_stage(HandshakeState::Connected),
+            ignored_messages: 0,
+        }
+    }
+
+    /// Messages opening the handshake, only our version for an outbound connection
+    pub(crate) fn start(&mut self) -> Result<Vec<BitcoinMessage>, HandshakeError> {
+        if self.inbound {
+            return Ok(Vec::new());
+        }
+        let version = self.send_version()?;
+        self.enter_stage();
+        Ok(vec![version])
+    }
+
+    pub(crate) fn state(&self) -> HandshakeState {
+        self.state
+    }
+
+    pub(crate) fn is_established(&self) -> bool {
+        self.state == HandshakeState::Established
+    }
+
+    /// Time left for the remote node's next message before the current stage times out
+    /// The deadline is set once per stage, so messages ignored meanwhile do not extend it
+    pub(crate) fn remaining(&self) -> Result<Duration, HandshakeError> {
+        let remaining = self
+            .stage_deadline
+            .saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(HandshakeError::Timeout { stage: self.state });
+        }
+        Ok(remaining)
+    }
+
+    /// Start the clock of the stage the handshake just moved to
+    fn enter_stage(&mut self) {
+        self.stage_deadline = Instant::now() + self.timeouts.for_stage(self.state);
+    }
+
+    /// Process a message from the remote node and return the replies to send
+    pub(crate) fn on_message(
+        &mut self,
+        message: &BitcoinMessage,
+    ) -> Result<Vec<BitcoinMessage>, HandshakeError> {
+        let command = Command::from_fixed_length(message.command())?;
+        let stage = self.state;
+        self.state = self.state.on_received(command)?;
+
+        let mut replies = Vec::new();
+        match NetworkMessage::from_bitcoin_message(message)? {
+            NetworkMessage::Version(version) => self.on_version(version, &mut replies)?,
+            NetworkMessage::Verack => self.round_trip_time = self.sent_at.elapsed(),
+            NetworkMessage::WtxidRelay => self.negotiated.wtxid_relay = self.sent_wtxid_relay,
+            NetworkMessage::SendAddrV2 => self.negotiated.addr_v2 = true,
+            NetworkMessage::SendTxRcncl(sendtxrcncl) => self.on_sendtxrcncl(sendtxrcncl)?,
+            // Like Bitcoin Core, other messages are ignored until the handshake completes,
+            // as long as they do not keep the remote node from ever sending its verack
+            _ => {
+                self.ignored_messages += 1;
+                if self.ignored_messages > MAX_IGNORED_MESSAGES {
+                    return Err(HandshakeError::UnexpectedCommand {
+                        command: command.as_str().to_string(),
+                        stage: self.state,
+                    });
+                }
+            }
+        }
+        self.state = self.state.complete();
+        if self.state != stage {
+            self.enter_stage();
+        }
+        if self.is_established() {
+            self.send_preferences(&mut replies)?;
+        }
+        Ok(replies)
+    }
+
+    /// Check an offer of transaction reconciliation, agreed if we made one too
+    fn on_sendtxrcncl(&mut self, sendtxrcncl: SendTxRcnclMessage) -> Result<(), HandshakeError> {
+        let relay = self
+            .peer_version
+            .as_ref()
+            .is_some_and(VersionMessage::relay);
+        // Like Bitcoin Core, offers are only allowed when both nodes relay transactions
+        if !relay || !self.version_message.relay() {
+            return Err(HandshakeError::UnexpectedCommand {
+                command: Command::SendTxRcncl.as_str().to_string(),
+                stage: self.state,
+            });
+        }
+        if sendtxrcncl.version < 1 {
+            return Err(HandshakeError::InvalidMessage(
+                "Invalid sendtxrcncl version",
+            ));
+        }
+        self.negotiated.tx_reconciliation = self.sent_tx_reconciliation;
+        Ok(())
+    }
+
+    /// Send our preferences once the handshake is complete, as far as the negotiated
+    /// version allows
+    fn send_preferences(&self, replies: &mut Vec<BitcoinMessage>) -> Result<(), HandshakeError> {
+        let peer_version = self
+            .peer_version
+            .as_ref()
+            .map_or(0, VersionMessage::version);
+        let features =
+            ProtocolFeatures::for_version(self.version_message.version().min(peer_version));
+        let mut preferences = Vec::new();
+        if self.send_headers && features.send_headers {
+            preferences.push(NetworkMessage::SendHeaders);
+        }
+        if let Some(sendcmpct) = self.compact_blocks.filter(|_| features.compact_blocks) {
+            preferences.push(NetworkMessage::SendCmpct(sendcmpct));
+        }
+        if let Some(fee_rate) = self.fee_filter.filter(|_| features.fee_filter) {
+            preferences.push(NetworkMessage::FeeFilter(FeeFilterMessage { fee_rate }));
+        }
+        for message in preferences {
+            replies.push(message.to_bitcoin_message(self.network)?);
+        }
+        Ok(())
+    }
+
+    /// Check the remote version, then answer it with ours if needed and a verack
+    fn on_version(
+        &mut self,
+        version: VersionMessage,
+        replies: &mut Vec<BitcoinMessage>,
+    ) -> Result<(), HandshakeError> {
+        if version.nonce() == self.version_message.nonce() || self.nonces.contains(version.nonce())
+        {
+            return Err(HandshakeError::SelfConnection);
+        }
+        if version.version() < self.network.params().min_protocol_version {
+            return Err(HandshakeError::UnsupportedVersion(version.version()));
+        }
+        let missing = self.required_services - version.services();
+        if !missing.is_empty() {
+            return Err(HandshakeError::MissingServices { missing });
+        }
+        let features =
+            ProtocolFeatures::for_version(self.version_message.version().min(version.version()));
+        let relay = version.relay() && self.version_message.relay();
+        self.peer_version = Some(version);
+
+        // Introduce ourselves to a node that connected to us
+        if self.inbound {
+            replies.push(self.send_version()?);
+        }
+
+        // Negotiation messages must come between version and verack
+        let mut negotiation = Vec::new();
+        if features.wtxid_relay {
+            negotiation.push(NetworkMessage::WtxidRelay);
+            self.sent_wtxid_relay = true;
+        }
+        if features.addr_v2 {
+            negotiation.push(NetworkMessage::SendAddrV2);
+        }
+        // Reconciliation relies on wtxids and is pointless without transaction relay
+        if let Some(salt) = self
+            .tx_reconciliation_salt
+            .filter(|_| features.wtxid_relay && relay)
+        {
+            negotiation.push(NetworkMessage::SendTxRcncl(SendTxRcnclMessage {
+                version: TXRECONCILIATION_VERSION,
+                salt,
+            }));
+            self.sent_tx_reconciliation = true;
+        }
+        for message in negotiation {
+            replies.push(message.to_bitcoin_message(self.network)?);
+        }
+
+        // Acknowledge the remote node's version
+        replies.push(NetworkMessage::Verack.to_bitcoin_message(self.network)?);
+        self.state = self.state.on_sent(Command::Verack)?;
+        Ok(())
+    }
+
+    /// Produce our version message, the clock for the round trip starts here
+    fn send_version(&mut self) -> Result<BitcoinMessage, HandshakeError> {
+        let payload = self.version_message.serialize()?;
+        self.state = self.state.on_sent(Command::Version)?;
+        self.sent_at = Instant::now();
+        Ok(BitcoinMessage::new(Command::Version, payload, self.network))
+    }
+
+    /// Turn the established handshake into its outcome holding the connection
+    pub(crate) fn finish<S>(
+        self,
+        stream: S,
+        local_addr: SocketAddr,
+        remote_addr: SocketAddr,
+    ) -> Result<HandshakeOutcome<S>, HandshakeError> {
+        // The state machine only completes after a version was received
+        let peer_version = self
+            .peer_version
+            .ok_or(HandshakeError::InvalidMessage("Missing version message"))?;
+        Ok(HandshakeOutcome {
+            network: self.network,
+            negotiated_version: self.version_message.version().min(peer_version.version()),
+            peer_version,
+            negotiated: self.negotiated,
+            round_trip_time: self.round_trip_time,
+            local_addr,
+            remote_addr,
+            stream,
+        })
+    }
+}
 
 /// Establish a TCP connection to a Bitcoin node for one of its network
-/// Performs the handshake protocol by sending the intial version, then  waiting for the reply
-/// the verack message and finally closes the connection
+/// Performs the handshake protocol by sending the initial version, then waiting for the
+/// remote node's version that is acknowledged with our verack, and finally waiting for
+/// the remote node's verack
+/// The connection is left open and returned with the remote node's details
 /// *Arguments
-/// network - network type between Mainnet, Testnet3 and Regtest
+/// network - network type, e.g. Mainnet, Testnet4, Signet or Regtest
 /// sender - sending node's socket address
 /// receiver - receiving node's socket address
-/// user_agent - user agent's string - //TODO: removed it or test with other value
+/// user_agent - software advertised in our version message, e.g. /node-handshake:0.1.0/ (BIP14)
 /// start_height - node's block height
 pub fn perform_handshake(
     network: BitcoinNetwork,
@@ -19,58 +676,394 @@ pub fn perform_handshake(
     receiver: SocketAddr,
     user_agent: String,
     start_height: i32,
-) -> Result<(), Error> {
-    let mut stream = TcpStream::connect(sender)?;
+) -> Result<HandshakeOutcome, HandshakeError> {
+    let config = HandshakeConfig {
+        user_agent,
+        start_height,
+        ..HandshakeConfig::new(network)
+    };
+    perform_handshake_with_config(&config, sender, receiver)
+}
+
+/// Same as `perform_handshake` with every setting of our node taken from the config
+pub fn perform_handshake_with_config(
+    config: &HandshakeConfig,
+    sender: SocketAddr,
+    receiver: SocketAddr,
+) -> Result<HandshakeOutcome, HandshakeError> {
+    let stream = TcpStream::connect_timeout(&sender, config.timeouts.connect)
+        .map_err(HandshakeError::ConnectionFailed)?;
+    let handshake = Handshake::outbound(config, sender, receiver);
+    run_handshake(config, handshake, stream)
+}
+
+/// Answer the handshake of a remote node on a connection it opened to us
+/// Waits for the remote node's version, replies with our version and verack,
+/// and finally waits for the remote node's verack
+pub fn accept_handshake(
+    config: &HandshakeConfig,
+    stream: TcpStream,
+) -> Result<HandshakeOutcome, HandshakeError> {
+    let handshake = Handshake::inbound(config, stream.local_addr()?, stream.peer_addr()?);
+    run_handshake(config, handshake, stream)
+}
+
+/// Drive a handshake over a blocking TCP stream until both sides are established
+fn run_handshake(
+    config: &HandshakeConfig,
+    mut handshake: Handshake,
+    stream: TcpStream,
+) -> Result<HandshakeOutcome, HandshakeError> {
+    stream.set_write_timeout(Some(config.timeouts.verack))?;
+    let local_addr = stream.local_addr()?;
+    let remote_addr = stream.peer_addr()?;
 
-    // Create Version Message
-    let version_message = VersionMessage::new(receiver, sender, user_agent, start_height, false);
+    // Both directions of the connection are framed from shared references to the stream
+    let mut reader = MessageReader::new(&stream, config.network);
+    let mut writer = MessageWriter::new(&stream);
 
-    // Prepare Bitcoin message payload ready to be sent
-    let vrs_msg_payload = version_message.serialize()?;
+    // Send the version message to initialize handshake when we are the one connecting
+    for message in handshake.start()? {
+        writer
+            .write_message(&message)
+            .map_err(|e| e.at_stage(handshake.state()))?;
+    }
+
+    while !handshake.is_established() {
+        let stage = handshake.state();
+        stream.set_read_timeout(Some(handshake.remaining()?))?;
+        let message = reader.read_message().map_err(|e| e.at_stage(stage))?;
+        for reply in handshake.on_message(&message)? {
+            writer
+                .write_message(&reply)
+                .map_err(|e| e.at_stage(stage))?;
+        }
+    }
+
+    // Leave the connection in blocking mode without time limits for the caller
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)?;
+    handshake.finish(stream, local_addr, remote_addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{FixedClock, FixedNonceSource};
+    use crate::negotiation::CMPCTBLOCKS_VERSION;
+
+    #[test]
+    fn test_handshake_states_ok() {
+        let state = HandshakeState::Connected
+            .on_sent(Command::Version)
+            .and_then(|state| state.on_received(Command::Version))
+            .and_then(|state| state.on_sent(Command::Verack))
+            .and_then(|state| state.on_received(Command::Verack))
+            .expect("Legal sequence should be accepted");
+        assert_eq!(state.complete(), HandshakeState::Established);
+    }
+
+    #[test]
+    fn test_inbound_handshake_states_ok() {
+        let state = HandshakeState::Connected
+            .on_received(Command::Version)
+            .and_then(|state| state.on_sent(Command::Version))
+            .and_then(|state| state.on_sent(Command::Verack))
+            .and_then(|state| state.on_received(Command::Verack))
+            .expect("Legal inbound sequence should be accepted");
+        assert_eq!(state.complete(), HandshakeState::Established);
+    }
+
+    #[test]
+    fn test_handshake_ignores_unknown_messages_after_version() {
+        let ping = Command::from_name("ping").expect("Valid command name");
+        let state = HandshakeState::SentVersion
+            .on_received(Command::Version)
+            .and_then(|state| state.on_received(ping))
+            .and_then(|state| state.on_sent(Command::Verack))
+            .and_then(|state| state.on_received(ping))
+            .expect("Unknown messages after version should be ignored");
+        assert_eq!(state, HandshakeState::SentVerack);
+    }
+
+    #[test]
+    fn test_handshake_message_before_version_error() {
+        assert!(HandshakeState::SentVersion
+            .on_received(Command::Verack)
+            .is_err());
+        let ping = Command::from_name("ping").expect("Valid command name");
+        assert!(HandshakeState::SentVersion.on_received(ping).is_err());
+        assert!(HandshakeState::Connected
+            .on_received(Command::Verack)
+            .is_err());
+    }
+
+    #[test]
+    fn test_handshake_duplicate_messages_error() {
+        assert!(HandshakeState::SentVerack
+            .on_received(Command::Version)
+            .is_err());
+        assert!(HandshakeState::GotVerack
+            .on_received(Command::Verack)
+            .is_err());
+    }
+
+    #[test]
+    fn test_handshake_self_connection_error() {
+        let config = HandshakeConfig::new(BitcoinNetwork::Regtest);
+        let addr: SocketAddr = "127.0.0.1:18444".parse().expect("Invalid socket address");
 
-    // Build the Bitcoin Message with Version Type to initialize handshake
-    let bitcoin_message = BitcoinMessage::new(Command::Version, vrs_msg_payload, network);
+        let mut outbound = Handshake::outbound(&config, addr, addr);
+        let version = outbound
+            .start()
+            .expect("Failed to start handshake")
+            .remove(0);
+        assert_eq!(config.nonces.len(), 1);
 
-    let serialized_btc_msg = bitcoin_message
-        .serialize()
-        .expect("Bitcoin Message could not be serialized");
+        // Our own version coming back on an inbound connection sharing the registry
+        let mut inbound = Handshake::inbound(&config, addr, addr);
+        assert!(matches!(
+            inbound.on_message(&version),
+            Err(HandshakeError::SelfConnection)
+        ));
 
-    stream.write_all(&serialized_btc_msg).unwrap();
-    stream.flush().unwrap();
+        // The nonce is released once the outbound handshake is over
+        drop(outbound);
+        assert!(config.nonces.is_empty());
+        let mut inbound = Handshake::inbound(&config, addr, addr);
+        assert!(inbound.on_message(&version).is_ok());
+    }
+
+    // Feed messages to a handshake and collect its replies
+    fn exchange(handshake: &mut Handshake, messages: &[BitcoinMessage]) -> Vec<BitcoinMessage> {
+        messages
+            .iter()
+            .flat_map(|message| handshake.on_message(message).expect("Legal message"))
+            .collect()
+    }
 
-    let mut res_version_msg = [0; 24];
+    fn commands(messages: &[BitcoinMessage]) -> Vec<Command> {
+        messages
+            .iter()
+            .map(|message| Command::from_fixed_length(message.command()).expect("Valid command"))
+            .collect()
+    }
+
+    #[test]
+    fn test_handshake_negotiation_before_verack_ok() {
+        // Each side has its own nonce registry, as two distinct nodes would
+        let addr: SocketAddr = "127.0.0.1:18444".parse().expect("Invalid socket address");
+        let config = || HandshakeConfig {
+            relay: true,
+            tx_reconciliation: true,
+            ..HandshakeConfig::new(BitcoinNetwork::Regtest)
+        };
+        let mut outbound = Handshake::outbound(&config(), addr, addr);
+        let mut inbound = Handshake::inbound(&config(), addr, addr);
 
-    match stream.read_exact(&mut res_version_msg) {
-        Ok(_) => {
-            // Read Verack message response and
-            // Verify some of its content regarding the version message
-            VerackMessage::deserialize_and_verify(
-                res_version_msg.into(),
-                network,
+        let version = outbound.start().expect("Failed to start handshake");
+        let replies = exchange(&mut inbound, &version);
+        assert_eq!(
+            commands(&replies),
+            [
                 Command::Version,
-            )
-            .unwrap();
-        }
-        Err(e) => {
-            // Handle different error types
-            match e.kind() {
-                ErrorKind::UnexpectedEof => {
-                    // Not enough bytes were available to read
-                    eprintln!("Unexpected end of file: {:?}", e);
-                }
-                ErrorKind::WouldBlock => {
-                    // The operation would block but the socket is set to non-blocking mode
-                    eprintln!("Operation would block: {:?}", e);
-                }
-                _ => {
-                    // Unspecified error occurred
-                    eprintln!("Failed to read the version message answer: {:?}", e);
-                }
-            }
+                Command::WtxidRelay,
+                Command::SendAddrV2,
+                Command::SendTxRcncl,
+                Command::Verack
+            ]
+        );
+        let replies = exchange(&mut outbound, &replies);
+        assert_eq!(
+            commands(&replies),
+            [
+                Command::WtxidRelay,
+                Command::SendAddrV2,
+                Command::SendTxRcncl,
+                Command::Verack
+            ]
+        );
+        exchange(&mut inbound, &replies);
+
+        for handshake in [outbound, inbound] {
+            assert!(handshake.is_established());
+            let outcome = handshake
+                .finish((), addr, addr)
+                .expect("Failed to finish handshake");
+            let negotiated = outcome.negotiated();
+            assert!(negotiated.wtxid_relay && negotiated.addr_v2 && negotiated.tx_reconciliation);
         }
     }
 
-    let _ = stream.shutdown(Shutdown::Both);
+    #[test]
+    fn test_handshake_preferences_after_verack_ok() {
+        let addr: SocketAddr = "127.0.0.1:18444".parse().expect("Invalid socket address");
+        let mut outbound =
+            Handshake::outbound(&HandshakeConfig::new(BitcoinNetwork::Regtest), addr, addr);
+        let config = HandshakeConfig {
+            send_headers: true,
+            compact_blocks: Some(SendCmpctMessage {
+                announce: false,
+                version: CMPCTBLOCKS_VERSION,
+            }),
+            fee_filter: Some(1000),
+            ..HandshakeConfig::new(BitcoinNetwork::Regtest)
+        };
+        let mut inbound = Handshake::inbound(&config, addr, addr);
+
+        let version = outbound.start().expect("Failed to start handshake");
+        let replies = exchange(&mut inbound, &version);
+        let replies = exchange(&mut outbound, &replies);
+        // Preferences follow the verack completing the handshake
+        assert_eq!(
+            commands(&exchange(&mut inbound, &replies)),
+            [Command::SendHeaders, Command::SendCmpct, Command::FeeFilter]
+        );
+
+        // Without transaction relay, reconciliation is neither offered nor agreed
+        let outcome = outbound
+            .finish((), addr, addr)
+            .expect("Failed to finish handshake");
+        assert!(outcome.negotiated().wtxid_relay);
+        assert!(!outcome.negotiated().tx_reconciliation);
+    }
+
+    #[test]
+    fn test_handshake_sendtxrcncl_without_relay_error() {
+        let addr: SocketAddr = "127.0.0.1:18444".parse().expect("Invalid socket address");
+        let config = HandshakeConfig {
+            relay: true,
+            tx_reconciliation: true,
+            ..HandshakeConfig::new(BitcoinNetwork::Regtest)
+        };
+        let mut outbound = Handshake::outbound(&config, addr, addr);
+        let mut inbound =
+            Handshake::inbound(&HandshakeConfig::new(BitcoinNetwork::Regtest), addr, addr);
+
+        // A peer offering reconciliation although we asked for no transactions
+        let version = outbound.start().expect("Failed to start handshake");
+        let sendtxrcncl = NetworkMessage::SendTxRcncl(SendTxRcnclMessage {
+            version: TXRECONCILIATION_VERSION,
+            salt: 7,
+        })
+        .to_bitcoin_message(BitcoinNetwork::Regtest)
+        .expect("Failed to encode sendtxrcncl");
+        exchange(&mut inbound, &version);
+        assert!(matches!(
+            inbound.on_message(&sendtxrcncl),
+            Err(HandshakeError::UnexpectedCommand { .. })
+        ));
+    }
+
+    #[test]
+    fn test_handshake_ignored_messages_error() {
+        let addr: SocketAddr = "127.0.0.1:18444".parse().expect("Invalid socket address");
+        let mut outbound =
+            Handshake::outbound(&HandshakeConfig::new(BitcoinNetwork::Regtest), addr, addr);
+        let mut inbound =
+            Handshake::inbound(&HandshakeConfig::new(BitcoinNetwork::Regtest), addr, addr);
 
-    Ok(())
+        // A peer that sends its version then anything but a verack
+        let version = outbound.start().expect("Failed to start handshake");
+        exchange(&mut inbound, &version);
+        let getaddr = NetworkMessage::GetAddr
+            .to_bitcoin_message(BitcoinNetwork::Regtest)
+            .expect("Failed to encode getaddr");
+        for _ in 0..MAX_IGNORED_MESSAGES {
+            exchange(&mut inbound, std::slice::from_ref(&getaddr));
+        }
+        assert!(matches!(
+            inbound.on_message(&getaddr),
+            Err(HandshakeError::UnexpectedCommand {
+                stage: HandshakeState::SentVerack,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_handshake_stage_deadline_error() {
+        let addr: SocketAddr = "127.0.0.1:18444".parse().expect("Invalid socket address");
+        let mut outbound =
+            Handshake::outbound(&HandshakeConfig::new(BitcoinNetwork::Regtest), addr, addr);
+        let config = HandshakeConfig {
+            timeouts: HandshakeTimeouts {
+                verack: Duration::from_millis(100),
+                ..HandshakeTimeouts::default()
+            },
+            ..HandshakeConfig::new(BitcoinNetwork::Regtest)
+        };
+        let mut inbound = Handshake::inbound(&config, addr, addr);
+
+        let version = outbound.start().expect("Failed to start handshake");
+        exchange(&mut inbound, &version);
+        // Ignored messages do not give the remote node more time for its verack
+        let getaddr = NetworkMessage::GetAddr
+            .to_bitcoin_message(BitcoinNetwork::Regtest)
+            .expect("Failed to encode getaddr");
+        std::thread::sleep(Duration::from_millis(60));
+        exchange(&mut inbound, std::slice::from_ref(&getaddr));
+        assert!(inbound.remaining().is_ok());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(matches!(
+            inbound.remaining(),
+            Err(HandshakeError::Timeout {
+                stage: HandshakeState::SentVerack
+            })
+        ));
+    }
+
+    #[test]
+    fn test_handshake_no_sendaddrv2_to_older_nodes_ok() {
+        let older = HandshakeConfig {
+            protocol_version: 70015,
+            ..HandshakeConfig::new(BitcoinNetwork::Regtest)
+        };
+        let addr: SocketAddr = "127.0.0.1:18444".parse().expect("Invalid socket address");
+        let mut outbound = Handshake::outbound(&older, addr, addr);
+        let mut inbound =
+            Handshake::inbound(&HandshakeConfig::new(BitcoinNetwork::Regtest), addr, addr);
+
+        let version = outbound.start().expect("Failed to start handshake");
+        let replies = exchange(&mut inbound, &version);
+        assert_eq!(commands(&replies), [Command::Version, Command::Verack]);
+        assert_eq!(
+            commands(&exchange(&mut outbound, &replies)),
+            [Command::Verack]
+        );
+    }
+
+    // Version message of a regtest node at 127.0.0.1:18445 to 127.0.0.1:18444, framed
+    // Protocol 70016, NODE_NETWORK | NODE_WITNESS, 2023-11-14 22:13:20, no relay
+    const GOLDEN_VERSION: &str = concat!(
+        "fabfb5da76657273696f6e00000000006c000000828bdab5",
+        "80110100090000000000000000f1536500000000",
+        "090000000000000000000000000000000000ffff7f000001480c",
+        "090000000000000000000000000000000000ffff7f000001480d",
+        "0807060504030201162f6e6f64652d68616e647368616b653a302e312e302f",
+        "0000000000",
+    );
+
+    #[test]
+    fn test_handshake_golden_version_ok() {
+        let config = HandshakeConfig {
+            protocol_version: 70016,
+            services: ServiceFlags::NODE_NETWORK | ServiceFlags::NODE_WITNESS,
+            clock: Arc::new(FixedClock(1_700_000_000)),
+            nonce_source: Arc::new(FixedNonceSource(0x0102_0304_0506_0708)),
+            ..HandshakeConfig::new(BitcoinNetwork::Regtest)
+        };
+        let sender: SocketAddr = "127.0.0.1:18445".parse().expect("Invalid socket address");
+        let receiver: SocketAddr = "127.0.0.1:18444".parse().expect("Invalid socket address");
+
+        let mut handshake = Handshake::outbound(&config, sender, receiver);
+        let version = handshake
+            .start()
+            .expect("Failed to start handshake")
+            .remove(0);
+        assert_eq!(
+            hex::encode(version.serialize().expect("Failed to serialize version")),
+            GOLDEN_VERSION
+        );
+    }
 }
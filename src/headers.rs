@@ -0,0 +1,283 @@
+use super::codec::CompactSize;
+use super::error::HandshakeError;
+use super::messages::Serializable;
+use super::uint::U256;
+use super::utils::sha256d;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Cursor, Read, Write};
+
+// Size of a serialized block header
+pub const BLOCK_HEADER_SIZE: usize = 80;
+// Most headers sent in a single headers message, same as Bitcoin Core
+pub const MAX_HEADERS_RESULTS: usize = 2000;
+// Most hashes accepted in a block locator, same as Bitcoin Core's MAX_LOCATOR_SZ
+pub const MAX_LOCATOR_SIZE: usize = 101;
+
+/// Header of a block, the part committed to by its proof of work
+/// More information https://en.bitcoin.it/wiki/Protocol_documentation#Block_Headers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockHeader {
+    // Block version, whose bits signal soft forks
+    pub version: i32,
+    // Hash of the previous block header, in internal byte order
+    pub prev_blockhash: [u8; 32],
+    // Root of the merkle tree of the block's transactions, in internal byte order
+    pub merkle_root: [u8; 32],
+    // Time the block was mined, as a UNIX timestamp
+    pub time: u32,
+    // Proof-of-work target in compact form
+    pub bits: u32,
+    // Value changed by miners until the hash meets the target
+    pub nonce: u32,
+}
+
+impl BlockHeader {
+    pub fn encode<W: Write>(&self, writer: &mut W) -> Result<(), HandshakeError> {
+        writer.write_i32::<LittleEndian>(self.version)?;
+        writer.write_all(&self.prev_blockhash)?;
+        writer.write_all(&self.merkle_root)?;
+        writer.write_u32::<LittleEndian>(self.time)?;
+        writer.write_u32::<LittleEndian>(self.bits)?;
+        writer.write_u32::<LittleEndian>(self.nonce)?;
+        Ok(())
+    }
+
+    pub fn decode<R: Read>(reader: &mut R) -> Result<Self, HandshakeError> {
+        let version = reader.read_i32::<LittleEndian>()?;
+        let mut prev_blockhash = [0u8; 32];
+        reader.read_exact(&mut prev_blockhash)?;
+        let mut merkle_root = [0u8; 32];
+        reader.read_exact(&mut merkle_root)?;
+        Ok(Self {
+            version,
+            prev_blockhash,
+            merkle_root,
+            time: reader.read_u32::<LittleEndian>()?,
+            bits: reader.read_u32::<LittleEndian>()?,
+            nonce: reader.read_u32::<LittleEndian>()?,
+        })
+    }
+
+    /// Hash of the header in internal byte order, the reverse of the usual hex form
+    pub fn block_hash(&self) -> [u8; 32] {
+        let mut bytes = Vec::with_capacity(BLOCK_HEADER_SIZE);
+        self.encode(&mut bytes)
+            .expect("Writing to a vector cannot fail");
+        sha256d(&bytes)
+    }
+
+    /// Target the hash must not exceed, unless the bits are not a valid target
+    pub fn target(&self) -> Option<U256> {
+        U256::from_compact(self.bits).filter(|target| *target != U256::ZERO)
+    }
+
+    /// Expected number of hashes needed to find a header meeting the target
+    pub fn work(&self) -> U256 {
+        // 2^256 / (target + 1), computed without overflowing as ~target / (target + 1) + 1
+        self.target().map_or(U256::ZERO, |target| {
+            !target / (target + U256::ONE) + U256::ONE
+        })
+    }
+
+    /// Check the hash meets the target and the target is not above the network's limit
+    pub fn check_proof_of_work(&self, pow_limit: &[u8; 32]) -> Result<(), HandshakeError> {
+        let hash = self.block_hash();
+        let target = self
+            .target()
+            .filter(|target| *target <= U256::from_le_bytes(pow_limit))
+            .ok_or_else(|| HandshakeError::invalid_header(&hash, "Invalid target"))?;
+        if U256::from_le_bytes(&hash) > target {
+            return Err(HandshakeError::invalid_header(
+                &hash,
+                "Hash above the proof-of-work target",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Usual hex form of a hash, with its bytes reversed
+pub fn hash_to_hex(hash: &[u8; 32]) -> String {
+    let mut bytes = *hash;
+    bytes.reverse();
+    hex::encode(bytes)
+}
+
+/// Parse a hash written in the usual hex form into internal byte order
+pub fn hex_to_hash(hex: &str) -> Result<[u8; 32], HandshakeError> {
+    let mut hash = [0u8; 32];
+    hex::decode_to_slice(hex, &mut hash)?;
+    hash.reverse();
+    Ok(hash)
+}
+
+/// Request for the headers following the first locator hash the remote node knows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetHeadersMessage {
+    // Protocol version of the sender
+    pub version: u32,
+    // Hashes of our chain from the tip back to the genesis block, ever more spaced out
+    pub locator: Vec<[u8; 32]>,
+    // Hash of the last header wanted, all zeros for as many as possible
+    pub stop_hash: [u8; 32],
+}
+
+impl Serializable for GetHeadersMessage {
+    fn serialize(&self) -> Result<Vec<u8>, HandshakeError> {
+        let mut message = Vec::with_capacity(4 + 9 + 32 * (self.locator.len() + 1));
+        message.write_u32::<LittleEndian>(self.version)?;
+        CompactSize(self.locator.len() as u64).encode(&mut message)?;
+        for hash in &self.locator {
+            message.write_all(hash)?;
+        }
+        message.write_all(&self.stop_hash)?;
+        Ok(message)
+    }
+
+    fn deserialize(msg: Vec<u8>) -> Result<Box<Self>, HandshakeError> {
+        let mut cursor = Cursor::new(msg);
+        let version = cursor.read_u32::<LittleEndian>()?;
+        let count = CompactSize::decode_length(&mut cursor, MAX_LOCATOR_SIZE as u64)?;
+        let mut locator = vec![[0u8; 32]; count];
+        for hash in &mut locator {
+            cursor.read_exact(hash)?;
+        }
+        let mut stop_hash = [0u8; 32];
+        cursor.read_exact(&mut stop_hash)?;
+        Ok(Box::new(Self {
+            version,
+            locator,
+            stop_hash,
+        }))
+    }
+}
+
+/// Headers answering a getheaders, each followed by an always empty transaction count
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeadersMessage {
+    pub headers: Vec<BlockHeader>,
+}
+
+impl Serializable for HeadersMessage {
+    fn serialize(&self) -> Result<Vec<u8>, HandshakeError> {
+        let mut message = Vec::with_capacity(9 + (BLOCK_HEADER_SIZE + 1) * self.headers.len());
+        CompactSize(self.headers.len() as u64).encode(&mut message)?;
+        for header in &self.headers {
+            header.encode(&mut message)?;
+            CompactSize(0).encode(&mut message)?;
+        }
+        Ok(message)
+    }
+
+    fn deserialize(msg: Vec<u8>) -> Result<Box<Self>, HandshakeError> {
+        let mut cursor = Cursor::new(msg);
+        let count = CompactSize::decode_length(&mut cursor, MAX_HEADERS_RESULTS as u64)?;
+        let mut headers = Vec::with_capacity(count);
+        for _ in 0..count {
+            headers.push(BlockHeader::decode(&mut cursor)?);
+            // Like Bitcoin Core, the transaction count is ignored
+            CompactSize::decode(&mut cursor)?;
+        }
+        Ok(Box::new(Self { headers }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::BitcoinNetwork;
+
+    // Block 1 of mainnet
+    const BLOCK_1: &str = concat!(
+        "010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000",
+        "982051fd1e4ba744bbbe680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e",
+        "61bc6649ffff001d01e36299",
+    );
+
+    #[test]
+    fn test_block_header_hash_ok() {
+        let bytes = hex::decode(BLOCK_1).expect("Invalid hex");
+        let header = BlockHeader::decode(&mut bytes.as_slice()).expect("Failed to decode");
+        assert_eq!(
+            header.prev_blockhash,
+            BitcoinNetwork::Mainnet.genesis_hash()
+        );
+        assert_eq!((header.time, header.bits), (1231469665, 0x1d00ffff));
+        assert_eq!(
+            hash_to_hex(&header.block_hash()),
+            "00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048"
+        );
+
+        let mut encoded = Vec::new();
+        header.encode(&mut encoded).expect("Failed to encode");
+        assert_eq!(encoded, bytes);
+        assert_eq!(
+            hex_to_hash("00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048")
+                .expect("Invalid hash"),
+            header.block_hash()
+        );
+        assert!(hex_to_hash("00000000839a8e68").is_err());
+        assert_eq!(header.work(), U256::from_u64(0x1_0001_0001));
+
+        let pow_limit = BitcoinNetwork::Mainnet.params().pow_limit;
+        assert!(header.check_proof_of_work(&pow_limit).is_ok());
+    }
+
+    #[test]
+    fn test_block_header_proof_of_work_error() {
+        let bytes = hex::decode(BLOCK_1).expect("Invalid hex");
+        let header = BlockHeader::decode(&mut bytes.as_slice()).expect("Failed to decode");
+        let pow_limit = BitcoinNetwork::Mainnet.params().pow_limit;
+        for header in [
+            BlockHeader {
+                nonce: header.nonce + 1,
+                ..header
+            },
+            // Easier than the limit of the network
+            BlockHeader {
+                bits: 0x1d01ffff,
+                ..header
+            },
+            BlockHeader { bits: 0, ..header },
+        ] {
+            assert!(matches!(
+                header.check_proof_of_work(&pow_limit),
+                Err(HandshakeError::InvalidHeader { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_headers_messages_round_trip_ok() {
+        let genesis = BitcoinNetwork::Regtest
+            .params()
+            .genesis_header
+            .expect("Regtest genesis header is known");
+        let headers = HeadersMessage {
+            headers: vec![genesis, genesis],
+        };
+        let bytes = headers.serialize().expect("Failed to serialize");
+        assert_eq!(bytes.len(), 1 + 2 * (BLOCK_HEADER_SIZE + 1));
+        assert_eq!(*HeadersMessage::deserialize(bytes).unwrap(), headers);
+
+        let getheaders = GetHeadersMessage {
+            version: 70016,
+            locator: vec![genesis.block_hash()],
+            stop_hash: [0u8; 32],
+        };
+        let bytes = getheaders.serialize().expect("Failed to serialize");
+        assert_eq!(*GetHeadersMessage::deserialize(bytes).unwrap(), getheaders);
+    }
+
+    #[test]
+    fn test_headers_message_too_many_error() {
+        let mut bytes = Vec::new();
+        CompactSize(MAX_HEADERS_RESULTS as u64 + 1)
+            .encode(&mut bytes)
+            .expect("Failed to encode count");
+        assert!(matches!(
+            HeadersMessage::deserialize(bytes),
+            Err(HandshakeError::FieldTooLong { .. })
+        ));
+    }
+}
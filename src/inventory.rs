@@ -0,0 +1,149 @@
+use super::codec::CompactSize;
+use super::error::HandshakeError;
+use super::messages::Serializable;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Cursor, Read, Write};
+
+// Most entries accepted in an inventory message, same as Bitcoin Core's MAX_INV_SZ
+pub const MAX_INV_SIZE: usize = 50_000;
+// Inventory types, see https://en.bitcoin.it/wiki/Protocol_documentation#Inventory_Vectors
+pub const MSG_TX: u32 = 1;
+pub const MSG_BLOCK: u32 = 2;
+// Transaction announced by wtxid, see BIP339
+pub const MSG_WTX: u32 = 5;
+// Flag asking for objects with their witness data, see BIP144
+pub const MSG_WITNESS_FLAG: u32 = 1 << 30;
+
+/// Object announced or requested through inv, getdata and notfound messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Inventory {
+    // Transaction by txid, without witness data when requested
+    Tx([u8; 32]),
+    // Transaction by txid, with its witness data when requested
+    WitnessTx([u8; 32]),
+    // Transaction by wtxid, only used once wtxidrelay is negotiated
+    WTx([u8; 32]),
+    // Block by hash, without witness data when requested
+    Block([u8; 32]),
+    // Block by hash, with its witness data when requested
+    WitnessBlock([u8; 32]),
+    // Any other type, such as filtered or compact blocks, kept as received
+    Unknown { inv_type: u32, hash: [u8; 32] },
+}
+
+impl Inventory {
+    /// Type of the entry on the wire
+    pub fn inv_type(&self) -> u32 {
+        match self {
+            Inventory::Tx(_) => MSG_TX,
+            Inventory::WitnessTx(_) => MSG_TX | MSG_WITNESS_FLAG,
+            Inventory::WTx(_) => MSG_WTX,
+            Inventory::Block(_) => MSG_BLOCK,
+            Inventory::WitnessBlock(_) => MSG_BLOCK | MSG_WITNESS_FLAG,
+            Inventory::Unknown { inv_type, .. } => *inv_type,
+        }
+    }
+
+    /// Hash of the object, in internal byte order
+    pub fn hash(&self) -> &[u8; 32] {
+        match self {
+            Inventory::Tx(hash)
+            | Inventory::WitnessTx(hash)
+            | Inventory::WTx(hash)
+            | Inventory::Block(hash)
+            | Inventory::WitnessBlock(hash)
+            | Inventory::Unknown { hash, .. } => hash,
+        }
+    }
+
+    pub fn encode<W: Write>(&self, writer: &mut W) -> Result<(), HandshakeError> {
+        writer.write_u32::<LittleEndian>(self.inv_type())?;
+        writer.write_all(self.hash())?;
+        Ok(())
+    }
+
+    pub fn decode<R: Read>(reader: &mut R) -> Result<Self, HandshakeError> {
+        let inv_type = reader.read_u32::<LittleEndian>()?;
+        let mut hash = [0u8; 32];
+        reader.read_exact(&mut hash)?;
+        Ok(match inv_type {
+            MSG_TX => Inventory::Tx(hash),
+            t if t == MSG_TX | MSG_WITNESS_FLAG => Inventory::WitnessTx(hash),
+            MSG_WTX => Inventory::WTx(hash),
+            MSG_BLOCK => Inventory::Block(hash),
+            t if t == MSG_BLOCK | MSG_WITNESS_FLAG => Inventory::WitnessBlock(hash),
+            inv_type => Inventory::Unknown { inv_type, hash },
+        })
+    }
+}
+
+/// Payload shared by inv, getdata and notfound messages
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InventoryMessage {
+    pub inventory: Vec<Inventory>,
+}
+
+impl InventoryMessage {
+    pub fn new(inventory: Vec<Inventory>) -> Self {
+        Self { inventory }
+    }
+}
+
+impl Serializable for InventoryMessage {
+    fn serialize(&self) -> Result<Vec<u8>, HandshakeError> {
+        let mut message = Vec::with_capacity(9 + 36 * self.inventory.len());
+        CompactSize(self.inventory.len() as u64).encode(&mut message)?;
+        for inventory in &self.inventory {
+            inventory.encode(&mut message)?;
+        }
+        Ok(message)
+    }
+
+    fn deserialize(msg: Vec<u8>) -> Result<Box<Self>, HandshakeError> {
+        let mut cursor = Cursor::new(msg);
+        let count = CompactSize::decode_length(&mut cursor, MAX_INV_SIZE as u64)?;
+        let mut inventory = Vec::with_capacity(count);
+        for _ in 0..count {
+            inventory.push(Inventory::decode(&mut cursor)?);
+        }
+        Ok(Box::new(Self { inventory }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inventory_message_round_trip_ok() {
+        let message = InventoryMessage::new(vec![
+            Inventory::Tx([1u8; 32]),
+            Inventory::WitnessTx([2u8; 32]),
+            Inventory::WTx([3u8; 32]),
+            Inventory::Block([4u8; 32]),
+            Inventory::WitnessBlock([5u8; 32]),
+            // Compact block
+            Inventory::Unknown {
+                inv_type: 4,
+                hash: [6u8; 32],
+            },
+        ]);
+        let bytes = message.serialize().expect("Failed to serialize");
+        assert_eq!(bytes.len(), 1 + 6 * 36);
+        // Witness block type on the wire
+        assert_eq!(bytes[1 + 4 * 36..1 + 4 * 36 + 4], [0x02, 0x00, 0x00, 0x40]);
+        assert_eq!(*InventoryMessage::deserialize(bytes).unwrap(), message);
+    }
+
+    #[test]
+    fn test_inventory_message_too_large_error() {
+        let mut bytes = Vec::new();
+        CompactSize(MAX_INV_SIZE as u64 + 1)
+            .encode(&mut bytes)
+            .expect("Failed to encode count");
+        assert!(matches!(
+            InventoryMessage::deserialize(bytes),
+            Err(HandshakeError::FieldTooLong { .. })
+        ));
+    }
+}
@@ -0,0 +1,298 @@
+use super::clock::{NonceSource, OsNonceSource};
+use super::error::HandshakeError;
+use super::handshake::{HandshakeOutcome, HandshakeState};
+use super::messages::NetworkMessage;
+use super::ping::{PingMessage, PongMessage};
+use super::vv::ProtocolFeatures;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Time between two pings, same as Bitcoin Core
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(2 * 60);
+// Time the remote node has to answer a ping, same as Bitcoin Core
+pub const DEFAULT_PING_TIMEOUT: Duration = Duration::from_secs(20 * 60);
+
+/// Settings of the keepalive task
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeepaliveConfig {
+    // Time between two pings
+    pub interval: Duration,
+    // Time the remote node has to answer a ping before being disconnected
+    pub timeout: Duration,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_PING_INTERVAL,
+            timeout: DEFAULT_PING_TIMEOUT,
+        }
+    }
+}
+
+/// Round trip times measured by matching pongs to our pings
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LatencyStats {
+    // Round trip time of the last answered ping
+    pub last: Option<Duration>,
+    // Lowest round trip time, the best estimate of the network latency
+    pub min: Option<Duration>,
+    // Moving average of the round trip times, each new sample weighing 1/8
+    pub smoothed: Option<Duration>,
+    // Pings sent to the remote node
+    pub pings_sent: u64,
+    // Pongs matching one of our pings
+    pub pongs_received: u64,
+}
+
+impl LatencyStats {
+    fn record(&mut self, rtt: Duration) {
+        self.last = Some(rtt);
+        self.min = Some(self.min.map_or(rtt, |min| min.min(rtt)));
+        self.smoothed = Some(
+            self.smoothed
+                .map_or(rtt, |smoothed| smoothed * 7 / 8 + rtt / 8),
+        );
+        self.pongs_received += 1;
+    }
+}
+
+/// Transport independent keepalive of an established connection
+/// Drivers send the pings it produces, feed it every message they read and
+/// send back the pongs it returns
+#[derive(Debug)]
+pub struct Keepalive {
+    // Interval and timeout of the pings
+    config: KeepaliveConfig,
+    // Whether pings carry a nonce the remote node echoes, from BIP31 on
+    ping_nonce: bool,
+    // Source of the nonces of our pings
+    nonce_source: Arc<dyn NonceSource>,
+    // Nonce of the ping waiting for its pong and the moment it was sent
+    pending: Option<(u64, Instant)>,
+    // Moment the next ping is due
+    next_ping: Instant,
+    // Round trip times measured so far
+    stats: LatencyStats,
+}
+
+impl Keepalive {
+    /// Keepalive sending its first ping straight away
+    pub fn new(config: KeepaliveConfig, features: ProtocolFeatures) -> Self {
+        Self {
+            config,
+            ping_nonce: features.ping_nonce,
+            nonce_source: Arc::new(OsNonceSource),
+            pending: None,
+            next_ping: Instant::now(),
+            stats: LatencyStats::default(),
+        }
+    }
+
+    /// Draw the nonces of our pings from another source
+    pub fn with_nonce_source(mut self, nonce_source: Arc<dyn NonceSource>) -> Self {
+        self.nonce_source = nonce_source;
+        self
+    }
+
+    pub fn config(&self) -> &KeepaliveConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> &LatencyStats {
+        &self.stats
+    }
+
+    /// Moment `poll` has something to do, either sending a ping or giving up on one
+    pub fn deadline(&self) -> Instant {
+        match self.pending {
+            Some((_, sent_at)) => sent_at + self.config.timeout,
+            None => self.next_ping,
+        }
+    }
+
+    /// Return the ping to send if one is due
+    /// Fails once the pending ping has been waiting for longer than the timeout
+    pub fn poll(&mut self, now: Instant) -> Result<Option<NetworkMessage>, HandshakeError> {
+        if let Some((_, sent_at)) = self.pending {
+            if now.saturating_duration_since(sent_at) >= self.config.timeout {
+                return Err(HandshakeError::PingTimeout(self.config.timeout));
+            }
+            // Like Bitcoin Core, a single ping is in flight at a time
+            return Ok(None);
+        }
+        if now < self.next_ping {
+            return Ok(None);
+        }
+
+        self.next_ping = now + self.config.interval;
+        self.stats.pings_sent += 1;
+        // Nodes older than BIP31 never answer, so no pong is awaited
+        if !self.ping_nonce {
+            return Ok(Some(NetworkMessage::Ping(PingMessage::without_nonce())));
+        }
+        let nonce = self.nonce_source.next_nonce();
+        self.pending = Some((nonce, now));
+        Ok(Some(NetworkMessage::Ping(PingMessage::new(nonce))))
+    }
+
+    /// Process a message from the remote node and return the pong to send, if any
+    pub fn on_message(&mut self, message: &NetworkMessage, now: Instant) -> Option<NetworkMessage> {
+        match message {
+            NetworkMessage::Ping(ping) => ping
+                .nonce()
+                .map(|nonce| NetworkMessage::Pong(PongMessage::new(nonce))),
+            NetworkMessage::Pong(pong) => {
+                // Pongs that do not match our ping are ignored like Bitcoin Core does
+                if let Some((nonce, sent_at)) = self.pending {
+                    if pong.nonce() == nonce {
+                        self.stats.record(now.saturating_duration_since(sent_at));
+                        self.pending = None;
+                    }
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+impl HandshakeOutcome<TcpStream> {
+    /// Keep the connection alive, pinging the remote node and answering its pings
+    /// Every received message is then handed to `on_message` with the latency so far,
+    /// the task stops when it returns false, or fails when the remote node disconnects
+    /// or leaves a ping unanswered
+    /// The read timeout of the stream is restored before returning, whatever the outcome
+    pub fn keep_alive(
+        &mut self,
+        keepalive: &mut Keepalive,
+        on_message: impl FnMut(&NetworkMessage, &LatencyStats) -> bool,
+    ) -> Result<(), HandshakeError> {
+        let previous = self.stream().read_timeout()?;
+        let result = self.keep_pinging(keepalive, on_message);
+        self.stream().set_read_timeout(previous)?;
+        result
+    }
+
+    fn keep_pinging(
+        &mut self,
+        keepalive: &mut Keepalive,
+        mut on_message: impl FnMut(&NetworkMessage, &LatencyStats) -> bool,
+    ) -> Result<(), HandshakeError> {
+        let stage = HandshakeState::Established;
+        loop {
+            if let Some(ping) = keepalive.poll(Instant::now())? {
+                self.send(&ping).map_err(|e| e.at_stage(stage))?;
+            }
+
+            // Time to ping again or to give up on a ping before anything arrived
+            if !self
+                .wait_until(keepalive.deadline())
+                .map_err(|e| e.at_stage(stage))?
+            {
+                continue;
+            }
+
+            // The rest of the message must follow within the ping timeout
+            self.stream()
+                .set_read_timeout(Some(keepalive.config().timeout))?;
+            let message = self.receive().map_err(|e| e.at_stage(stage))?;
+            if let Some(pong) = keepalive.on_message(&message, Instant::now()) {
+                self.send(&pong).map_err(|e| e.at_stage(stage))?;
+            }
+            if !on_message(&message, keepalive.stats()) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedNonceSource;
+    use crate::vv::PROTOCOL_VERSION;
+
+    fn keepalive() -> Keepalive {
+        let config = KeepaliveConfig {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+        };
+        Keepalive::new(config, ProtocolFeatures::for_version(PROTOCOL_VERSION))
+            .with_nonce_source(Arc::new(FixedNonceSource(42)))
+    }
+
+    #[test]
+    fn test_keepalive_matches_pongs_ok() {
+        let mut keepalive = keepalive();
+        let start = Instant::now();
+
+        let ping = keepalive.poll(start).expect("Ping should be due");
+        assert_eq!(ping, Some(NetworkMessage::Ping(PingMessage::new(42))));
+        // A single ping is in flight at a time
+        assert_eq!(
+            keepalive.poll(start + Duration::from_secs(20)).ok(),
+            Some(None)
+        );
+
+        // Pongs with another nonce are ignored
+        let stray = NetworkMessage::Pong(PongMessage::new(7));
+        keepalive.on_message(&stray, start + Duration::from_millis(50));
+        assert_eq!(keepalive.stats().pongs_received, 0);
+
+        let pong = NetworkMessage::Pong(PongMessage::new(42));
+        keepalive.on_message(&pong, start + Duration::from_millis(80));
+        assert_eq!(keepalive.stats().last, Some(Duration::from_millis(80)));
+        assert_eq!(keepalive.deadline(), start + Duration::from_secs(10));
+
+        keepalive
+            .poll(start + Duration::from_secs(10))
+            .expect("Ping should be due");
+        keepalive.on_message(&pong, start + Duration::from_millis(10_040));
+        let stats = keepalive.stats();
+        assert_eq!(stats.min, Some(Duration::from_millis(40)));
+        assert_eq!(stats.smoothed, Some(Duration::from_millis(75)));
+        assert_eq!((stats.pings_sent, stats.pongs_received), (2, 2));
+    }
+
+    #[test]
+    fn test_keepalive_answers_pings_ok() {
+        let mut keepalive = keepalive();
+        let now = Instant::now();
+        let ping = NetworkMessage::Ping(PingMessage::new(7));
+        assert_eq!(
+            keepalive.on_message(&ping, now),
+            Some(NetworkMessage::Pong(PongMessage::new(7)))
+        );
+        let legacy = NetworkMessage::Ping(PingMessage::without_nonce());
+        assert_eq!(keepalive.on_message(&legacy, now), None);
+    }
+
+    #[test]
+    fn test_keepalive_unanswered_ping_error() {
+        let mut keepalive = keepalive();
+        let start = Instant::now();
+        keepalive.poll(start).expect("Ping should be due");
+        assert!(matches!(
+            keepalive.poll(start + Duration::from_secs(30)),
+            Err(HandshakeError::PingTimeout(_))
+        ));
+    }
+
+    #[test]
+    fn test_keepalive_before_bip31_ok() {
+        let mut keepalive = Keepalive::new(
+            KeepaliveConfig::default(),
+            ProtocolFeatures::for_version(31800),
+        );
+        let start = Instant::now();
+        let ping = keepalive.poll(start).expect("Ping should be due");
+        assert_eq!(
+            ping,
+            Some(NetworkMessage::Ping(PingMessage::without_nonce()))
+        );
+        // No pong is awaited, so the ping never times out
+        assert_eq!(keepalive.deadline(), start + DEFAULT_PING_INTERVAL);
+    }
+}
@@ -1,5 +1,29 @@
+pub mod addr;
+#[cfg(feature = "tokio")]
+pub mod asynchronous;
+pub mod block;
+pub mod broadcast;
+pub mod chain;
+pub mod clock;
+pub mod codec;
+pub mod detection;
+pub mod error;
+pub mod framing;
 pub mod handshake;
+pub mod headers;
+pub mod inventory;
+pub mod keepalive;
+pub mod listener;
 pub mod messages;
+pub mod negotiation;
 pub mod network;
+pub mod nonce;
+pub mod params;
+pub mod ping;
+pub mod services;
+#[cfg(feature = "test-utils")]
+pub mod testing;
+pub mod transaction;
+pub mod uint;
 pub mod utils;
 pub mod vv;
@@ -0,0 +1,123 @@
+use super::error::HandshakeError;
+use super::handshake::{accept_handshake, HandshakeConfig, HandshakeOutcome};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+
+/// Listen for remote nodes and answer their handshakes as a Bitcoin node would
+/// The incoming version must carry the magic of the configured network,
+/// each fully handshaken connection is handed back to the caller
+#[derive(Debug)]
+pub struct HandshakeListener {
+    // Socket accepting the TCP connections
+    listener: TcpListener,
+    // Settings of our node advertised to every remote node
+    config: HandshakeConfig,
+}
+
+impl HandshakeListener {
+    /// Bind the listener to a local address, port 0 picks an ephemeral port
+    pub fn bind<A: ToSocketAddrs>(
+        addr: A,
+        config: HandshakeConfig,
+    ) -> Result<Self, HandshakeError> {
+        let listener = TcpListener::bind(addr).map_err(HandshakeError::ConnectionFailed)?;
+        Ok(Self { listener, config })
+    }
+
+    /// Local address the listener is bound to
+    pub fn local_addr(&self) -> Result<SocketAddr, HandshakeError> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Settings of our node advertised to every remote node
+    pub fn config(&self) -> &HandshakeConfig {
+        &self.config
+    }
+
+    /// Wait for the next remote node and complete its handshake
+    pub fn accept(&self) -> Result<HandshakeOutcome, HandshakeError> {
+        let (stream, _) = self.listener.accept()?;
+        accept_handshake(&self.config, stream)
+    }
+
+    /// Endless iterator over the handshakes of incoming connections
+    /// A failed handshake only affects its own connection, the next ones are still accepted
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming { listener: self }
+    }
+}
+
+/// Iterator returned by `HandshakeListener::incoming`
+#[derive(Debug)]
+pub struct Incoming<'a> {
+    listener: &'a HandshakeListener,
+}
+
+impl Iterator for Incoming<'_> {
+    type Item = Result<HandshakeOutcome, HandshakeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.listener.accept())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::perform_handshake_with_config;
+    use crate::network::BitcoinNetwork;
+    use std::thread;
+
+    #[test]
+    fn test_listener_answers_handshake_ok() {
+        let config = HandshakeConfig {
+            user_agent: "/listener:0.1.0/".to_string(),
+            ..HandshakeConfig::new(BitcoinNetwork::Regtest)
+        };
+        let listener =
+            HandshakeListener::bind("127.0.0.1:0", config).expect("Failed to bind listener");
+        let addr = listener
+            .local_addr()
+            .expect("Failed to get listener address");
+
+        let client = thread::spawn(move || {
+            perform_handshake_with_config(
+                &HandshakeConfig::new(BitcoinNetwork::Regtest),
+                addr,
+                addr,
+            )
+        });
+
+        let inbound = listener.accept().expect("Inbound handshake should succeed");
+        let outbound = client
+            .join()
+            .expect("Client thread panicked")
+            .expect("Outbound handshake should succeed");
+
+        assert_eq!(inbound.remote_addr(), outbound.local_addr());
+        assert_eq!(outbound.remote_addr(), addr);
+    }
+
+    #[test]
+    fn test_listener_rejects_other_network_error() {
+        let listener =
+            HandshakeListener::bind("127.0.0.1:0", HandshakeConfig::new(BitcoinNetwork::Regtest))
+                .expect("Failed to bind listener");
+        let addr = listener
+            .local_addr()
+            .expect("Failed to get listener address");
+
+        let client = thread::spawn(move || {
+            perform_handshake_with_config(
+                &HandshakeConfig::new(BitcoinNetwork::Testnet3),
+                addr,
+                addr,
+            )
+        });
+
+        assert!(matches!(
+            listener.accept(),
+            Err(HandshakeError::WrongNetworkMagic { .. })
+        ));
+        assert!(client.join().expect("Client thread panicked").is_err());
+    }
+}
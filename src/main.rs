@@ -1,23 +1,387 @@
-use node_handshake::handshake::perform_handshake;
+mod cli;
+
+use clap::Parser;
+use cli::{BroadcastArgs, Cli, Command, ConnectArgs, DetectArgs, Output};
+use node_handshake::addr::AddrV2Entry;
+use node_handshake::block::Block;
+use node_handshake::broadcast::{broadcast_tx, BroadcastReport, BroadcastStatus};
+use node_handshake::chain::HeaderChain;
+use node_handshake::detection::{detect_network, Detection, DetectionConfig};
+use node_handshake::error::HandshakeError;
+use node_handshake::handshake::{
+    perform_handshake_with_config, HandshakeConfig, HandshakeOutcome, HandshakeTimeouts,
+};
+use node_handshake::headers::hash_to_hex;
+use node_handshake::keepalive::{Keepalive, KeepaliveConfig, LatencyStats};
 use node_handshake::network::BitcoinNetwork;
-use std::io::Error;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-
-fn main() -> Result<(), Error> {
-    // Example parameters for a simple handshake
-    // Bitcoin node listens by default on 18444 on regtest network
-    let sender = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 18444);
-    let receiver = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 18445);
-    let user_agent = "/my-bitcoin-client:0.1.0/".to_string();
-    let start_height = 0;
-
-    // Perform basic handshake for regtest network
-    perform_handshake(
-        BitcoinNetwork::Regtest,
-        sender,
-        receiver,
-        user_agent,
-        start_height,
-    )?;
-    Ok(())
+use serde_json::json;
+use std::net::SocketAddr;
+use std::process::ExitCode;
+use std::time::Duration;
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Command::Connect(args) => connect(&args),
+        Command::Detect(args) => detect(&args),
+        Command::Broadcast(args) => broadcast(&args),
+    };
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            ExitCode::from(cli::exit_code(&e))
+        }
+    }
+}
+
+/// Perform a handshake with the target node and print what it advertised
+fn connect(args: &ConnectArgs) -> Result<ExitCode, HandshakeError> {
+    let network = args.network.resolve()?;
+    let target = cli::resolve_target(&args.target, network.default_port())?;
+    let config = HandshakeConfig {
+        protocol_version: args.protocol_version,
+        user_agent: args.user_agent.clone(),
+        start_height: args.start_height,
+        relay: args.relay,
+        services: args.services,
+        required_services: args.required_services,
+        timeouts: HandshakeTimeouts {
+            connect: args.timeout,
+            version: args.timeout,
+            verack: args.timeout,
+        },
+        ..HandshakeConfig::new(network)
+    };
+
+    let mut outcome = perform_handshake_with_config(&config, target, target)?;
+    let latency = match args.pings {
+        0 => None,
+        pings => {
+            let keepalive_config = KeepaliveConfig {
+                interval: args.ping_interval,
+                timeout: args.timeout,
+            };
+            let mut keepalive = Keepalive::new(keepalive_config, outcome.features());
+            outcome.keep_alive(&mut keepalive, |_, stats| stats.pongs_received < pings)?;
+            Some(*keepalive.stats())
+        }
+    };
+    let addresses = if args.getaddr {
+        Some(outcome.request_addresses(args.timeout)?)
+    } else {
+        None
+    };
+    let addresses = addresses.as_deref();
+    let chain = if args.sync_headers {
+        let mut chain = HeaderChain::new(network)?;
+        outcome.sync_headers(&mut chain, args.timeout)?;
+        Some(chain)
+    } else {
+        None
+    };
+    let chain = chain.as_ref();
+    let block = match args.get_block {
+        Some(hash) => Some(outcome.get_block(hash, args.timeout)?),
+        None => None,
+    };
+    let block = block.as_ref();
+    match args.output {
+        Output::Text => print_text(&config, &outcome, latency.as_ref(), addresses, chain, block),
+        Output::Json => print_json(&config, &outcome, latency.as_ref(), addresses, chain, block),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Find the network of the target node and print how it was found
+fn detect(args: &DetectArgs) -> Result<ExitCode, HandshakeError> {
+    let target = cli::resolve_target(&args.target, BitcoinNetwork::Mainnet.default_port())?;
+    let config = DetectionConfig {
+        user_agent: args.user_agent.clone(),
+        timeouts: HandshakeTimeouts {
+            connect: args.timeout,
+            version: args.timeout,
+            verack: args.timeout,
+        },
+        ..DetectionConfig::new()
+    };
+
+    let detection = detect_network(target, &config)?;
+    match args.output {
+        Output::Text => print_detection_text(target, &detection),
+        Output::Json => print_detection_json(target, &detection),
+    }
+    Ok(match detection.network {
+        Some(_) => ExitCode::SUCCESS,
+        None => ExitCode::from(cli::EXIT_WRONG_NETWORK),
+    })
+}
+
+/// Announce a raw transaction to the target node and print what it made of it
+fn broadcast(args: &BroadcastArgs) -> Result<ExitCode, HandshakeError> {
+    let raw_tx = hex::decode(args.raw_tx.trim())?;
+    let network = args.network.resolve()?;
+    let target = cli::resolve_target(&args.peer, network.default_port())?;
+    // Nodes only announce transactions back to peers asking for relay
+    let config = HandshakeConfig {
+        user_agent: args.user_agent.clone(),
+        relay: true,
+        timeouts: HandshakeTimeouts {
+            connect: args.timeout,
+            version: args.timeout,
+            verack: args.timeout,
+        },
+        ..HandshakeConfig::new(network)
+    };
+
+    let report = broadcast_tx(&config, target, &raw_tx, args.wait)?;
+    match args.output {
+        Output::Text => print_broadcast_text(target, &report),
+        Output::Json => print_broadcast_json(target, &report),
+    }
+    Ok(match report.status {
+        BroadcastStatus::Rejected { .. } => ExitCode::from(cli::EXIT_TX_REJECTED),
+        _ => ExitCode::SUCCESS,
+    })
+}
+
+fn print_text(
+    config: &HandshakeConfig,
+    outcome: &HandshakeOutcome,
+    latency: Option<&LatencyStats>,
+    addresses: Option<&[AddrV2Entry]>,
+    chain: Option<&HeaderChain>,
+    block: Option<&Block>,
+) {
+    let version = outcome.peer_version();
+    println!(
+        "Handshake established with {} on {}",
+        outcome.remote_addr(),
+        config.network
+    );
+    println!("  user agent:         {}", version.user_agent());
+    println!("  protocol version:   {}", version.version());
+    println!("  negotiated version: {}", outcome.negotiated_version());
+    println!(
+        "  features:           {}",
+        feature_names(outcome).join(", ")
+    );
+    println!(
+        "  negotiated:         {}",
+        negotiated_names(outcome).join(", ")
+    );
+    println!(
+        "  services:           {} ({:#x})",
+        version.services(),
+        version.services().bits()
+    );
+    println!("  start height:       {}", version.start_height());
+    println!("  relay:              {}", version.relay());
+    println!(
+        "  round trip time:    {:.3} ms",
+        outcome.round_trip_time().as_secs_f64() * 1000.0
+    );
+    if let Some(latency) = latency {
+        println!(
+            "  ping:               {} sent, last {} ms, min {} ms, smoothed {} ms",
+            latency.pings_sent,
+            millis(latency.last),
+            millis(latency.min),
+            millis(latency.smoothed)
+        );
+    }
+    if let Some(addresses) = addresses {
+        println!("  addresses:          {} received", addresses.len());
+        for entry in addresses {
+            println!("    {entry} ({})", entry.services);
+        }
+    }
+    if let Some(chain) = chain {
+        println!(
+            "  headers tip:        {} at height {}",
+            hash_to_hex(&chain.tip_hash()),
+            chain.height()
+        );
+        println!("  chain work:         0x{}", chain.chain_work());
+    }
+    if let Some(block) = block {
+        println!(
+            "  block:              {} with {} transaction(s)",
+            hash_to_hex(&block.block_hash()),
+            block.transactions.len()
+        );
+        for transaction in &block.transactions {
+            println!("    {}", hash_to_hex(&transaction.txid()));
+        }
+    }
+}
+
+/// Round trip time in milliseconds, if one was measured
+fn millis(rtt: Option<Duration>) -> String {
+    rtt.map_or("-".to_string(), |rtt| {
+        format!("{:.3}", rtt.as_secs_f64() * 1000.0)
+    })
+}
+
+/// Names of the features both nodes agreed on before verack
+fn negotiated_names(outcome: &HandshakeOutcome) -> Vec<&'static str> {
+    let negotiated = outcome.negotiated();
+    [
+        ("wtxidrelay", negotiated.wtxid_relay),
+        ("addrv2", negotiated.addr_v2),
+        ("txrcncl", negotiated.tx_reconciliation),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect()
+}
+
+/// Names of the version-gated features available on the connection
+fn feature_names(outcome: &HandshakeOutcome) -> Vec<&'static str> {
+    let features = outcome.features();
+    [
+        ("ping-nonce", features.ping_nonce),
+        ("relay-flag", features.relay_flag),
+        ("sendheaders", features.send_headers),
+        ("feefilter", features.fee_filter),
+        ("compact-blocks", features.compact_blocks),
+        ("wtxidrelay", features.wtxid_relay),
+        ("addrv2", features.addr_v2),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect()
+}
+
+fn print_json(
+    config: &HandshakeConfig,
+    outcome: &HandshakeOutcome,
+    latency: Option<&LatencyStats>,
+    addresses: Option<&[AddrV2Entry]>,
+    chain: Option<&HeaderChain>,
+    block: Option<&Block>,
+) {
+    let version = outcome.peer_version();
+    let report = json!({
+        "network": config.network.to_string(),
+        "remote_addr": outcome.remote_addr().to_string(),
+        "local_addr": outcome.local_addr().to_string(),
+        "user_agent": version.user_agent(),
+        "version": version.version(),
+        "negotiated_version": outcome.negotiated_version(),
+        "features": feature_names(outcome),
+        "negotiated": negotiated_names(outcome),
+        "services": version.services().bits(),
+        "service_names": version
+            .services()
+            .iter_names()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>(),
+        "timestamp": version.timestamp(),
+        "nonce": version.nonce(),
+        "start_height": version.start_height(),
+        "relay": version.relay(),
+        "addr_recv": version.receiver().socket_addr().to_string(),
+        "addr_from": version.sender().socket_addr().to_string(),
+        "round_trip_ms": outcome.round_trip_time().as_secs_f64() * 1000.0,
+        "ping": latency.map(|latency| json!({
+            "pings_sent": latency.pings_sent,
+            "pongs_received": latency.pongs_received,
+            "last_ms": latency.last.map(|rtt| rtt.as_secs_f64() * 1000.0),
+            "min_ms": latency.min.map(|rtt| rtt.as_secs_f64() * 1000.0),
+            "smoothed_ms": latency.smoothed.map(|rtt| rtt.as_secs_f64() * 1000.0),
+        })),
+        "addresses": addresses.map(|addresses| addresses
+            .iter()
+            .map(|entry| json!({
+                "addr": entry.to_string(),
+                "services": entry.services.bits(),
+                "time": entry.time,
+            }))
+            .collect::<Vec<_>>()),
+        "headers": chain.map(|chain| json!({
+            "height": chain.height(),
+            "tip": hash_to_hex(&chain.tip_hash()),
+            "chain_work": format!("0x{}", chain.chain_work()),
+        })),
+        "block": block.map(|block| json!({
+            "hash": hash_to_hex(&block.block_hash()),
+            "merkle_root": hash_to_hex(&block.header.merkle_root),
+            "txids": block
+                .transactions
+                .iter()
+                .map(|transaction| hash_to_hex(&transaction.txid()))
+                .collect::<Vec<_>>(),
+        })),
+    });
+    println!("{report}");
+}
+
+fn print_broadcast_text(target: SocketAddr, report: &BroadcastReport) {
+    println!(
+        "Transaction {} announced to {target} by {}",
+        hash_to_hex(&report.txid),
+        if report.by_wtxid { "wtxid" } else { "txid" }
+    );
+    println!("  wtxid:              {}", hash_to_hex(&report.wtxid));
+    match &report.status {
+        BroadcastStatus::Ignored => println!("  status:             never asked for"),
+        BroadcastStatus::Requested => println!("  status:             asked for"),
+        BroadcastStatus::Rejected { code, reason } => {
+            println!("  status:             rejected with code {code:#04x}, {reason}")
+        }
+        BroadcastStatus::Relayed => println!("  status:             relayed back"),
+    }
+}
+
+fn print_broadcast_json(target: SocketAddr, report: &BroadcastReport) {
+    let (reject_code, reject_reason) = match &report.status {
+        BroadcastStatus::Rejected { code, reason } => (Some(*code), Some(reason.as_str())),
+        _ => (None, None),
+    };
+    let report = json!({
+        "remote_addr": target.to_string(),
+        "txid": hash_to_hex(&report.txid),
+        "wtxid": hash_to_hex(&report.wtxid),
+        "announced_by": if report.by_wtxid { "wtxid" } else { "txid" },
+        "status": report.status.as_str(),
+        "reject_code": reject_code,
+        "reject_reason": reject_reason,
+    });
+    println!("{report}");
+}
+
+fn print_detection_text(target: SocketAddr, detection: &Detection) {
+    match (detection.network, detection.method) {
+        (Some(network), Some(method)) => println!(
+            "{target} is on {network}, found by {} after {} attempt(s)",
+            method.as_str(),
+            detection.attempts.len()
+        ),
+        _ => match detection.magic {
+            Some(magic) => println!("{target} uses the unknown magic {}", hex::encode(magic)),
+            None => println!("{target} dropped every attempt, its network is unknown"),
+        },
+    }
+    if let Some(version) = &detection.peer_version {
+        println!("  user agent:         {}", version.user_agent());
+        println!("  protocol version:   {}", version.version());
+    }
+}
+
+fn print_detection_json(target: SocketAddr, detection: &Detection) {
+    let report = json!({
+        "remote_addr": target.to_string(),
+        "network": detection.network.map(|network| network.to_string()),
+        "magic": detection.magic.map(hex::encode),
+        "method": detection.method.map(|method| method.as_str()),
+        "attempts": detection
+            .attempts
+            .iter()
+            .map(|network| network.to_string())
+            .collect::<Vec<_>>(),
+        "user_agent": detection.peer_version.as_ref().map(|version| version.user_agent()),
+        "version": detection.peer_version.as_ref().map(|version| version.version()),
+    });
+    println!("{report}");
 }
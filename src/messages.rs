@@ -1,18 +1,31 @@
+use super::addr::{AddrMessage, AddrV2Message};
+use super::block::Block;
+use super::broadcast::RejectMessage;
+use super::error::HandshakeError;
+use super::headers::{GetHeadersMessage, HeadersMessage};
+use super::inventory::InventoryMessage;
+use super::negotiation::{FeeFilterMessage, SendCmpctMessage, SendTxRcnclMessage};
 use super::network::BitcoinNetwork;
+use super::ping::{PingMessage, PongMessage};
+use super::transaction::Transaction;
 use super::utils::calculate_checksum;
-use super::vv::Command;
+use super::vv::{Command, VersionMessage};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use std::io::{Cursor, Error, ErrorKind, Read};
+use std::io::{Cursor, Read};
 
 // Constants for the Bitcoin protocol
 pub const COMMAND_SIZE: usize = 12;
+// Size of a message header: magic, command, length and checksum
+pub const HEADER_SIZE: usize = 24;
 // First 4 bytes of the double hash
 pub const CHECKSUM_SIZE: usize = 4;
+// Maximum payload length accepted from a remote node, same limit as Bitcoin Core
+pub const MAX_PAYLOAD_SIZE: u32 = 4_000_000;
 
 /// Trait for operate serialization on different Message structures
 pub trait Serializable {
-    fn serialize(&self) -> Result<Vec<u8>, Error>;
-    fn deserialize(msg: Vec<u8>) -> Result<Box<Self>, Error>;
+    fn serialize(&self) -> Result<Vec<u8>, HandshakeError>;
+    fn deserialize(msg: Vec<u8>) -> Result<Box<Self>, HandshakeError>;
 }
 
 /// Bitcoin protocol message
@@ -49,13 +62,201 @@ impl BitcoinMessage {
             payload,
         }
     }
+
+    /// Rebuild a received message from its header fields and payload
+    /// The payload must match the checksum announced in the header
+    pub(crate) fn from_parts(
+        magic: u32,
+        command: [u8; COMMAND_SIZE],
+        checksum: [u8; CHECKSUM_SIZE],
+        payload: Vec<u8>,
+    ) -> Result<Self, HandshakeError> {
+        if checksum != calculate_checksum(payload.clone()) {
+            return Err(HandshakeError::BadChecksum);
+        }
+        Ok(BitcoinMessage {
+            magic,
+            command,
+            length: payload.len() as u32,
+            checksum: u32::from_ne_bytes(checksum),
+            payload,
+        })
+    }
+
+    /// Magic value identifying the network the message was sent on
+    pub fn magic(&self) -> u32 {
+        self.magic
+    }
+
+    /// Raw null-padded command bytes of the message
+    pub fn command(&self) -> &[u8; COMMAND_SIZE] {
+        &self.command
+    }
+
+    /// Data carried by the message
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+}
+
+/// Decoded Bitcoin message, one variant per supported message type
+/// Protocol code matches on it instead of comparing raw command bytes
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetworkMessage {
+    Version(VersionMessage),
+    Verack,
+    Ping(PingMessage),
+    Pong(PongMessage),
+    GetAddr,
+    Addr(AddrMessage),
+    AddrV2(AddrV2Message),
+    SendAddrV2,
+    WtxidRelay,
+    SendTxRcncl(SendTxRcnclMessage),
+    SendHeaders,
+    SendCmpct(SendCmpctMessage),
+    FeeFilter(FeeFilterMessage),
+    GetHeaders(GetHeadersMessage),
+    Headers(HeadersMessage),
+    Inv(InventoryMessage),
+    GetData(InventoryMessage),
+    NotFound(InventoryMessage),
+    Block(Block),
+    Tx(Transaction),
+    Reject(RejectMessage),
+    // Message of a type the crate does not decode, kept as received, known commands
+    // are refused when encoding so that it never decodes as another variant
+    Unknown { command: String, payload: Vec<u8> },
+}
+
+impl NetworkMessage {
+    /// Command naming the message type
+    pub fn command(&self) -> Result<Command, HandshakeError> {
+        match self {
+            NetworkMessage::Version(_) => Ok(Command::Version),
+            NetworkMessage::Verack => Ok(Command::Verack),
+            NetworkMessage::Ping(_) => Ok(Command::Ping),
+            NetworkMessage::Pong(_) => Ok(Command::Pong),
+            NetworkMessage::GetAddr => Ok(Command::GetAddr),
+            NetworkMessage::Addr(_) => Ok(Command::Addr),
+            NetworkMessage::AddrV2(_) => Ok(Command::AddrV2),
+            NetworkMessage::SendAddrV2 => Ok(Command::SendAddrV2),
+            NetworkMessage::WtxidRelay => Ok(Command::WtxidRelay),
+            NetworkMessage::SendTxRcncl(_) => Ok(Command::SendTxRcncl),
+            NetworkMessage::SendHeaders => Ok(Command::SendHeaders),
+            NetworkMessage::SendCmpct(_) => Ok(Command::SendCmpct),
+            NetworkMessage::FeeFilter(_) => Ok(Command::FeeFilter),
+            NetworkMessage::GetHeaders(_) => Ok(Command::GetHeaders),
+            NetworkMessage::Headers(_) => Ok(Command::Headers),
+            NetworkMessage::Inv(_) => Ok(Command::Inv),
+            NetworkMessage::GetData(_) => Ok(Command::GetData),
+            NetworkMessage::NotFound(_) => Ok(Command::NotFound),
+            NetworkMessage::Block(_) => Ok(Command::Block),
+            NetworkMessage::Tx(_) => Ok(Command::Tx),
+            NetworkMessage::Reject(_) => Ok(Command::Reject),
+            // A known command would be decoded back as its own message type
+            NetworkMessage::Unknown { command, .. } => match Command::from_name(command)? {
+                command @ Command::Other(_) => Ok(command),
+                _ => Err(HandshakeError::InvalidMessage(
+                    "Unknown message with a known command",
+                )),
+            },
+        }
+    }
+
+    /// Encode the payload and frame it for a network
+    pub fn to_bitcoin_message(
+        &self,
+        network: BitcoinNetwork,
+    ) -> Result<BitcoinMessage, HandshakeError> {
+        let payload = match self {
+            NetworkMessage::Version(version) => version.serialize()?,
+            NetworkMessage::Verack
+            | NetworkMessage::GetAddr
+            | NetworkMessage::SendAddrV2
+            | NetworkMessage::WtxidRelay
+            | NetworkMessage::SendHeaders => Vec::new(),
+            NetworkMessage::Ping(ping) => ping.serialize()?,
+            NetworkMessage::Pong(pong) => pong.serialize()?,
+            NetworkMessage::Addr(addr) => addr.serialize()?,
+            NetworkMessage::AddrV2(addr) => addr.serialize()?,
+            NetworkMessage::SendTxRcncl(sendtxrcncl) => sendtxrcncl.serialize()?,
+            NetworkMessage::SendCmpct(sendcmpct) => sendcmpct.serialize()?,
+            NetworkMessage::FeeFilter(feefilter) => feefilter.serialize()?,
+            NetworkMessage::GetHeaders(getheaders) => getheaders.serialize()?,
+            NetworkMessage::Headers(headers) => headers.serialize()?,
+            NetworkMessage::Inv(inventory)
+            | NetworkMessage::GetData(inventory)
+            | NetworkMessage::NotFound(inventory) => inventory.serialize()?,
+            NetworkMessage::Block(block) => block.serialize()?,
+            NetworkMessage::Tx(transaction) => transaction.serialize()?,
+            NetworkMessage::Reject(reject) => reject.serialize()?,
+            NetworkMessage::Unknown { payload, .. } => payload.clone(),
+        };
+        Ok(BitcoinMessage::new(self.command()?, payload, network))
+    }
+
+    /// Decode the payload of a framed message according to its command
+    pub fn from_bitcoin_message(message: &BitcoinMessage) -> Result<Self, HandshakeError> {
+        let payload = message.payload().to_vec();
+        match Command::from_fixed_length(message.command())? {
+            Command::Version => Ok(NetworkMessage::Version(*VersionMessage::deserialize(
+                payload,
+            )?)),
+            Command::Verack if payload.is_empty() => Ok(NetworkMessage::Verack),
+            Command::Verack => Err(HandshakeError::InvalidMessage("Verack with a payload")),
+            Command::Ping => Ok(NetworkMessage::Ping(*PingMessage::deserialize(payload)?)),
+            Command::Pong => Ok(NetworkMessage::Pong(*PongMessage::deserialize(payload)?)),
+            Command::GetAddr => Ok(NetworkMessage::GetAddr),
+            Command::Addr => Ok(NetworkMessage::Addr(*AddrMessage::deserialize(payload)?)),
+            Command::AddrV2 => Ok(NetworkMessage::AddrV2(*AddrV2Message::deserialize(
+                payload,
+            )?)),
+            Command::SendAddrV2 => Ok(NetworkMessage::SendAddrV2),
+            Command::WtxidRelay => Ok(NetworkMessage::WtxidRelay),
+            Command::SendTxRcncl => Ok(NetworkMessage::SendTxRcncl(
+                *SendTxRcnclMessage::deserialize(payload)?,
+            )),
+            Command::SendHeaders => Ok(NetworkMessage::SendHeaders),
+            Command::SendCmpct => Ok(NetworkMessage::SendCmpct(*SendCmpctMessage::deserialize(
+                payload,
+            )?)),
+            Command::FeeFilter => Ok(NetworkMessage::FeeFilter(*FeeFilterMessage::deserialize(
+                payload,
+            )?)),
+            Command::GetHeaders => Ok(NetworkMessage::GetHeaders(*GetHeadersMessage::deserialize(
+                payload,
+            )?)),
+            Command::Headers => Ok(NetworkMessage::Headers(*HeadersMessage::deserialize(
+                payload,
+            )?)),
+            Command::Inv => Ok(NetworkMessage::Inv(*InventoryMessage::deserialize(
+                payload,
+            )?)),
+            Command::GetData => Ok(NetworkMessage::GetData(*InventoryMessage::deserialize(
+                payload,
+            )?)),
+            Command::NotFound => Ok(NetworkMessage::NotFound(*InventoryMessage::deserialize(
+                payload,
+            )?)),
+            Command::Block => Ok(NetworkMessage::Block(*Block::deserialize(payload)?)),
+            Command::Tx => Ok(NetworkMessage::Tx(*Transaction::deserialize(payload)?)),
+            Command::Reject => Ok(NetworkMessage::Reject(*RejectMessage::deserialize(
+                payload,
+            )?)),
+            command @ Command::Other(_) => Ok(NetworkMessage::Unknown {
+                command: command.as_str().to_string(),
+                payload,
+            }),
+        }
+    }
 }
 
 impl Serializable for BitcoinMessage {
     /// Serialize the Bitcoin message to a byte vector
     /// Append the magic value, command, payload size, checksum, and payload
     /// to a byte vector which represents the serialized message
-    fn serialize(&self) -> Result<Vec<u8>, Error> {
+    fn serialize(&self) -> Result<Vec<u8>, HandshakeError> {
         let mut message = Vec::new();
 
         // Add all bitcoin message keys to vec
@@ -69,44 +270,37 @@ impl Serializable for BitcoinMessage {
         Ok(message)
     }
     /// Deserialize Bitcoin messagee
-    fn deserialize(msg: Vec<u8>) -> Result<Box<Self>, Error> {
+    fn deserialize(msg: Vec<u8>) -> Result<Box<Self>, HandshakeError> {
         let mut cursor = Cursor::new(msg);
 
         // Check the magic number
         let magic = cursor.read_u32::<LittleEndian>()?;
 
         // Read the command
-        let mut command = vec![0u8; COMMAND_SIZE];
+        let mut command = [0u8; COMMAND_SIZE];
         cursor.read_exact(&mut command)?;
 
-        let mut command_v = Vec::with_capacity(COMMAND_SIZE);
-        command_v.extend(&command);
-        command_v.resize(COMMAND_SIZE, 0);
-
         // Read the payload size
-        let payload_size = cursor.read_u32::<LittleEndian>()? as usize;
+        let payload_size = cursor.read_u32::<LittleEndian>()?;
+        if payload_size > MAX_PAYLOAD_SIZE {
+            return Err(HandshakeError::PayloadTooLarge {
+                length: payload_size,
+                max: MAX_PAYLOAD_SIZE,
+            });
+        }
 
         // Read the checksum
         let mut checksum = [0u8; CHECKSUM_SIZE];
         cursor.read_exact(&mut checksum)?;
 
         // Read the payload
-        let mut payload = vec![0u8; payload_size];
+        let mut payload = vec![0u8; payload_size as usize];
         cursor.read_exact(&mut payload)?;
 
         // Verify the checksum once the payload is read
-        let calculated_checksum = calculate_checksum(payload.clone());
-        if checksum != calculated_checksum {
-            return Err(Error::new(ErrorKind::InvalidData, "Invalid checksum"));
-        }
-
-        Ok(Box::new(BitcoinMessage {
-            magic,
-            length: payload_size as u32,
-            command: command_v.try_into().unwrap(),
-            checksum: u32::from_ne_bytes(checksum),
-            payload,
-        }))
+        Ok(Box::new(BitcoinMessage::from_parts(
+            magic, command, checksum, payload,
+        )?))
     }
 }
 
@@ -114,7 +308,8 @@ impl Serializable for BitcoinMessage {
 mod tests {
 
     use super::*;
-    use crate::vv::VersionMessage;
+    use crate::services::ServiceFlags;
+    use crate::vv::{VersionMessage, PROTOCOL_VERSION};
     use std::net::SocketAddr;
     use std::str::FromStr;
 
@@ -159,8 +354,15 @@ mod tests {
         let start_height = 0;
         let relay = false;
 
-        let version_message =
-            VersionMessage::new(add_recv, add_from, user_agent, start_height, relay);
+        let version_message = VersionMessage::new(
+            PROTOCOL_VERSION,
+            add_recv,
+            add_from,
+            ServiceFlags::NODE_NETWORK,
+            user_agent,
+            start_height,
+            relay,
+        );
 
         let payload = version_message
             .serialize()
@@ -185,8 +387,15 @@ mod tests {
         let start_height = 0;
         let relay = false;
 
-        let version_message =
-            VersionMessage::new(add_recv, add_from, user_agent, start_height, relay);
+        let version_message = VersionMessage::new(
+            PROTOCOL_VERSION,
+            add_recv,
+            add_from,
+            ServiceFlags::NODE_NETWORK,
+            user_agent,
+            start_height,
+            relay,
+        );
 
         let payload = version_message
             .serialize()
@@ -202,4 +411,88 @@ mod tests {
         assert_eq!(deserialized_msg.length as usize, payload.len());
         assert_eq!(deserialized_msg.payload, payload);
     }
+
+    #[test]
+    fn test_deserialization_bad_checksum_error() {
+        let message = BitcoinMessage::new(Command::Verack, vec![0x01], BitcoinNetwork::Regtest);
+        let mut serialized_msg = message
+            .serialize()
+            .expect("Bitcoin Message could not be serialized");
+
+        // Corrupt the payload after the checksum was computed
+        let last = serialized_msg.len() - 1;
+        serialized_msg[last] ^= 0xff;
+
+        assert!(matches!(
+            BitcoinMessage::deserialize(serialized_msg),
+            Err(HandshakeError::BadChecksum)
+        ));
+    }
+
+    #[test]
+    fn test_deserialization_payload_too_large_error() {
+        let message = BitcoinMessage::new(Command::Verack, Vec::new(), BitcoinNetwork::Regtest);
+        let mut serialized_msg = message
+            .serialize()
+            .expect("Bitcoin Message could not be serialized");
+        serialized_msg[16..20].copy_from_slice(&(MAX_PAYLOAD_SIZE + 1).to_le_bytes());
+
+        assert!(matches!(
+            BitcoinMessage::deserialize(serialized_msg),
+            Err(HandshakeError::PayloadTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn test_network_message_round_trip_ok() {
+        let network = BitcoinNetwork::Regtest;
+        let addr =
+            SocketAddr::from_str("127.0.0.1:18444").expect("Failed to convert to socket address");
+        let version = VersionMessage::new(
+            PROTOCOL_VERSION,
+            addr,
+            addr,
+            ServiceFlags::NODE_NETWORK,
+            "/test:0.1.0/".to_string(),
+            0,
+            true,
+        );
+        let messages = [
+            NetworkMessage::Version(version),
+            NetworkMessage::Verack,
+            NetworkMessage::Ping(PingMessage::new(42)),
+            NetworkMessage::Pong(PongMessage::new(42)),
+            NetworkMessage::Unknown {
+                command: "mempool".to_string(),
+                payload: vec![1, 2, 3, 4, 5, 6, 7, 8],
+            },
+        ];
+        for message in messages {
+            let framed = message
+                .to_bitcoin_message(network)
+                .expect("Failed to frame message");
+            assert_eq!(
+                Command::from_fixed_length(framed.command()).ok(),
+                message.command().ok()
+            );
+            let decoded =
+                NetworkMessage::from_bitcoin_message(&framed).expect("Failed to decode message");
+            assert_eq!(decoded, message);
+        }
+    }
+
+    #[test]
+    fn test_network_message_unknown_with_known_command_error() {
+        let message = NetworkMessage::Unknown {
+            command: "ping".to_string(),
+            payload: 42u64.to_le_bytes().to_vec(),
+        };
+        assert!(message.to_bitcoin_message(BitcoinNetwork::Regtest).is_err());
+    }
+
+    #[test]
+    fn test_network_message_verack_with_payload_error() {
+        let message = BitcoinMessage::new(Command::Verack, vec![0], BitcoinNetwork::Regtest);
+        assert!(NetworkMessage::from_bitcoin_message(&message).is_err());
+    }
 }
@@ -0,0 +1,193 @@
+use super::error::HandshakeError;
+use super::handshake::HandshakeState;
+use super::messages::{NetworkMessage, Serializable};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::Cursor;
+
+// Version of the transaction reconciliation protocol we speak, see BIP330
+pub const TXRECONCILIATION_VERSION: u32 = 1;
+// Version of compact blocks carrying witnesses, the only one Bitcoin Core still uses
+pub const CMPCTBLOCKS_VERSION: u64 = 2;
+
+/// Offer to reconcile transactions instead of flooding their announcements, see BIP330
+/// Sent between version and verack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendTxRcnclMessage {
+    // Highest reconciliation protocol version supported
+    pub version: u32,
+    // Random salt contributed to the short ids of the transactions
+    pub salt: u64,
+}
+
+impl Serializable for SendTxRcnclMessage {
+    fn serialize(&self) -> Result<Vec<u8>, HandshakeError> {
+        let mut message = Vec::new();
+        message.write_u32::<LittleEndian>(self.version)?;
+        message.write_u64::<LittleEndian>(self.salt)?;
+        Ok(message)
+    }
+
+    fn deserialize(msg: Vec<u8>) -> Result<Box<Self>, HandshakeError> {
+        let mut cursor = Cursor::new(msg);
+        let version = cursor.read_u32::<LittleEndian>()?;
+        let salt = cursor.read_u64::<LittleEndian>()?;
+        Ok(Box::new(Self { version, salt }))
+    }
+}
+
+/// Preference for new blocks to be announced as compact blocks, see BIP152
+/// Sent after verack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendCmpctMessage {
+    // High bandwidth mode, blocks are pushed before being fully validated
+    pub announce: bool,
+    // Compact blocks version, 2 for blocks with witnesses
+    pub version: u64,
+}
+
+impl Serializable for SendCmpctMessage {
+    fn serialize(&self) -> Result<Vec<u8>, HandshakeError> {
+        let mut message = Vec::new();
+        message.write_u8(self.announce as u8)?;
+        message.write_u64::<LittleEndian>(self.version)?;
+        Ok(message)
+    }
+
+    fn deserialize(msg: Vec<u8>) -> Result<Box<Self>, HandshakeError> {
+        let mut cursor = Cursor::new(msg);
+        let announce = cursor.read_u8()? != 0;
+        let version = cursor.read_u64::<LittleEndian>()?;
+        Ok(Box::new(Self { announce, version }))
+    }
+}
+
+/// Lowest fee rate of the transactions the sender wants to hear about, see BIP133
+/// Sent after verack, and again whenever the fee rate changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeeFilterMessage {
+    // Fee rate in satoshis per 1000 virtual bytes
+    pub fee_rate: i64,
+}
+
+impl Serializable for FeeFilterMessage {
+    fn serialize(&self) -> Result<Vec<u8>, HandshakeError> {
+        Ok(self.fee_rate.to_le_bytes().to_vec())
+    }
+
+    fn deserialize(msg: Vec<u8>) -> Result<Box<Self>, HandshakeError> {
+        let fee_rate = Cursor::new(msg).read_i64::<LittleEndian>()?;
+        Ok(Box::new(Self { fee_rate }))
+    }
+}
+
+/// Features agreed on a connection through the negotiation messages of both nodes
+/// Those sent before verack are settled by the handshake, the remote node's
+/// preferences sent after verack are recorded as they arrive
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NegotiatedFeatures {
+    // Both nodes sent wtxidrelay, transactions are announced by wtxid (BIP339)
+    pub wtxid_relay: bool,
+    // The remote node sent sendaddrv2, addresses must be sent to it with addrv2 (BIP155)
+    pub addr_v2: bool,
+    // Both nodes sent sendtxrcncl, transactions may be reconciled (BIP330)
+    pub tx_reconciliation: bool,
+    // The remote node sent sendheaders, new blocks must be announced to it with headers
+    pub send_headers: bool,
+    // Latest compact blocks preference of the remote node
+    pub compact_blocks: Option<SendCmpctMessage>,
+    // Latest fee filter of the remote node, in satoshis per 1000 virtual bytes
+    pub fee_filter: Option<i64>,
+}
+
+impl NegotiatedFeatures {
+    /// Record a message received once the handshake is over
+    /// Negotiation messages only allowed before verack are a protocol violation
+    pub(crate) fn on_message_after_verack(
+        &mut self,
+        message: &NetworkMessage,
+    ) -> Result<(), HandshakeError> {
+        match message {
+            NetworkMessage::WtxidRelay
+            | NetworkMessage::SendAddrV2
+            | NetworkMessage::SendTxRcncl(_) => {
+                return Err(HandshakeError::UnexpectedCommand {
+                    command: message.command()?.as_str().to_string(),
+                    stage: HandshakeState::Established,
+                })
+            }
+            NetworkMessage::SendHeaders => self.send_headers = true,
+            NetworkMessage::SendCmpct(sendcmpct) => self.compact_blocks = Some(*sendcmpct),
+            NetworkMessage::FeeFilter(feefilter) => self.fee_filter = Some(feefilter.fee_rate),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiation_messages_encoding_ok() {
+        let sendtxrcncl = SendTxRcnclMessage {
+            version: TXRECONCILIATION_VERSION,
+            salt: 0x0102_0304_0506_0708,
+        };
+        let bytes = sendtxrcncl.serialize().expect("Failed to serialize");
+        assert_eq!(hex::encode(&bytes), "010000000807060504030201");
+        assert_eq!(
+            *SendTxRcnclMessage::deserialize(bytes).unwrap(),
+            sendtxrcncl
+        );
+
+        let sendcmpct = SendCmpctMessage {
+            announce: true,
+            version: CMPCTBLOCKS_VERSION,
+        };
+        let bytes = sendcmpct.serialize().expect("Failed to serialize");
+        assert_eq!(hex::encode(&bytes), "010200000000000000");
+        assert_eq!(*SendCmpctMessage::deserialize(bytes).unwrap(), sendcmpct);
+
+        let feefilter = FeeFilterMessage { fee_rate: 1000 };
+        let bytes = feefilter.serialize().expect("Failed to serialize");
+        assert_eq!(hex::encode(&bytes), "e803000000000000");
+        assert_eq!(*FeeFilterMessage::deserialize(bytes).unwrap(), feefilter);
+    }
+
+    #[test]
+    fn test_negotiated_features_after_verack_ok() {
+        let mut features = NegotiatedFeatures::default();
+        let sendcmpct = SendCmpctMessage {
+            announce: false,
+            version: CMPCTBLOCKS_VERSION,
+        };
+        for message in [
+            NetworkMessage::SendHeaders,
+            NetworkMessage::SendCmpct(sendcmpct),
+            NetworkMessage::FeeFilter(FeeFilterMessage { fee_rate: 1000 }),
+            NetworkMessage::FeeFilter(FeeFilterMessage { fee_rate: 2000 }),
+        ] {
+            features
+                .on_message_after_verack(&message)
+                .expect("Message allowed after verack");
+        }
+        assert!(features.send_headers);
+        assert_eq!(features.compact_blocks, Some(sendcmpct));
+        assert_eq!(features.fee_filter, Some(2000));
+    }
+
+    #[test]
+    fn test_negotiation_after_verack_error() {
+        let mut features = NegotiatedFeatures::default();
+        for message in [NetworkMessage::WtxidRelay, NetworkMessage::SendAddrV2] {
+            assert!(matches!(
+                features.on_message_after_verack(&message),
+                Err(HandshakeError::UnexpectedCommand {
+                    stage: HandshakeState::Established,
+                    ..
+                })
+            ));
+        }
+    }
+}
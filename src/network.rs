@@ -1,9 +1,18 @@
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use std::io::{Error, Read};
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use super::codec::write_var_bytes;
+use super::error::HandshakeError;
+use super::params::{self, NetworkParams};
+use super::services::ServiceFlags;
+use super::utils::sha256d;
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::borrow::Cow;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock, PoisonError};
 
 /// Different Bitcoin networks
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BitcoinNetwork {
     // Main Network
     Mainnet,
@@ -11,108 +20,321 @@ pub enum BitcoinNetwork {
     Regtest,
     // Test Network
     Testnet3,
+    // Test Network replacing Testnet3, see BIP94
+    Testnet4,
+    // Default Signet, whose blocks are signed by a fixed challenge
+    Signet,
+    // Any other network, such as a custom Signet or another Bitcoin-protocol chain
+    Custom(&'static NetworkParams),
 }
 
+// Networks known without any parameters file
+pub const BUILTIN_NETWORKS: [BitcoinNetwork; 5] = [
+    BitcoinNetwork::Mainnet,
+    BitcoinNetwork::Testnet3,
+    BitcoinNetwork::Testnet4,
+    BitcoinNetwork::Signet,
+    BitcoinNetwork::Regtest,
+];
+
+// Challenge script of the default Signet, a 1-of-2 multisig
+pub const DEFAULT_SIGNET_CHALLENGE: &str = "512103ad5e0edad18cb1f0fc0d28a3d4f1f3e445640337489abb10404f2d1e086be430210359ef5021964fe22d6f8e05b2463c9540ce96883fe3b278760f048f5189f2e6c452ae";
+
 impl BitcoinNetwork {
-    // Returns the magic value for every network
-    pub fn magic(&self) -> [u8; 4] {
+    /// Network described by the given parameters
+    /// The parameters live for the whole process to keep networks `Copy`, they are
+    /// interned so building the same network again reuses them instead of leaking more
+    pub fn custom(params: NetworkParams) -> Self {
+        static CUSTOM_NETWORKS: OnceLock<Mutex<Vec<&'static NetworkParams>>> = OnceLock::new();
+        let mut networks = CUSTOM_NETWORKS
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(known) = networks.iter().find(|known| ***known == params) {
+            return BitcoinNetwork::Custom(known);
+        }
+        let params = Box::leak(Box::new(params));
+        networks.push(params);
+        BitcoinNetwork::Custom(params)
+    }
+
+    /// Signet whose challenge script is given in hex
+    /// Its magic is the first 4 bytes of sha256d of the serialized challenge
+    pub fn custom_signet(challenge_hex: &str) -> Result<Self, HandshakeError> {
+        let challenge = hex::decode(challenge_hex.trim())?;
+        let mut serialized = Vec::with_capacity(challenge.len() + 9);
+        write_var_bytes(&mut serialized, &challenge)?;
+        let mut magic = [0u8; 4];
+        magic.copy_from_slice(&sha256d(&serialized)[..4]);
+        Ok(BitcoinNetwork::custom(NetworkParams {
+            magic,
+            // Nodes of private networks cannot be found through DNS
+            dns_seeds: Cow::Borrowed(&[]),
+            ..NetworkParams::signet()
+        }))
+    }
+    // Returns the parameters of every network
+    pub fn params(&self) -> &'static NetworkParams {
         match *self {
-            BitcoinNetwork::Mainnet => [0xf9, 0xbe, 0xb4, 0xd9], // 0xD9B4BEF9
-            BitcoinNetwork::Regtest => [0xfa, 0xbf, 0xb5, 0xda], // 0xDAB5BFFA
-            BitcoinNetwork::Testnet3 => [0x0b, 0x11, 0x09, 0x07], // 0x0709110B
+            BitcoinNetwork::Mainnet => &params::MAINNET,
+            BitcoinNetwork::Regtest => &params::REGTEST,
+            BitcoinNetwork::Testnet3 => &params::TESTNET3,
+            BitcoinNetwork::Testnet4 => &params::TESTNET4,
+            BitcoinNetwork::Signet => &params::SIGNET,
+            BitcoinNetwork::Custom(params) => params,
         }
     }
+    // Returns the magic value for every network
+    pub fn magic(&self) -> [u8; 4] {
+        self.params().magic
+    }
     pub fn as_u32(&self) -> u32 {
         u32::from_le_bytes(self.magic())
     }
+    // Returns the port nodes of every network listen on by default
+    pub fn default_port(&self) -> u16 {
+        self.params().port
+    }
+    // Returns the name of every network as used by Bitcoin Core's -chain option
+    pub fn as_str(&self) -> &'static str {
+        &self.params().name
+    }
+    /// Hash of the genesis block in internal byte order, the reverse of the usual hex form
+    pub fn genesis_hash(&self) -> [u8; 32] {
+        self.params().genesis_hash
+    }
+    // Returns the DNS seeds queried to find nodes of every network, same as Bitcoin Core
+    pub fn dns_seeds(&self) -> &'static [Cow<'static, str>] {
+        &self.params().dns_seeds
+    }
 }
 
-/// Helper to serialize IP address either V4 or V6
-/// For the Bitcoin protocol, when serializing data structures such as network addresses
-/// Each address is prefixed with the services field
-/// Once the address is serialized, it is added to the payload
-pub fn add_serialize_addr(
-    payload: &mut Vec<u8>,
-    services: u64,
-    add: &SocketAddr,
-) -> Result<(), Error> {
-    payload.write_u64::<LittleEndian>(services)?;
-    match add {
-        SocketAddr::V4(add_v4) => {
-            // Serialize the IPv4 address in IPv6-mapped format ::ffff:0:0/96 prefix
-            // First 10 bytes are zeros
-            payload.extend(&[0; 10]);
-            // Next 2 bytes are 0xff representing IPv4-mapping
-            payload.extend(&[0xff, 0xff]);
-            payload.extend(&add_v4.ip().octets());
+impl fmt::Display for BitcoinNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for BitcoinNetwork {
+    type Err = HandshakeError;
+
+    /// Parse a network from its name, `main` and `test` aliases are accepted
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "mainnet" | "main" | "bitcoin" => Ok(BitcoinNetwork::Mainnet),
+            "regtest" => Ok(BitcoinNetwork::Regtest),
+            "testnet3" | "testnet" | "test" => Ok(BitcoinNetwork::Testnet3),
+            "testnet4" => Ok(BitcoinNetwork::Testnet4),
+            "signet" => Ok(BitcoinNetwork::Signet),
+            _ => Err(HandshakeError::UnknownNetwork(name.to_string())),
         }
-        SocketAddr::V6(add_v6) => {
-            // Serialize the IPv6 address directly
-            for &segment in &add_v6.ip().segments() {
-                payload.write_u16::<LittleEndian>(segment)?
-            }
+    }
+}
+
+/// Network address of a node, prefixed with the services it supports
+/// IPv4 addresses are carried as IPv4-mapped IPv6 addresses, the address and the port
+/// are both in network byte order
+/// More information https://en.bitcoin.it/wiki/Protocol_documentation#Network_address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NetAddr {
+    // Services supported by the node
+    pub services: ServiceFlags,
+    // IP address of the node
+    pub ip: IpAddr,
+    // Port the node listens on
+    pub port: u16,
+}
+
+impl NetAddr {
+    // Size of an encoded network address: services, IPv6 address and port
+    pub const ENCODED_SIZE: usize = 26;
+
+    pub fn new(services: ServiceFlags, addr: SocketAddr) -> Self {
+        Self {
+            services,
+            ip: addr.ip(),
+            port: addr.port(),
         }
     }
-    payload.write_u16::<LittleEndian>(add.port())?;
-    Ok(())
+
+    /// Socket address of the node
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port)
+    }
+
+    /// Write the services, the 16 bytes address and the port
+    pub fn encode<W: Write>(&self, writer: &mut W) -> Result<(), HandshakeError> {
+        writer.write_u64::<LittleEndian>(self.services.bits())?;
+        let ip = match self.ip {
+            // Serialize the IPv4 address in IPv6-mapped format ::ffff:0:0/96 prefix
+            IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+            IpAddr::V6(ip) => ip,
+        };
+        writer.write_all(&ip.octets())?;
+        writer.write_u16::<BigEndian>(self.port)?;
+        Ok(())
+    }
+
+    /// Read a network address, IPv4-mapped addresses are turned back into IPv4
+    pub fn decode<R: Read>(reader: &mut R) -> Result<Self, HandshakeError> {
+        let services = ServiceFlags::from_bits_retain(reader.read_u64::<LittleEndian>()?);
+        let mut octets = [0u8; 16];
+        reader.read_exact(&mut octets)?;
+        let ip = Ipv6Addr::from(octets);
+        let ip = match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(ip),
+        };
+        let port = reader.read_u16::<BigEndian>()?;
+        Ok(Self { services, ip, port })
+    }
+}
+
+/// Network address with the last time the node was seen, as carried by addr messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimestampedNetAddr {
+    // UNIX timestamp in seconds of the last time the node was seen
+    pub time: u32,
+    // Network address of the node
+    pub addr: NetAddr,
 }
 
-/// Helper to deserialize a SocketAddr from a slice of bytes
-pub fn read_deserialized_add(cursor: &mut std::io::Cursor<Vec<u8>>) -> Result<SocketAddr, Error> {
-    let _services = cursor.read_u64::<LittleEndian>()?;
-
-    // Check if we have an IPv4-mapped IPv6 address or a regular IPv6 address
-    let mut addr_buf = [0u8; 16];
-    cursor.read_exact(&mut addr_buf)?;
-
-    let addr = if addr_buf[..10] == [0u8; 10] && addr_buf[10..12] == [0xff, 0xff] {
-        // If it is IPv4-mapped IPv6 address
-        let ipv4_bytes = &addr_buf[12..16];
-        let ipv4_addr = Ipv4Addr::new(ipv4_bytes[0], ipv4_bytes[1], ipv4_bytes[2], ipv4_bytes[3]);
-        SocketAddr::V4(SocketAddrV4::new(
-            ipv4_addr,
-            cursor.read_u16::<LittleEndian>()?,
-        ))
-    } else {
-        // If it is a regular IPv6 address
-        let ipv6_addr = Ipv6Addr::from(addr_buf);
-        SocketAddr::V6(SocketAddrV6::new(
-            ipv6_addr,
-            cursor.read_u16::<LittleEndian>()?,
-            0,
-            0,
-        ))
-    };
-    Ok(addr)
+impl TimestampedNetAddr {
+    // Size of an encoded timestamped network address
+    pub const ENCODED_SIZE: usize = 4 + NetAddr::ENCODED_SIZE;
+
+    /// Write the timestamp followed by the network address
+    pub fn encode<W: Write>(&self, writer: &mut W) -> Result<(), HandshakeError> {
+        writer.write_u32::<LittleEndian>(self.time)?;
+        self.addr.encode(writer)
+    }
+
+    /// Read the timestamp followed by the network address
+    pub fn decode<R: Read>(reader: &mut R) -> Result<Self, HandshakeError> {
+        let time = reader.read_u32::<LittleEndian>()?;
+        let addr = NetAddr::decode(reader)?;
+        Ok(Self { time, addr })
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+    use std::io::Cursor;
+    use std::net::Ipv4Addr;
 
     #[test]
-    fn test_add_ipv4_to_payload_ok() {
-        let mut payload = Vec::new();
-        let services = 1u64;
-        let ip = Ipv4Addr::new(127, 0, 0, 1);
-        let port = 8080;
-        let add = SocketAddr::V4(SocketAddrV4::new(ip, port));
+    fn test_network_from_name_ok() {
+        for network in BUILTIN_NETWORKS {
+            assert_eq!(
+                network.to_string().parse::<BitcoinNetwork>().ok(),
+                Some(network)
+            );
+        }
+        assert!("litecoin".parse::<BitcoinNetwork>().is_err());
+    }
+
+    #[test]
+    fn test_testnet4_params_ok() {
+        let network = BitcoinNetwork::Testnet4;
+        assert_eq!(network.as_u32(), 0x283f161c);
+        assert_eq!(network.default_port(), 48333);
+        assert_eq!(network.dns_seeds().len(), 2);
+
+        let mut genesis = network.genesis_hash();
+        genesis.reverse();
+        assert_eq!(
+            hex::encode(genesis),
+            "00000000da84f2bafbbc53dee25a72ae507ff4914b867c565be350b0da8bf043"
+        );
+    }
+
+    #[test]
+    fn test_custom_signet_magic_ok() {
+        // The default challenge must give back the default Signet magic
+        let network = BitcoinNetwork::custom_signet(DEFAULT_SIGNET_CHALLENGE)
+            .expect("Failed to derive signet magic");
+        assert_eq!(network.magic(), BitcoinNetwork::Signet.magic());
+        assert_eq!(network.default_port(), 38333);
 
-        assert!(add_serialize_addr(&mut payload, services, &add).is_ok());
-        assert_eq!(payload.len(), 26);
+        // Any other challenge gives another magic
+        let network = BitcoinNetwork::custom_signet("51").expect("Failed to derive signet magic");
+        assert_ne!(network.magic(), BitcoinNetwork::Signet.magic());
+        // Building the same network again reuses its parameters
+        let again = BitcoinNetwork::custom_signet("51").expect("Failed to derive signet magic");
+        assert!(std::ptr::eq(network.params(), again.params()));
     }
 
     #[test]
-    fn test_add_ipv6_to_payload_ok() {
+    fn test_custom_signet_invalid_hex_error() {
+        assert!(matches!(
+            BitcoinNetwork::custom_signet("51zz"),
+            Err(HandshakeError::InvalidHex(_))
+        ));
+    }
+
+    // addr_recv field of a version message captured from Bitcoin Core
+    // 10.0.0.1:8333 with NODE_NETWORK
+    const IPV4_NET_ADDR: [u8; 26] = [
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0xff, 0xff, 0x0a, 0x00, 0x00, 0x01, 0x20, 0x8d,
+    ];
+
+    // Address entry of an addr message captured from Bitcoin Core
+    // 10.0.0.1:8333 with NODE_NETWORK, seen at 2010-12-21 02:50:10
+    const TIMESTAMPED_NET_ADDR: [u8; 30] = [
+        0xe2, 0x15, 0x10, 0x4d, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0x0a, 0x00, 0x00, 0x01, 0x20, 0x8d,
+    ];
+
+    #[test]
+    fn test_net_addr_ipv4_round_trip_ok() {
+        let addr = NetAddr::decode(&mut Cursor::new(IPV4_NET_ADDR))
+            .expect("Failed to decode network address");
+        assert_eq!(addr.services, ServiceFlags::NODE_NETWORK);
+        assert_eq!(addr.ip, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(addr.port, 8333);
+
         let mut payload = Vec::new();
-        let services = 1u64;
-        let ip = Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1);
-        let port = 8080;
+        addr.encode(&mut payload)
+            .expect("Failed to encode network address");
+        assert_eq!(payload, IPV4_NET_ADDR);
+    }
 
-        let add = SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0));
+    #[test]
+    fn test_net_addr_ipv6_round_trip_ok() {
+        let ip = Ipv6Addr::new(0x2001, 0x0db8, 0, 0, 0, 0, 0, 1);
+        let addr = NetAddr::new(
+            ServiceFlags::from_bits_retain(0x409),
+            SocketAddr::new(IpAddr::V6(ip), 8333),
+        );
 
-        assert!(add_serialize_addr(&mut payload, services, &add).is_ok());
-        assert_eq!(payload.len(), 26);
+        let mut payload = Vec::new();
+        addr.encode(&mut payload)
+            .expect("Failed to encode network address");
+        assert_eq!(payload.len(), NetAddr::ENCODED_SIZE);
+        // Segments and port are written most significant byte first
+        assert_eq!(&payload[8..12], &[0x20, 0x01, 0x0d, 0xb8]);
+        assert_eq!(&payload[24..26], &[0x20, 0x8d]);
+
+        let decoded =
+            NetAddr::decode(&mut Cursor::new(payload)).expect("Failed to decode network address");
+        assert_eq!(decoded, addr);
+    }
+
+    #[test]
+    fn test_timestamped_net_addr_round_trip_ok() {
+        let addr = TimestampedNetAddr::decode(&mut Cursor::new(TIMESTAMPED_NET_ADDR))
+            .expect("Failed to decode timestamped network address");
+        assert_eq!(addr.time, 1292899810);
+        assert_eq!(
+            addr.addr.socket_addr(),
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 8333)
+        );
+
+        let mut payload = Vec::new();
+        addr.encode(&mut payload)
+            .expect("Failed to encode timestamped network address");
+        assert_eq!(payload, TIMESTAMPED_NET_ADDR);
     }
 }
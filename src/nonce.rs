@@ -0,0 +1,84 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Nonces of our outbound handshakes still in progress
+/// A version carrying one of them means we connected to ourselves, for instance through
+/// a NAT hairpin. Clones share the same nonces, so a single registry should be given to
+/// every outbound connection and listener of a node
+#[derive(Debug, Clone, Default)]
+pub struct NonceRegistry {
+    nonces: Arc<Mutex<HashSet<u64>>>,
+}
+
+impl NonceRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a nonce until the returned guard is dropped
+    pub fn register(&self, nonce: u64) -> NonceGuard {
+        self.lock().insert(nonce);
+        NonceGuard {
+            registry: self.clone(),
+            nonce,
+        }
+    }
+
+    /// Whether the nonce belongs to one of our handshakes in progress
+    pub fn contains(&self, nonce: u64) -> bool {
+        self.lock().contains(&nonce)
+    }
+
+    /// Number of handshakes in progress
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashSet<u64>> {
+        // The set stays consistent even if a holder panicked
+        self.nonces.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Keeps a nonce in its registry for as long as it lives
+#[derive(Debug)]
+pub struct NonceGuard {
+    registry: NonceRegistry,
+    nonce: u64,
+}
+
+impl NonceGuard {
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+}
+
+impl Drop for NonceGuard {
+    fn drop(&mut self) {
+        self.registry.lock().remove(&self.nonce);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nonce_registry_guard_ok() {
+        let registry = NonceRegistry::new();
+        let shared = registry.clone();
+
+        let guard = registry.register(42);
+        assert_eq!(guard.nonce(), 42);
+        assert!(shared.contains(42));
+        assert!(!shared.contains(43));
+
+        drop(guard);
+        assert!(!shared.contains(42));
+        assert!(shared.is_empty());
+    }
+}
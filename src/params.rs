@@ -0,0 +1,458 @@
+use super::error::HandshakeError;
+use super::headers::{hex_to_hash, BlockHeader, BLOCK_HEADER_SIZE};
+use serde::{Deserialize, Deserializer};
+use std::borrow::Cow;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+
+// Oldest protocol version Bitcoin Core still talks to, MIN_PEER_PROTO_VERSION
+pub const MIN_PEER_PROTOCOL_VERSION: i32 = 31800;
+// Expected time between two Bitcoin blocks, 10 minutes
+pub const POW_TARGET_SPACING: u32 = 10 * 60;
+// Expected time of a Bitcoin difficulty adjustment period, 2016 blocks of 10 minutes
+pub const POW_TARGET_TIMESPAN: u32 = 14 * 24 * 60 * 60;
+
+/// Parameters describing a Bitcoin-protocol network
+/// Built-in networks use the values of Bitcoin Core, other chains such as custom
+/// regtest forks, Litecoin or Dogecoin can be described in a TOML file
+/// Proof-of-work settings used by header chains default to those of Bitcoin's mainnet
+///
+/// ```toml
+/// name = "litecoin"
+/// magic = "fbc0b6db"
+/// port = 9333
+/// genesis_hash = "12a765e31ffd4059bada1e25190f6e98c99d9714d334efa41a195a7e7e04bfe2"
+/// dns_seeds = ["seed-a.litecoin.loshan.co.uk"]
+/// fixed_seeds = ["203.0.113.7:9333"]
+/// min_protocol_version = 70002
+/// pubkey_address_prefix = 48
+/// script_address_prefix = 50
+/// bech32_hrp = "ltc"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct NetworkParams {
+    // Name of the network, as used by Bitcoin Core's -chain option for built-in ones
+    pub name: Cow<'static, str>,
+    // Bytes starting every message, in wire order and written as hex in TOML
+    #[serde(deserialize_with = "deserialize_magic")]
+    pub magic: [u8; 4],
+    // Port nodes listen on by default
+    pub port: u16,
+    // Hash of the genesis block in internal byte order, written in the usual reversed
+    // hex form in TOML
+    #[serde(deserialize_with = "deserialize_hash")]
+    pub genesis_hash: [u8; 32],
+    // Host names queried to find nodes
+    #[serde(default)]
+    pub dns_seeds: Cow<'static, [Cow<'static, str>]>,
+    // Addresses of nodes tried when DNS seeds do not answer
+    #[serde(default)]
+    pub fixed_seeds: Cow<'static, [SocketAddr]>,
+    // Oldest protocol version accepted from a remote node
+    #[serde(default = "default_min_protocol_version")]
+    pub min_protocol_version: i32,
+    // Version byte of base58 pay-to-pubkey-hash addresses
+    pub pubkey_address_prefix: u8,
+    // Version byte of base58 pay-to-script-hash addresses
+    pub script_address_prefix: u8,
+    // Human readable part of segwit addresses, if the network supports them
+    #[serde(default)]
+    pub bech32_hrp: Option<Cow<'static, str>>,
+    // Header of the genesis block, the root of header chains, as 80 bytes of hex in TOML
+    #[serde(default, deserialize_with = "deserialize_header")]
+    pub genesis_header: Option<BlockHeader>,
+    // Highest proof-of-work target in internal byte order, written in reversed hex in TOML
+    #[serde(default = "default_pow_limit", deserialize_with = "deserialize_hash")]
+    pub pow_limit: [u8; 32],
+    // Expected time between two blocks, in seconds
+    #[serde(default = "default_pow_target_spacing")]
+    pub pow_target_spacing: u32,
+    // Expected time of a difficulty adjustment period, in seconds
+    #[serde(default = "default_pow_target_timespan")]
+    pub pow_target_timespan: u32,
+    // Whether a block may use the lowest difficulty once none was found for two spacings
+    #[serde(default)]
+    pub pow_allow_min_difficulty_blocks: bool,
+    // Whether the difficulty never changes, as on regtest
+    #[serde(default)]
+    pub pow_no_retargeting: bool,
+    // Whether retargets start from the first block of the period, see BIP94
+    #[serde(default)]
+    pub enforce_bip94: bool,
+}
+
+impl NetworkParams {
+    /// Parameters of the main network
+    pub fn mainnet() -> Self {
+        MAINNET.clone()
+    }
+
+    /// Parameters of the regression test network
+    pub fn regtest() -> Self {
+        REGTEST.clone()
+    }
+
+    /// Parameters of the third test network
+    pub fn testnet3() -> Self {
+        TESTNET3.clone()
+    }
+
+    /// Parameters of the fourth test network, see BIP94
+    pub fn testnet4() -> Self {
+        TESTNET4.clone()
+    }
+
+    /// Parameters of the default Signet
+    pub fn signet() -> Self {
+        SIGNET.clone()
+    }
+
+    /// Number of blocks between two difficulty adjustments
+    pub fn difficulty_adjustment_interval(&self) -> u32 {
+        self.pow_target_timespan / self.pow_target_spacing
+    }
+
+    /// Read parameters from a TOML document
+    /// Proof-of-work timings must leave at least one block per adjustment period
+    pub fn from_toml(document: &str) -> Result<Self, HandshakeError> {
+        let params: Self = toml::from_str(document)?;
+        if params.pow_target_spacing == 0 {
+            return Err(invalid_params("pow_target_spacing must be positive"));
+        }
+        if params.pow_target_timespan < params.pow_target_spacing {
+            return Err(invalid_params(
+                "pow_target_timespan must be at least pow_target_spacing",
+            ));
+        }
+        Ok(params)
+    }
+
+    /// Read parameters from a TOML file
+    pub fn from_toml_file<P: AsRef<Path>>(path: P) -> Result<Self, HandshakeError> {
+        Self::from_toml(&fs::read_to_string(path)?)
+    }
+}
+
+/// Error for parameters read fine but unusable by header chains
+fn invalid_params(reason: &str) -> HandshakeError {
+    HandshakeError::InvalidNetworkParams(serde::de::Error::custom(reason))
+}
+
+pub(crate) static MAINNET: NetworkParams = NetworkParams {
+    name: Cow::Borrowed("mainnet"),
+    magic: [0xf9, 0xbe, 0xb4, 0xd9],
+    port: 8333,
+    genesis_hash: hash_from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"),
+    dns_seeds: Cow::Borrowed(&[
+        Cow::Borrowed("seed.bitcoin.sipa.be"),
+        Cow::Borrowed("dnsseed.bluematt.me"),
+        Cow::Borrowed("seed.bitcoin.jonasschnelli.ch"),
+        Cow::Borrowed("seed.btc.petertodd.net"),
+        Cow::Borrowed("seed.bitcoin.sprovoost.nl"),
+        Cow::Borrowed("dnsseed.emzy.de"),
+        Cow::Borrowed("seed.bitcoin.wiz.biz"),
+        Cow::Borrowed("seed.mainnet.achownodes.xyz"),
+    ]),
+    // Bitcoin Core generates hundreds of fixed seeds, only DNS seeds are built in
+    fixed_seeds: Cow::Borrowed(&[]),
+    min_protocol_version: MIN_PEER_PROTOCOL_VERSION,
+    pubkey_address_prefix: 0,
+    script_address_prefix: 5,
+    bech32_hrp: Some(Cow::Borrowed("bc")),
+    genesis_header: Some(genesis_header(
+        "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+        1231006505,
+        0x1d00ffff,
+        2083236893,
+    )),
+    pow_limit: hash_from_hex("00000000ffffffffffffffffffffffffffffffffffffffffffffffffffffffff"),
+    pow_target_spacing: POW_TARGET_SPACING,
+    pow_target_timespan: POW_TARGET_TIMESPAN,
+    pow_allow_min_difficulty_blocks: false,
+    pow_no_retargeting: false,
+    enforce_bip94: false,
+};
+
+pub(crate) static REGTEST: NetworkParams = NetworkParams {
+    name: Cow::Borrowed("regtest"),
+    magic: [0xfa, 0xbf, 0xb5, 0xda],
+    port: 18444,
+    genesis_hash: hash_from_hex("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206"),
+    dns_seeds: Cow::Borrowed(&[]),
+    fixed_seeds: Cow::Borrowed(&[]),
+    min_protocol_version: MIN_PEER_PROTOCOL_VERSION,
+    pubkey_address_prefix: 111,
+    script_address_prefix: 196,
+    bech32_hrp: Some(Cow::Borrowed("bcrt")),
+    genesis_header: Some(genesis_header(
+        "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+        1296688602,
+        0x207fffff,
+        2,
+    )),
+    pow_limit: hash_from_hex("7fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"),
+    pow_target_spacing: POW_TARGET_SPACING,
+    pow_target_timespan: POW_TARGET_TIMESPAN,
+    pow_allow_min_difficulty_blocks: true,
+    pow_no_retargeting: true,
+    enforce_bip94: false,
+};
+
+pub(crate) static TESTNET3: NetworkParams = NetworkParams {
+    name: Cow::Borrowed("testnet3"),
+    magic: [0x0b, 0x11, 0x09, 0x07],
+    port: 18333,
+    genesis_hash: hash_from_hex("000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943"),
+    dns_seeds: Cow::Borrowed(&[
+        Cow::Borrowed("testnet-seed.bitcoin.jonasschnelli.ch"),
+        Cow::Borrowed("seed.tbtc.petertodd.net"),
+        Cow::Borrowed("seed.testnet.bitcoin.sprovoost.nl"),
+        Cow::Borrowed("testnet-seed.bluematt.me"),
+        Cow::Borrowed("seed.testnet.achownodes.xyz"),
+    ]),
+    fixed_seeds: Cow::Borrowed(&[]),
+    min_protocol_version: MIN_PEER_PROTOCOL_VERSION,
+    pubkey_address_prefix: 111,
+    script_address_prefix: 196,
+    bech32_hrp: Some(Cow::Borrowed("tb")),
+    genesis_header: Some(genesis_header(
+        "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+        1296688602,
+        0x1d00ffff,
+        414098458,
+    )),
+    pow_limit: hash_from_hex("00000000ffffffffffffffffffffffffffffffffffffffffffffffffffffffff"),
+    pow_target_spacing: POW_TARGET_SPACING,
+    pow_target_timespan: POW_TARGET_TIMESPAN,
+    pow_allow_min_difficulty_blocks: true,
+    pow_no_retargeting: false,
+    enforce_bip94: false,
+};
+
+pub(crate) static TESTNET4: NetworkParams = NetworkParams {
+    name: Cow::Borrowed("testnet4"),
+    magic: [0x1c, 0x16, 0x3f, 0x28],
+    port: 48333,
+    genesis_hash: hash_from_hex("00000000da84f2bafbbc53dee25a72ae507ff4914b867c565be350b0da8bf043"),
+    dns_seeds: Cow::Borrowed(&[
+        Cow::Borrowed("seed.testnet4.bitcoin.sprovoost.nl"),
+        Cow::Borrowed("seed.testnet4.wiz.biz"),
+    ]),
+    fixed_seeds: Cow::Borrowed(&[]),
+    min_protocol_version: MIN_PEER_PROTOCOL_VERSION,
+    pubkey_address_prefix: 111,
+    script_address_prefix: 196,
+    bech32_hrp: Some(Cow::Borrowed("tb")),
+    genesis_header: Some(genesis_header(
+        "7aa0a7ae1e223414cb807e40cd57e667b718e42aaf9306db9102fe28912b7b4e",
+        1714777860,
+        0x1d00ffff,
+        393743547,
+    )),
+    pow_limit: hash_from_hex("00000000ffffffffffffffffffffffffffffffffffffffffffffffffffffffff"),
+    pow_target_spacing: POW_TARGET_SPACING,
+    pow_target_timespan: POW_TARGET_TIMESPAN,
+    pow_allow_min_difficulty_blocks: true,
+    pow_no_retargeting: false,
+    enforce_bip94: true,
+};
+
+// Every Signet shares this genesis block, custom ones only differ by their magic
+pub(crate) static SIGNET: NetworkParams = NetworkParams {
+    name: Cow::Borrowed("signet"),
+    magic: [0x0a, 0x03, 0xcf, 0x40],
+    port: 38333,
+    genesis_hash: hash_from_hex("00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6"),
+    dns_seeds: Cow::Borrowed(&[
+        Cow::Borrowed("seed.signet.bitcoin.sprovoost.nl"),
+        Cow::Borrowed("seed.signet.achownodes.xyz"),
+    ]),
+    fixed_seeds: Cow::Borrowed(&[]),
+    min_protocol_version: MIN_PEER_PROTOCOL_VERSION,
+    pubkey_address_prefix: 111,
+    script_address_prefix: 196,
+    bech32_hrp: Some(Cow::Borrowed("tb")),
+    genesis_header: Some(genesis_header(
+        "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+        1598918400,
+        0x1e0377ae,
+        52613770,
+    )),
+    pow_limit: hash_from_hex("00000377ae000000000000000000000000000000000000000000000000000000"),
+    pow_target_spacing: POW_TARGET_SPACING,
+    pow_target_timespan: POW_TARGET_TIMESPAN,
+    pow_allow_min_difficulty_blocks: false,
+    pow_no_retargeting: false,
+    enforce_bip94: false,
+};
+
+fn default_min_protocol_version() -> i32 {
+    MIN_PEER_PROTOCOL_VERSION
+}
+
+fn default_pow_limit() -> [u8; 32] {
+    MAINNET.pow_limit
+}
+
+fn default_pow_target_spacing() -> u32 {
+    POW_TARGET_SPACING
+}
+
+fn default_pow_target_timespan() -> u32 {
+    POW_TARGET_TIMESPAN
+}
+
+/// Genesis header of the built-in networks, which only differ by these fields
+const fn genesis_header(merkle_root: &str, time: u32, bits: u32, nonce: u32) -> BlockHeader {
+    BlockHeader {
+        version: 1,
+        prev_blockhash: [0u8; 32],
+        merkle_root: hash_from_hex(merkle_root),
+        time,
+        bits,
+        nonce,
+    }
+}
+
+/// Turn a hash written in the usual reversed hex form into internal byte order
+/// Evaluated at compile time for the built-in networks
+pub(crate) const fn hash_from_hex(hex: &str) -> [u8; 32] {
+    const fn nibble(c: u8) -> u8 {
+        match c {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'f' => c - b'a' + 10,
+            b'A'..=b'F' => c - b'A' + 10,
+            _ => panic!("Invalid hex digit"),
+        }
+    }
+    let bytes = hex.as_bytes();
+    assert!(bytes.len() == 64, "A hash is 32 bytes long");
+    let mut hash = [0u8; 32];
+    let mut i = 0;
+    while i < 32 {
+        hash[31 - i] = (nibble(bytes[2 * i]) << 4) | nibble(bytes[2 * i + 1]);
+        i += 1;
+    }
+    hash
+}
+
+fn deserialize_magic<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 4], D::Error> {
+    let hex_magic = String::deserialize(deserializer)?;
+    let mut magic = [0u8; 4];
+    hex::decode_to_slice(hex_magic.trim_start_matches("0x"), &mut magic)
+        .map_err(serde::de::Error::custom)?;
+    Ok(magic)
+}
+
+fn deserialize_hash<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 32], D::Error> {
+    let hex_hash = String::deserialize(deserializer)?;
+    hex_to_hash(&hex_hash).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_header<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<BlockHeader>, D::Error> {
+    let hex_header = String::deserialize(deserializer)?;
+    let mut header = [0u8; BLOCK_HEADER_SIZE];
+    hex::decode_to_slice(&hex_header, &mut header).map_err(serde::de::Error::custom)?;
+    BlockHeader::decode(&mut header.as_slice())
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LITECOIN_PARAMS: &str = r#"
+        name = "litecoin"
+        magic = "fbc0b6db"
+        port = 9333
+        genesis_hash = "12a765e31ffd4059bada1e25190f6e98c99d9714d334efa41a195a7e7e04bfe2"
+        dns_seeds = ["seed-a.litecoin.loshan.co.uk", "dnsseed.thrasher.io"]
+        fixed_seeds = ["203.0.113.7:9333"]
+        min_protocol_version = 70002
+        pubkey_address_prefix = 48
+        script_address_prefix = 50
+        bech32_hrp = "ltc"
+    "#;
+
+    #[test]
+    fn test_params_from_toml_ok() {
+        let params = NetworkParams::from_toml(LITECOIN_PARAMS).expect("Failed to read params");
+        assert_eq!(params.name, "litecoin");
+        assert_eq!(params.magic, [0xfb, 0xc0, 0xb6, 0xdb]);
+        assert_eq!(params.port, 9333);
+        assert_eq!(params.genesis_hash[31], 0x12);
+        assert_eq!(params.genesis_hash[0], 0xe2);
+        assert_eq!(params.dns_seeds.len(), 2);
+        assert_eq!(params.fixed_seeds[0], "203.0.113.7:9333".parse().unwrap());
+        assert_eq!(params.min_protocol_version, 70002);
+        assert_eq!(params.pubkey_address_prefix, 48);
+        assert_eq!(params.bech32_hrp.as_deref(), Some("ltc"));
+    }
+
+    #[test]
+    fn test_params_from_toml_defaults_ok() {
+        // A Dogecoin-like chain without seeds nor segwit addresses
+        let params = NetworkParams::from_toml(
+            r#"
+            name = "dogecoin"
+            magic = "c0c0c0c0"
+            port = 22556
+            genesis_hash = "1a91e3dace36e2be3bf030a65679fe821aa1d6ef92e7c9902eb318182c355691"
+            pubkey_address_prefix = 30
+            script_address_prefix = 22
+            "#,
+        )
+        .expect("Failed to read params");
+        assert!(params.dns_seeds.is_empty());
+        assert!(params.fixed_seeds.is_empty());
+        assert_eq!(params.min_protocol_version, MIN_PEER_PROTOCOL_VERSION);
+        assert_eq!(params.bech32_hrp, None);
+    }
+
+    #[test]
+    fn test_params_from_toml_invalid_magic_error() {
+        let document = LITECOIN_PARAMS.replace("fbc0b6db", "fbc0b6");
+        assert!(matches!(
+            NetworkParams::from_toml(&document),
+            Err(HandshakeError::InvalidNetworkParams(_))
+        ));
+    }
+
+    #[test]
+    fn test_params_from_toml_invalid_pow_timing_error() {
+        for timing in [
+            "pow_target_spacing = 0",
+            "pow_target_spacing = 600\npow_target_timespan = 300",
+        ] {
+            let document = format!("{LITECOIN_PARAMS}\n{timing}");
+            assert!(matches!(
+                NetworkParams::from_toml(&document),
+                Err(HandshakeError::InvalidNetworkParams(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_builtin_genesis_header_ok() {
+        for params in [&MAINNET, &TESTNET3, &TESTNET4, &SIGNET, &REGTEST] {
+            let genesis = params.genesis_header.expect("Genesis header is known");
+            assert_eq!(genesis.block_hash(), params.genesis_hash, "{}", params.name);
+            assert!(genesis.check_proof_of_work(&params.pow_limit).is_ok());
+        }
+        assert_eq!(MAINNET.difficulty_adjustment_interval(), 2016);
+    }
+
+    #[test]
+    fn test_builtin_genesis_hash_ok() {
+        let mut genesis = NetworkParams::mainnet().genesis_hash;
+        genesis.reverse();
+        assert_eq!(
+            hex::encode(genesis),
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+        );
+    }
+}
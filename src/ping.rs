@@ -0,0 +1,97 @@
+use super::error::HandshakeError;
+use super::messages::Serializable;
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::io::Cursor;
+
+/// Ping message checking the remote node is still there, see BIP31
+/// More information https://en.bitcoin.it/wiki/Protocol_documentation#ping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PingMessage {
+    // Nonce to echo in the pong, absent before BIP31 where no pong is expected
+    nonce: Option<u64>,
+}
+
+impl PingMessage {
+    pub fn new(nonce: u64) -> Self {
+        Self { nonce: Some(nonce) }
+    }
+
+    /// Ping for nodes older than BIP31, which do not answer with a pong
+    pub fn without_nonce() -> Self {
+        Self { nonce: None }
+    }
+
+    pub fn nonce(&self) -> Option<u64> {
+        self.nonce
+    }
+}
+
+impl Serializable for PingMessage {
+    fn serialize(&self) -> Result<Vec<u8>, HandshakeError> {
+        Ok(self
+            .nonce
+            .map_or_else(Vec::new, |nonce| nonce.to_le_bytes().to_vec()))
+    }
+
+    fn deserialize(msg: Vec<u8>) -> Result<Box<Self>, HandshakeError> {
+        // Nodes older than BIP31 send pings without any payload
+        if msg.is_empty() {
+            return Ok(Box::new(Self::without_nonce()));
+        }
+        let nonce = Cursor::new(msg).read_u64::<LittleEndian>()?;
+        Ok(Box::new(Self::new(nonce)))
+    }
+}
+
+/// Pong message answering a ping with its nonce, see BIP31
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PongMessage {
+    // Nonce of the ping being answered
+    nonce: u64,
+}
+
+impl PongMessage {
+    pub fn new(nonce: u64) -> Self {
+        Self { nonce }
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+}
+
+impl Serializable for PongMessage {
+    fn serialize(&self) -> Result<Vec<u8>, HandshakeError> {
+        Ok(self.nonce.to_le_bytes().to_vec())
+    }
+
+    fn deserialize(msg: Vec<u8>) -> Result<Box<Self>, HandshakeError> {
+        let nonce = Cursor::new(msg).read_u64::<LittleEndian>()?;
+        Ok(Box::new(Self::new(nonce)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ping_pong_round_trip_ok() {
+        let ping = PingMessage::new(0x0102030405060708);
+        let bytes = ping.serialize().expect("Failed to serialize ping");
+        assert_eq!(bytes, [8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(*PingMessage::deserialize(bytes.clone()).unwrap(), ping);
+
+        let pong = PongMessage::new(0x0102030405060708);
+        assert_eq!(pong.serialize().ok(), Some(bytes.clone()));
+        assert_eq!(*PongMessage::deserialize(bytes).unwrap(), pong);
+    }
+
+    #[test]
+    fn test_ping_without_nonce_ok() {
+        let ping = PingMessage::deserialize(Vec::new()).expect("Failed to decode ping");
+        assert_eq!(ping.nonce(), None);
+        assert!(ping.serialize().unwrap().is_empty());
+        assert!(PongMessage::deserialize(Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+use super::error::HandshakeError;
+use bitflags::bitflags;
+use std::fmt;
+use std::str::FromStr;
+
+bitflags! {
+    /// Services a node supports, advertised in its version message and its addresses
+    /// Bits without a name are kept as received
+    /// More information https://github.com/bitcoin/bitcoin/blob/master/src/protocol.h
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub struct ServiceFlags: u64 {
+        // Full node serving the whole blockchain
+        const NODE_NETWORK = 1 << 0;
+        // Node answering bloom filtered requests, see BIP111
+        const NODE_BLOOM = 1 << 2;
+        // Node serving blocks and transactions with their witness, see BIP144
+        const NODE_WITNESS = 1 << 3;
+        // Node serving compact block filters, see BIP157
+        const NODE_COMPACT_FILTERS = 1 << 6;
+        // Node serving only the last 288 blocks, see BIP159
+        const NODE_NETWORK_LIMITED = 1 << 10;
+        // Node supporting the encrypted transport, see BIP324
+        const NODE_P2P_V2 = 1 << 11;
+
+        // Unknown bits are valid and must survive every operation
+        const _ = !0;
+    }
+}
+
+impl ServiceFlags {
+    // Flags without any service
+    pub const NONE: ServiceFlags = ServiceFlags::empty();
+}
+
+impl fmt::Display for ServiceFlags {
+    /// Names of the known services separated by `|`, unknown bits written in hex
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("NONE");
+        }
+        let mut known = ServiceFlags::NONE;
+        let mut separator = "";
+        for (name, flag) in self.iter_names() {
+            write!(f, "{separator}{name}")?;
+            known |= flag;
+            separator = " | ";
+        }
+        let unknown = self.bits() & !known.bits();
+        if unknown != 0 {
+            write!(f, "{separator}{unknown:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ServiceFlags {
+    type Err = HandshakeError;
+
+    /// Parse service names separated by `|` or `,`, with or without their `NODE_` prefix,
+    /// or a raw bitmask written in decimal or hex
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut flags = ServiceFlags::NONE;
+        for part in value.split(['|', ',']).map(str::trim) {
+            let name = part.to_ascii_uppercase();
+            let name = name.strip_prefix("NODE_").unwrap_or(&name);
+            let flag = match name {
+                "" | "NONE" => ServiceFlags::NONE,
+                _ => match ServiceFlags::from_name(&format!("NODE_{name}")) {
+                    Some(flag) => flag,
+                    None => {
+                        let bits = match part.strip_prefix("0x") {
+                            Some(hex_bits) => u64::from_str_radix(hex_bits, 16),
+                            None => part.parse(),
+                        };
+                        let bits =
+                            bits.map_err(|_| HandshakeError::UnknownService(part.to_string()))?;
+                        ServiceFlags::from_bits_retain(bits)
+                    }
+                },
+            };
+            flags |= flag;
+        }
+        Ok(flags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_service_flags_keep_unknown_bits_ok() {
+        let flags = ServiceFlags::from_bits_retain(0x409 | 1 << 24);
+        assert!(flags.contains(ServiceFlags::NODE_NETWORK | ServiceFlags::NODE_WITNESS));
+        assert_eq!(flags.bits(), 0x409 | 1 << 24);
+        assert_eq!((flags | ServiceFlags::NODE_BLOOM).bits(), 0x40d | 1 << 24);
+    }
+
+    #[test]
+    fn test_service_flags_display_ok() {
+        assert_eq!(ServiceFlags::NONE.to_string(), "NONE");
+        assert_eq!(
+            ServiceFlags::from_bits_retain(0xc09 | 1 << 24).to_string(),
+            "NODE_NETWORK | NODE_WITNESS | NODE_NETWORK_LIMITED | NODE_P2P_V2 | 0x1000000"
+        );
+    }
+
+    #[test]
+    fn test_service_flags_from_str_ok() {
+        assert_eq!(
+            "network,witness".parse::<ServiceFlags>().ok(),
+            Some(ServiceFlags::NODE_NETWORK | ServiceFlags::NODE_WITNESS)
+        );
+        assert_eq!(
+            "NODE_BLOOM | 0x1000000".parse::<ServiceFlags>().ok(),
+            Some(ServiceFlags::from_bits_retain(0x4 | 1 << 24))
+        );
+        assert_eq!(
+            "1033".parse::<ServiceFlags>().ok().map(|f| f.bits()),
+            Some(1033)
+        );
+        assert_eq!(
+            "none".parse::<ServiceFlags>().ok(),
+            Some(ServiceFlags::NONE)
+        );
+        assert!("teleport".parse::<ServiceFlags>().is_err());
+    }
+}
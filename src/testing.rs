@@ -0,0 +1,484 @@
+use super::addr::{AddrMessage, AddrV2Entry, AddrV2Message};
+use super::block::Block;
+use super::broadcast::{RejectMessage, REJECT_INVALID};
+use super::error::HandshakeError;
+use super::framing::{MessageReader, MessageWriter};
+use super::headers::{BlockHeader, GetHeadersMessage, HeadersMessage, MAX_HEADERS_RESULTS};
+use super::inventory::{Inventory, InventoryMessage};
+use super::messages::{BitcoinMessage, NetworkMessage, Serializable, MAX_PAYLOAD_SIZE};
+use super::network::{BitcoinNetwork, NetAddr, TimestampedNetAddr};
+use super::services::ServiceFlags;
+use super::transaction::{OutPoint, Transaction, TxIn, TxOut};
+use super::vv::{Command, ProtocolFeatures, VersionMessage, PROTOCOL_VERSION};
+use std::io::{self, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Scripted reaction of the mock node to an incoming version message
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MockBehaviour {
+    // Answer with version and verack like Bitcoin Core does
+    Honest,
+    // Answer like an honest node after waiting for the given delay
+    Delayed(Duration),
+    // Keep the connection open without ever replying
+    Silent,
+    // Answer with a version framed with the magic of another network
+    WrongMagic(BitcoinNetwork),
+    // Answer with a version whose checksum does not match its payload
+    BadChecksum,
+    // Send only the beginning of a message header then close the connection
+    TruncatedHeader,
+    // Announce a version payload larger than the protocol allows
+    OversizedPayload,
+}
+
+/// Scripted reaction of the mock node to announced transactions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockTxPolicy {
+    // Never ask for announced transactions, as if already known
+    Ignore,
+    // Download announced transactions without saying anything, like Bitcoin Core
+    Accept,
+    // Download announced transactions then refuse them with a reject message
+    Reject,
+    // Download announced transactions then announce them back
+    Relay,
+}
+
+/// Settings of the mock node
+#[derive(Debug, Clone)]
+pub struct MockNodeConfig {
+    // Network the mock node belongs to
+    pub network: BitcoinNetwork,
+    // Protocol version advertised in the mock node's version message
+    pub version: i32,
+    // Software advertised in the mock node's version message
+    pub user_agent: String,
+    // Services advertised in the mock node's version message
+    pub services: ServiceFlags,
+    // Highest block advertised in the mock node's version message
+    pub start_height: i32,
+    // Reaction to incoming handshakes
+    pub behaviour: MockBehaviour,
+    // Addresses given in answer to getaddr, with addr or addrv2 as the remote node prefers
+    pub addresses: Vec<AddrV2Entry>,
+    // Announce its own address right before answering getaddr, like Bitcoin Core does
+    // on its own shortly after the handshake
+    pub announce_self: bool,
+    // Headers following the genesis header, given in answer to getheaders
+    pub headers: Vec<BlockHeader>,
+    // Blocks given in answer to getdata, others being answered with notfound
+    pub blocks: Vec<Block>,
+    // Reaction to transactions announced with inv
+    pub tx_policy: MockTxPolicy,
+}
+
+impl MockNodeConfig {
+    /// Honest full node settings on a given network
+    pub fn new(network: BitcoinNetwork) -> Self {
+        Self {
+            network,
+            version: PROTOCOL_VERSION,
+            user_agent: "/Satoshi:27.0.0/".to_string(),
+            services: ServiceFlags::NODE_NETWORK | ServiceFlags::NODE_WITNESS,
+            start_height: 0,
+            behaviour: MockBehaviour::Honest,
+            addresses: Vec::new(),
+            announce_self: false,
+            headers: Vec::new(),
+            blocks: Vec::new(),
+            tx_policy: MockTxPolicy::Accept,
+        }
+    }
+}
+
+/// Headers following the regtest genesis header, 10 minutes apart
+/// Regtest targets are met by about one hash out of two, so mining them is instant
+pub fn regtest_headers(count: usize) -> Vec<BlockHeader> {
+    let params = BitcoinNetwork::Regtest.params();
+    let mut prev = params
+        .genesis_header
+        .expect("Regtest genesis header is known");
+    let mut headers = Vec::with_capacity(count);
+    for _ in 0..count {
+        let mut header = BlockHeader {
+            version: 4,
+            prev_blockhash: prev.block_hash(),
+            merkle_root: [0u8; 32],
+            time: prev.time + 600,
+            bits: prev.bits,
+            nonce: 0,
+        };
+        while header.check_proof_of_work(&params.pow_limit).is_err() {
+            header.nonce += 1;
+        }
+        headers.push(header);
+        prev = header;
+    }
+    headers
+}
+
+/// Regtest block following the given header, made of a coinbase and the given transactions
+/// Its merkle root matches, but witness data is not committed to
+pub fn regtest_block(prev: &BlockHeader, transactions: Vec<Transaction>) -> Block {
+    let pow_limit = BitcoinNetwork::Regtest.params().pow_limit;
+    let coinbase = Transaction {
+        version: 2,
+        inputs: vec![TxIn {
+            previous_output: OutPoint::NULL,
+            script_sig: prev.time.to_le_bytes().to_vec(),
+            sequence: u32::MAX,
+            witness: Vec::new(),
+        }],
+        outputs: vec![TxOut {
+            value: 50_0000_0000,
+            script_pubkey: vec![0x51],
+        }],
+        lock_time: 0,
+    };
+    let mut block = Block {
+        header: BlockHeader {
+            version: 4,
+            prev_blockhash: prev.block_hash(),
+            merkle_root: [0u8; 32],
+            time: prev.time + 600,
+            bits: prev.bits,
+            nonce: 0,
+        },
+        transactions: [vec![coinbase], transactions].concat(),
+    };
+    block.header.merkle_root = block.merkle_root().0;
+    while block.header.check_proof_of_work(&pow_limit).is_err() {
+        block.header.nonce += 1;
+    }
+    block
+}
+
+/// In-process Bitcoin node listening on an ephemeral localhost port
+/// It speaks version/verack following its scripted behaviour then answers getaddr,
+/// getheaders, getdata and inv, so handshakes can be tested without running bitcoind.
+/// The node stops when dropped
+#[derive(Debug)]
+pub struct MockNode {
+    // Address the mock node listens on
+    addr: SocketAddr,
+    // Raised to stop accepting connections
+    stopped: Arc<AtomicBool>,
+    // Thread accepting the connections
+    handle: Option<JoinHandle<()>>,
+}
+
+impl MockNode {
+    /// Start a mock node with the given settings
+    pub fn start(config: MockNodeConfig) -> Result<Self, HandshakeError> {
+        let listener =
+            TcpListener::bind("127.0.0.1:0").map_err(HandshakeError::ConnectionFailed)?;
+        let addr = listener.local_addr()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let accept_stopped = Arc::clone(&stopped);
+        let handle = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if accept_stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    let config = config.clone();
+                    // Connection failures are the expected outcome of most behaviours
+                    thread::spawn(move || serve(&config, stream));
+                }
+            }
+        });
+
+        Ok(Self {
+            addr,
+            stopped,
+            handle: Some(handle),
+        })
+    }
+
+    /// Honest mock node on a given network
+    pub fn honest(network: BitcoinNetwork) -> Result<Self, HandshakeError> {
+        Self::start(MockNodeConfig::new(network))
+    }
+
+    /// Mock node with a scripted behaviour on a given network
+    pub fn with_behaviour(
+        network: BitcoinNetwork,
+        behaviour: MockBehaviour,
+    ) -> Result<Self, HandshakeError> {
+        Self::start(MockNodeConfig {
+            behaviour,
+            ..MockNodeConfig::new(network)
+        })
+    }
+
+    /// Address the mock node listens on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for MockNode {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wake the accepting thread up so it notices the node is stopped
+        let _ = TcpStream::connect(self.addr);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Handle a single connection following the scripted behaviour
+fn serve(config: &MockNodeConfig, stream: TcpStream) -> Result<(), HandshakeError> {
+    let mut reader = MessageReader::new(&stream, config.network);
+    let mut writer = MessageWriter::new(&stream);
+
+    // Every behaviour waits for the version of the connecting node
+    let peer_version = VersionMessage::deserialize(reader.read_message()?.payload().to_vec())?;
+
+    let version = VersionMessage::new(
+        config.version,
+        stream.peer_addr()?,
+        stream.local_addr()?,
+        config.services,
+        config.user_agent.clone(),
+        config.start_height,
+        false,
+    )
+    .serialize()?;
+    let version_message = BitcoinMessage::new(Command::Version, version, config.network);
+
+    match config.behaviour {
+        MockBehaviour::Honest => {}
+        MockBehaviour::Delayed(delay) => thread::sleep(delay),
+        MockBehaviour::Silent => return drain(&stream),
+        MockBehaviour::WrongMagic(network) => {
+            let version = BitcoinMessage::new(
+                Command::Version,
+                version_message.payload().to_vec(),
+                network,
+            );
+            return write_raw(&stream, &version.serialize()?);
+        }
+        MockBehaviour::BadChecksum => {
+            let mut version = version_message.serialize()?;
+            version[20] ^= 0xff;
+            return write_raw(&stream, &version);
+        }
+        MockBehaviour::TruncatedHeader => {
+            (&stream).write_all(&version_message.serialize()?[..10])?;
+            stream.shutdown(Shutdown::Both)?;
+            return Ok(());
+        }
+        MockBehaviour::OversizedPayload => {
+            let mut version = version_message.serialize()?;
+            version[16..20].copy_from_slice(&(MAX_PAYLOAD_SIZE + 1).to_le_bytes());
+            return write_raw(&stream, &version);
+        }
+    }
+
+    writer.write_message(&version_message)?;
+    // Like Bitcoin Core, wtxidrelay is sent to nodes recent enough to understand it
+    let wtxid_relay =
+        ProtocolFeatures::for_version(config.version.min(peer_version.version())).wtxid_relay;
+    if wtxid_relay {
+        writer.write_message(&NetworkMessage::WtxidRelay.to_bitcoin_message(config.network)?)?;
+    }
+    writer.write_message(&BitcoinMessage::new(
+        Command::Verack,
+        Vec::new(),
+        config.network,
+    ))?;
+    let local_addr = stream.local_addr()?;
+    answer_requests(config, &mut reader, &mut writer, wtxid_relay, local_addr)
+}
+
+/// Answer getaddr, getheaders, getdata and inv until the remote node disconnects
+/// Pings are left unanswered so that keepalive timeouts can be tested
+fn answer_requests(
+    config: &MockNodeConfig,
+    reader: &mut MessageReader<&TcpStream>,
+    writer: &mut MessageWriter<&TcpStream>,
+    sent_wtxid_relay: bool,
+    local_addr: SocketAddr,
+) -> Result<(), HandshakeError> {
+    let mut addr_v2 = false;
+    let mut wtxid_relay = false;
+    loop {
+        let message = match reader.read_message() {
+            Ok(message) => message,
+            // The remote node is gone
+            Err(HandshakeError::Io(_)) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let reply = match NetworkMessage::from_bitcoin_message(&message)? {
+            NetworkMessage::SendAddrV2 => {
+                addr_v2 = true;
+                continue;
+            }
+            NetworkMessage::WtxidRelay => {
+                wtxid_relay = sent_wtxid_relay;
+                continue;
+            }
+            NetworkMessage::Inv(inv) => {
+                // Transactions announced by txid are asked for with their witness data
+                let wanted: Vec<Inventory> = inv
+                    .inventory
+                    .iter()
+                    .filter_map(|inventory| match inventory {
+                        Inventory::Tx(hash) | Inventory::WitnessTx(hash) => {
+                            Some(Inventory::WitnessTx(*hash))
+                        }
+                        Inventory::WTx(_) => Some(*inventory),
+                        _ => None,
+                    })
+                    .collect();
+                if wanted.is_empty() || config.tx_policy == MockTxPolicy::Ignore {
+                    continue;
+                }
+                NetworkMessage::GetData(InventoryMessage::new(wanted))
+            }
+            NetworkMessage::Tx(transaction) => match config.tx_policy {
+                MockTxPolicy::Ignore | MockTxPolicy::Accept => continue,
+                MockTxPolicy::Reject => NetworkMessage::Reject(RejectMessage {
+                    message: Command::Tx.as_str().to_string(),
+                    code: REJECT_INVALID,
+                    reason: "bad-txns-inputs-missingorspent".to_string(),
+                    hash: Some(transaction.txid()),
+                }),
+                MockTxPolicy::Relay => {
+                    let inventory = if wtxid_relay {
+                        Inventory::WTx(transaction.wtxid())
+                    } else {
+                        Inventory::Tx(transaction.txid())
+                    };
+                    NetworkMessage::Inv(InventoryMessage::new(vec![inventory]))
+                }
+            },
+            NetworkMessage::GetData(getdata) => {
+                serve_getdata(config, writer, &getdata)?;
+                continue;
+            }
+            NetworkMessage::GetHeaders(getheaders) => NetworkMessage::Headers(HeadersMessage {
+                headers: headers_after(config, &getheaders),
+            }),
+            NetworkMessage::GetAddr => {
+                if config.announce_self {
+                    let own = AddrV2Entry {
+                        time: 1_700_000_000,
+                        services: config.services,
+                        addr: local_addr.ip().into(),
+                        port: local_addr.port(),
+                    };
+                    let announcement = addr_message(&[own], addr_v2);
+                    writer.write_message(&announcement.to_bitcoin_message(config.network)?)?;
+                }
+                addr_message(&config.addresses, addr_v2)
+            }
+            _ => continue,
+        };
+        writer.write_message(&reply.to_bitcoin_message(config.network)?)?;
+    }
+}
+
+/// Addresses in an addrv2 message, or in an addr message where only IP addresses fit
+fn addr_message(addresses: &[AddrV2Entry], addr_v2: bool) -> NetworkMessage {
+    if addr_v2 {
+        return NetworkMessage::AddrV2(AddrV2Message {
+            addresses: addresses.to_vec(),
+        });
+    }
+    NetworkMessage::Addr(AddrMessage {
+        addresses: addresses
+            .iter()
+            .filter_map(|entry| {
+                Some(TimestampedNetAddr {
+                    time: entry.time,
+                    addr: NetAddr::new(entry.services, entry.socket_addr()?),
+                })
+            })
+            .collect(),
+    })
+}
+
+/// Headers following the first locator hash the mock node knows, as many as fit in
+/// a headers message and up to the stop hash
+fn headers_after(config: &MockNodeConfig, getheaders: &GetHeadersMessage) -> Vec<BlockHeader> {
+    let genesis_hash = config.network.genesis_hash();
+    let start = getheaders
+        .locator
+        .iter()
+        .find_map(|hash| {
+            if *hash == genesis_hash {
+                return Some(0);
+            }
+            let position = config
+                .headers
+                .iter()
+                .position(|header| header.block_hash() == *hash)?;
+            Some(position + 1)
+        })
+        .unwrap_or(0);
+    let mut headers = Vec::new();
+    for header in config.headers.iter().skip(start).take(MAX_HEADERS_RESULTS) {
+        headers.push(*header);
+        if header.block_hash() == getheaders.stop_hash {
+            break;
+        }
+    }
+    headers
+}
+
+/// Send the requested blocks the mock node has, stripped of witness data unless asked
+/// for, then a single notfound listing everything else, like Bitcoin Core
+fn serve_getdata(
+    config: &MockNodeConfig,
+    writer: &mut MessageWriter<&TcpStream>,
+    getdata: &InventoryMessage,
+) -> Result<(), HandshakeError> {
+    let mut notfound = Vec::new();
+    for inventory in &getdata.inventory {
+        let block = match inventory {
+            Inventory::Block(hash) | Inventory::WitnessBlock(hash) => config
+                .blocks
+                .iter()
+                .find(|block| block.block_hash() == *hash),
+            _ => None,
+        };
+        let Some(block) = block else {
+            notfound.push(*inventory);
+            continue;
+        };
+        let mut block = block.clone();
+        if let Inventory::Block(_) = inventory {
+            for transaction in &mut block.transactions {
+                *transaction = transaction.without_witness();
+            }
+        }
+        writer.write_message(&NetworkMessage::Block(block).to_bitcoin_message(config.network)?)?;
+    }
+    if !notfound.is_empty() {
+        let notfound = NetworkMessage::NotFound(InventoryMessage::new(notfound));
+        writer.write_message(&notfound.to_bitcoin_message(config.network)?)?;
+    }
+    Ok(())
+}
+
+/// Write bytes that are not a valid message and keep the connection open
+fn write_raw(mut stream: &TcpStream, bytes: &[u8]) -> Result<(), HandshakeError> {
+    stream.write_all(bytes)?;
+    stream.flush()?;
+    drain(stream)
+}
+
+/// Ignore everything received until the remote node closes the connection
+fn drain(mut stream: &TcpStream) -> Result<(), HandshakeError> {
+    io::copy(&mut stream, &mut io::sink())?;
+    Ok(())
+}
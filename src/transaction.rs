@@ -0,0 +1,324 @@
+use super::codec::{read_var_bytes, write_var_bytes, CompactSize};
+use super::error::HandshakeError;
+use super::messages::{Serializable, MAX_PAYLOAD_SIZE};
+use super::utils::sha256d;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Cursor, Read, Write};
+
+// Flag announcing witness data after the inputs and outputs, see BIP144
+const WITNESS_FLAG: u8 = 1;
+
+/// Output of a previous transaction spent by an input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutPoint {
+    // Id of the transaction holding the output, in internal byte order
+    pub txid: [u8; 32],
+    // Index of the output in that transaction
+    pub vout: u32,
+}
+
+impl OutPoint {
+    /// Placeholder spent by the only input of a coinbase transaction
+    pub const NULL: OutPoint = OutPoint {
+        txid: [0u8; 32],
+        vout: u32::MAX,
+    };
+}
+
+/// Input of a transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxIn {
+    // Output being spent
+    pub previous_output: OutPoint,
+    // Script satisfying the conditions of the spent output
+    pub script_sig: Vec<u8>,
+    // Relative lock time and replaceability signal, see BIP68 and BIP125
+    pub sequence: u32,
+    // Witness stack of segwit spends, empty otherwise
+    pub witness: Vec<Vec<u8>>,
+}
+
+/// Output of a transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxOut {
+    // Amount in satoshis
+    pub value: i64,
+    // Conditions to spend the output
+    pub script_pubkey: Vec<u8>,
+}
+
+/// Bitcoin transaction, with the witness data of its inputs
+/// More information https://en.bitcoin.it/wiki/Protocol_documentation#tx
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transaction {
+    // Transaction version, 2 enabling relative lock times
+    pub version: i32,
+    pub inputs: Vec<TxIn>,
+    pub outputs: Vec<TxOut>,
+    // Block height or time before which the transaction cannot be mined
+    pub lock_time: u32,
+}
+
+impl Transaction {
+    /// Whether any input carries witness data
+    pub fn has_witness(&self) -> bool {
+        self.inputs.iter().any(|input| !input.witness.is_empty())
+    }
+
+    /// Whether this is the first transaction of a block, creating its reward
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.len() == 1 && self.inputs[0].previous_output == OutPoint::NULL
+    }
+
+    /// Copy of the transaction stripped of its witness data, as sent to nodes not asking
+    /// for it
+    pub fn without_witness(&self) -> Self {
+        let mut transaction = self.clone();
+        for input in &mut transaction.inputs {
+            input.witness.clear();
+        }
+        transaction
+    }
+
+    /// Write the transaction, with the segwit marker, flag and witnesses if asked and any
+    pub fn encode<W: Write>(
+        &self,
+        writer: &mut W,
+        with_witness: bool,
+    ) -> Result<(), HandshakeError> {
+        let with_witness = with_witness && self.has_witness();
+        writer.write_i32::<LittleEndian>(self.version)?;
+        if with_witness {
+            // An empty input list followed by the flag
+            writer.write_all(&[0x00, WITNESS_FLAG])?;
+        }
+        CompactSize(self.inputs.len() as u64).encode(writer)?;
+        for input in &self.inputs {
+            writer.write_all(&input.previous_output.txid)?;
+            writer.write_u32::<LittleEndian>(input.previous_output.vout)?;
+            write_var_bytes(writer, &input.script_sig)?;
+            writer.write_u32::<LittleEndian>(input.sequence)?;
+        }
+        CompactSize(self.outputs.len() as u64).encode(writer)?;
+        for output in &self.outputs {
+            writer.write_i64::<LittleEndian>(output.value)?;
+            write_var_bytes(writer, &output.script_pubkey)?;
+        }
+        if with_witness {
+            for input in &self.inputs {
+                CompactSize(input.witness.len() as u64).encode(writer)?;
+                for item in &input.witness {
+                    write_var_bytes(writer, item)?;
+                }
+            }
+        }
+        writer.write_u32::<LittleEndian>(self.lock_time)?;
+        Ok(())
+    }
+
+    /// Read a transaction with or without witness data, like Bitcoin Core does
+    pub fn decode<R: Read>(reader: &mut R) -> Result<Self, HandshakeError> {
+        let version = reader.read_i32::<LittleEndian>()?;
+        let mut inputs = decode_inputs(reader)?;
+        let mut flags = 0;
+        let outputs = if inputs.is_empty() {
+            // Either the segwit marker or a transaction without inputs
+            flags = reader.read_u8()?;
+            if flags == 0 {
+                Vec::new()
+            } else {
+                inputs = decode_inputs(reader)?;
+                decode_outputs(reader)?
+            }
+        } else {
+            decode_outputs(reader)?
+        };
+        if flags & WITNESS_FLAG != 0 {
+            flags ^= WITNESS_FLAG;
+            for input in &mut inputs {
+                let count = CompactSize::decode_length(reader, MAX_PAYLOAD_SIZE as u64)?;
+                for _ in 0..count {
+                    input
+                        .witness
+                        .push(read_var_bytes(reader, MAX_PAYLOAD_SIZE as usize)?);
+                }
+            }
+            let transaction_has_witness = inputs.iter().any(|input| !input.witness.is_empty());
+            if !transaction_has_witness {
+                return Err(HandshakeError::InvalidMessage("Superfluous witness record"));
+            }
+        }
+        if flags != 0 {
+            return Err(HandshakeError::InvalidMessage(
+                "Unknown transaction optional data",
+            ));
+        }
+        let lock_time = reader.read_u32::<LittleEndian>()?;
+        Ok(Self {
+            version,
+            inputs,
+            outputs,
+            lock_time,
+        })
+    }
+
+    /// Id of the transaction, the hash of its serialization without witness data
+    pub fn txid(&self) -> [u8; 32] {
+        self.hash(false)
+    }
+
+    /// Id covering witness data too, equal to the txid without any, see BIP141
+    pub fn wtxid(&self) -> [u8; 32] {
+        self.hash(true)
+    }
+
+    fn hash(&self, with_witness: bool) -> [u8; 32] {
+        let mut bytes = Vec::new();
+        self.encode(&mut bytes, with_witness)
+            .expect("Writing to a vector cannot fail");
+        sha256d(&bytes)
+    }
+}
+
+impl Serializable for Transaction {
+    fn serialize(&self) -> Result<Vec<u8>, HandshakeError> {
+        let mut message = Vec::new();
+        self.encode(&mut message, true)?;
+        Ok(message)
+    }
+
+    fn deserialize(msg: Vec<u8>) -> Result<Box<Self>, HandshakeError> {
+        let mut cursor = Cursor::new(msg);
+        Ok(Box::new(Self::decode(&mut cursor)?))
+    }
+}
+
+fn decode_inputs<R: Read>(reader: &mut R) -> Result<Vec<TxIn>, HandshakeError> {
+    let count = CompactSize::decode_length(reader, MAX_PAYLOAD_SIZE as u64)?;
+    // Counts are only bounded by the payload size, memory grows with the data read
+    let mut inputs = Vec::new();
+    for _ in 0..count {
+        let mut txid = [0u8; 32];
+        reader.read_exact(&mut txid)?;
+        let vout = reader.read_u32::<LittleEndian>()?;
+        inputs.push(TxIn {
+            previous_output: OutPoint { txid, vout },
+            script_sig: read_var_bytes(reader, MAX_PAYLOAD_SIZE as usize)?,
+            sequence: reader.read_u32::<LittleEndian>()?,
+            witness: Vec::new(),
+        });
+    }
+    Ok(inputs)
+}
+
+fn decode_outputs<R: Read>(reader: &mut R) -> Result<Vec<TxOut>, HandshakeError> {
+    let count = CompactSize::decode_length(reader, MAX_PAYLOAD_SIZE as u64)?;
+    let mut outputs = Vec::new();
+    for _ in 0..count {
+        outputs.push(TxOut {
+            value: reader.read_i64::<LittleEndian>()?,
+            script_pubkey: read_var_bytes(reader, MAX_PAYLOAD_SIZE as usize)?,
+        });
+    }
+    Ok(outputs)
+}
+
+// Coinbase transaction of the mainnet genesis block
+#[cfg(test)]
+pub(crate) const GENESIS_COINBASE: &str = concat!(
+    "01000000010000000000000000000000000000000000000000000000000000000000000000",
+    "ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368",
+    "616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f75742066",
+    "6f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a671",
+    "30b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c38",
+    "4df7ba0b8d578a4c702b6bf11d5fac00000000",
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headers::hash_to_hex;
+
+    fn segwit_transaction() -> Transaction {
+        Transaction {
+            version: 2,
+            inputs: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: [7u8; 32],
+                    vout: 1,
+                },
+                script_sig: Vec::new(),
+                sequence: 0xffff_fffd,
+                witness: vec![vec![0x30; 71], vec![0x02; 33]],
+            }],
+            outputs: vec![TxOut {
+                value: 50_000,
+                script_pubkey: [vec![0x00, 0x14], vec![0xab; 20]].concat(),
+            }],
+            lock_time: 0,
+        }
+    }
+
+    #[test]
+    fn test_transaction_txid_ok() {
+        let bytes = hex::decode(GENESIS_COINBASE).expect("Invalid hex");
+        let transaction = *Transaction::deserialize(bytes.clone()).expect("Failed to decode");
+        assert!(transaction.is_coinbase());
+        assert_eq!(transaction.outputs[0].value, 5_000_000_000);
+        assert_eq!(
+            hash_to_hex(&transaction.txid()),
+            "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b"
+        );
+        // Without witness data both ids are the same
+        assert_eq!(transaction.wtxid(), transaction.txid());
+        assert_eq!(transaction.serialize().expect("Failed to serialize"), bytes);
+    }
+
+    #[test]
+    fn test_transaction_witness_round_trip_ok() {
+        let transaction = segwit_transaction();
+        let bytes = transaction.serialize().expect("Failed to serialize");
+        // Marker and flag follow the version
+        assert_eq!(bytes[4..6], [0x00, 0x01]);
+        assert_eq!(
+            *Transaction::deserialize(bytes.clone()).expect("Failed to decode"),
+            transaction
+        );
+        assert_eq!(transaction.wtxid(), sha256d(&bytes));
+
+        let mut stripped = Vec::new();
+        transaction
+            .encode(&mut stripped, false)
+            .expect("Failed to encode");
+        assert_eq!(stripped.len(), bytes.len() - 2 - 1 - 1 - 71 - 1 - 33);
+        assert_eq!(transaction.txid(), sha256d(&stripped));
+        assert_ne!(transaction.txid(), transaction.wtxid());
+    }
+
+    #[test]
+    fn test_transaction_superfluous_witness_error() {
+        let mut transaction = segwit_transaction();
+        let mut bytes = transaction.serialize().expect("Failed to serialize");
+        // Unknown flag
+        bytes[5] = 0x03;
+        assert!(matches!(
+            Transaction::deserialize(bytes),
+            Err(HandshakeError::InvalidMessage(_))
+        ));
+
+        // Segwit serialization with empty witnesses only
+        transaction.inputs[0].witness.clear();
+        let mut bytes = Vec::new();
+        transaction
+            .encode(&mut bytes, false)
+            .expect("Failed to encode");
+        let lock_time = bytes.split_off(bytes.len() - 4);
+        bytes.splice(4..4, [0x00, 0x01]);
+        bytes.push(0x00);
+        bytes.extend(lock_time);
+        assert!(matches!(
+            Transaction::deserialize(bytes),
+            Err(HandshakeError::InvalidMessage("Superfluous witness record"))
+        ));
+    }
+}
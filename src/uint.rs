@@ -0,0 +1,263 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, Div, Not, Shl, Shr, Sub};
+
+/// Unsigned 256-bit integer used for proof-of-work targets and chain work
+/// Arithmetic wraps around like Bitcoin Core's arith_uint256
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct U256(
+    // Limbs from the least significant one
+    [u64; 4],
+);
+
+impl U256 {
+    pub const ZERO: U256 = U256([0; 4]);
+    pub const ONE: U256 = U256([1, 0, 0, 0]);
+    pub const MAX: U256 = U256([u64::MAX; 4]);
+
+    pub fn from_u64(value: u64) -> Self {
+        U256([value, 0, 0, 0])
+    }
+
+    /// Value of 32 little-endian bytes, the internal byte order of hashes
+    pub fn from_le_bytes(bytes: &[u8; 32]) -> Self {
+        let mut limbs = [0u64; 4];
+        for (limb, chunk) in limbs.iter_mut().zip(bytes.chunks_exact(8)) {
+            *limb = u64::from_le_bytes(chunk.try_into().expect("Chunks are 8 bytes long"));
+        }
+        U256(limbs)
+    }
+
+    pub fn to_le_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for (chunk, limb) in bytes.chunks_exact_mut(8).zip(self.0) {
+            chunk.copy_from_slice(&limb.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Target encoded in the compact nBits form of block headers
+    /// Negative and overflowing encodings are refused, as no valid block uses them
+    pub fn from_compact(bits: u32) -> Option<Self> {
+        let size = bits >> 24;
+        let word = bits & 0x007f_ffff;
+        if word != 0
+            && (bits & 0x0080_0000 != 0
+                || size > 34
+                || (word > 0xff && size > 33)
+                || (word > 0xffff && size > 32))
+        {
+            return None;
+        }
+        Some(if size <= 3 {
+            U256::from_u64((word >> (8 * (3 - size))) as u64)
+        } else {
+            U256::from_u64(word as u64) << (8 * (size - 3))
+        })
+    }
+
+    /// Compact nBits form of the value, losing all but its 3 most significant bytes
+    pub fn to_compact(&self) -> u32 {
+        let mut size = self.bits().div_ceil(8);
+        let mut compact = if size <= 3 {
+            (self.0[0] << (8 * (3 - size))) as u32
+        } else {
+            (*self >> (8 * (size - 3))).0[0] as u32
+        };
+        // The sign bit is not part of the mantissa
+        if compact & 0x0080_0000 != 0 {
+            compact >>= 8;
+            size += 1;
+        }
+        compact | (size << 24)
+    }
+
+    /// Number of significant bits
+    pub fn bits(&self) -> u32 {
+        self.0
+            .iter()
+            .rposition(|&limb| limb != 0)
+            .map_or(0, |i| 64 * i as u32 + 64 - self.0[i].leading_zeros())
+    }
+
+    fn bit(&self, index: u32) -> bool {
+        self.0[index as usize / 64] >> (index % 64) & 1 == 1
+    }
+
+    /// Product with a 64-bit value, truncated to 256 bits
+    pub fn mul_u64(&self, rhs: u64) -> Self {
+        let mut limbs = [0u64; 4];
+        let mut carry = 0u128;
+        for (limb, &lhs) in limbs.iter_mut().zip(&self.0) {
+            let product = lhs as u128 * rhs as u128 + carry;
+            *limb = product as u64;
+            carry = product >> 64;
+        }
+        U256(limbs)
+    }
+}
+
+impl Ord for U256 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+}
+
+impl PartialOrd for U256 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Add for U256 {
+    type Output = U256;
+
+    fn add(self, rhs: U256) -> U256 {
+        let mut limbs = [0u64; 4];
+        let mut carry = false;
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            let (sum, overflow) = self.0[i].overflowing_add(rhs.0[i]);
+            let (sum, carried) = sum.overflowing_add(carry as u64);
+            *limb = sum;
+            carry = overflow || carried;
+        }
+        U256(limbs)
+    }
+}
+
+impl Sub for U256 {
+    type Output = U256;
+
+    fn sub(self, rhs: U256) -> U256 {
+        self + (!rhs + U256::ONE)
+    }
+}
+
+impl Not for U256 {
+    type Output = U256;
+
+    fn not(self) -> U256 {
+        U256(self.0.map(|limb| !limb))
+    }
+}
+
+impl Shl<u32> for U256 {
+    type Output = U256;
+
+    fn shl(self, shift: u32) -> U256 {
+        let mut limbs = [0u64; 4];
+        let (offset, shift) = (shift as usize / 64, shift % 64);
+        for (i, limb) in limbs.iter_mut().enumerate().skip(offset) {
+            *limb = self.0[i - offset] << shift;
+            if shift > 0 && i > offset {
+                *limb |= self.0[i - offset - 1] >> (64 - shift);
+            }
+        }
+        U256(limbs)
+    }
+}
+
+impl Shr<u32> for U256 {
+    type Output = U256;
+
+    fn shr(self, shift: u32) -> U256 {
+        let mut limbs = [0u64; 4];
+        let (offset, shift) = (shift as usize / 64, shift % 64);
+        for (i, limb) in limbs
+            .iter_mut()
+            .take(4usize.saturating_sub(offset))
+            .enumerate()
+        {
+            *limb = self.0[i + offset] >> shift;
+            if shift > 0 && i + offset < 3 {
+                *limb |= self.0[i + offset + 1] << (64 - shift);
+            }
+        }
+        U256(limbs)
+    }
+}
+
+impl Div for U256 {
+    type Output = U256;
+
+    /// Long division, panicking on a zero divisor like primitive integers
+    fn div(self, rhs: U256) -> U256 {
+        assert!(rhs != U256::ZERO, "Division by zero");
+        let mut quotient = U256::ZERO;
+        let mut remainder = U256::ZERO;
+        for i in (0..self.bits()).rev() {
+            // The bit shifted out still counts in the comparison
+            let carry = remainder.bit(255);
+            remainder = remainder << 1;
+            remainder.0[0] |= self.bit(i) as u64;
+            if carry || remainder >= rhs {
+                remainder = remainder - rhs;
+                quotient.0[i as usize / 64] |= 1 << (i % 64);
+            }
+        }
+        quotient
+    }
+}
+
+impl fmt::Display for U256 {
+    /// Hexadecimal form without leading zeros
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut bytes = self.to_le_bytes();
+        bytes.reverse();
+        let hex = hex::encode(bytes);
+        let digits = hex.trim_start_matches('0');
+        f.write_str(if digits.is_empty() { "0" } else { digits })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_u256_compact_round_trip_ok() {
+        // Values of Bitcoin Core's arith_uint256 tests
+        for (bits, value, compact) in [
+            (0x0000_0000, "0", 0x0000_0000),
+            (0x0112_3456, "12", 0x0112_0000),
+            (0x0200_8000, "80", 0x0200_8000),
+            (0x0512_3456, "1234560000", 0x0512_3456),
+            (0x0400_0000, "0", 0x0000_0000),
+            (
+                0x2012_3456,
+                &format!("123456{}", "0".repeat(58)),
+                0x2012_3456,
+            ),
+            (0x1d00_ffff, &format!("ffff{}", "0".repeat(52)), 0x1d00_ffff),
+        ] {
+            let target = U256::from_compact(bits).expect("Valid compact target");
+            assert_eq!(target.to_string(), value);
+            assert_eq!(target.to_compact(), compact);
+        }
+    }
+
+    #[test]
+    fn test_u256_compact_invalid_error() {
+        // Negative
+        assert_eq!(U256::from_compact(0x04923456), None);
+        // Overflowing
+        assert_eq!(U256::from_compact(0xff123456), None);
+        assert_eq!(U256::from_compact(0x2112_3456), None);
+    }
+
+    #[test]
+    fn test_u256_arithmetic_ok() {
+        let target = U256::from_compact(0x1d00_ffff).expect("Valid compact target");
+        // Work of a block at the lowest mainnet difficulty
+        let work = !target / (target + U256::ONE) + U256::ONE;
+        assert_eq!(work, U256::from_u64(0x1_0001_0001));
+
+        let big = U256::MAX >> 4;
+        assert_eq!((big.mul_u64(16) >> 4), big);
+        assert_eq!(big / U256::from_u64(1 << 20), big >> 20);
+        assert_eq!(U256::MAX / U256::MAX, U256::ONE);
+        assert_eq!((U256::ONE << 255) - U256::ONE, U256::MAX >> 1);
+        assert!(U256::ONE << 64 > U256::from_u64(u64::MAX));
+        assert_eq!(U256::from_le_bytes(&big.to_le_bytes()), big);
+    }
+}
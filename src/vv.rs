@@ -1,38 +1,145 @@
-use super::messages::{Serializable, CHECKSUM_SIZE, COMMAND_SIZE};
-use super::network::{add_serialize_addr, read_deserialized_add, BitcoinNetwork};
-use super::utils::{calculate_checksum, calculate_timestamp, generate_nonce};
+use super::clock::{Clock, NonceSource, OsNonceSource, SystemClock};
+use super::codec::{VarStr, MAX_USER_AGENT_LENGTH};
+use super::error::HandshakeError;
+use super::messages::{Serializable, COMMAND_SIZE};
+use super::network::NetAddr;
+use super::services::ServiceFlags;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use std::io::{Cursor, Error, ErrorKind, Read};
+use std::fmt;
+use std::io::{Cursor, ErrorKind};
 use std::net::SocketAddr;
 
-// Constants for the Bitcoin protocol
-const PROTOCOL_VERSION: i32 = 70001i32;
-// Service contanst that corresponds to a full node that can serve the full blockchain
-const NODE_NETWORK_SERVICE: u64 = 1;
+// Protocol version advertised by default, same as Bitcoin Core
+pub const PROTOCOL_VERSION: i32 = 70016;
+// First version with a nonce in ping and pong messages, see BIP31
+pub const BIP0031_VERSION: i32 = 60000;
+// First version with the relay flag in version messages, see BIP37
+pub const RELAY_VERSION: i32 = 70001;
+// First version understanding sendheaders, see BIP130
+pub const SENDHEADERS_VERSION: i32 = 70012;
+// First version understanding feefilter, see BIP133
+pub const FEEFILTER_VERSION: i32 = 70013;
+// First version understanding compact blocks, see BIP152
+pub const SHORT_IDS_BLOCKS_VERSION: i32 = 70014;
+// First version understanding wtxidrelay, see BIP339
+pub const WTXID_RELAY_VERSION: i32 = 70016;
+// First version sendaddrv2 is sent to, see BIP155
+// Bitcoin Core does not send it to older nodes as some reject unknown messages
+pub const ADDRV2_VERSION: i32 = 70016;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+/// Command naming the type of a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Command {
     // Message used when two nodes first connect
     Version,
     // Response message sent after a version message
     Verack,
+    // Liveness check carrying a nonce, see BIP31
+    Ping,
+    // Answer to a ping echoing its nonce
+    Pong,
+    // Request for the addresses known by the remote node
+    GetAddr,
+    // Addresses of other nodes
+    Addr,
+    // Addresses of other nodes on any network, see BIP155
+    AddrV2,
+    // Preference for addrv2 over addr, sent before verack
+    SendAddrV2,
+    // Announcement of transactions by wtxid, sent before verack, see BIP339
+    WtxidRelay,
+    // Offer of transaction reconciliation, sent before verack, see BIP330
+    SendTxRcncl,
+    // Preference for blocks announced with headers, see BIP130
+    SendHeaders,
+    // Preference for compact blocks, see BIP152
+    SendCmpct,
+    // Lowest fee rate of the transactions to announce, see BIP133
+    FeeFilter,
+    // Request for the headers following a block locator
+    GetHeaders,
+    // Block headers answering getheaders
+    Headers,
+    // Announcement of transactions or blocks
+    Inv,
+    // Request for transactions or blocks
+    GetData,
+    // Transactions or blocks requested but not available
+    NotFound,
+    // Full block answering getdata
+    Block,
+    // Transaction answering getdata
+    Tx,
+    // Refusal of a message, dropped from Bitcoin Core 0.20
+    Reject,
+    // Any other command, kept as its null-padded bytes
+    Other([u8; COMMAND_SIZE]),
 }
 
 impl Command {
+    // Commands with their own message type
+    const KNOWN: [Command; 21] = [
+        Command::Version,
+        Command::Verack,
+        Command::Ping,
+        Command::Pong,
+        Command::GetAddr,
+        Command::Addr,
+        Command::AddrV2,
+        Command::SendAddrV2,
+        Command::WtxidRelay,
+        Command::SendTxRcncl,
+        Command::SendHeaders,
+        Command::SendCmpct,
+        Command::FeeFilter,
+        Command::GetHeaders,
+        Command::Headers,
+        Command::Inv,
+        Command::GetData,
+        Command::NotFound,
+        Command::Block,
+        Command::Tx,
+        Command::Reject,
+    ];
+
     pub fn as_str(&self) -> &str {
         match self {
             Command::Version => "version",
             Command::Verack => "verack",
+            Command::Ping => "ping",
+            Command::Pong => "pong",
+            Command::GetAddr => "getaddr",
+            Command::Addr => "addr",
+            Command::AddrV2 => "addrv2",
+            Command::SendAddrV2 => "sendaddrv2",
+            Command::WtxidRelay => "wtxidrelay",
+            Command::SendTxRcncl => "sendtxrcncl",
+            Command::SendHeaders => "sendheaders",
+            Command::SendCmpct => "sendcmpct",
+            Command::FeeFilter => "feefilter",
+            Command::GetHeaders => "getheaders",
+            Command::Headers => "headers",
+            Command::Inv => "inv",
+            Command::GetData => "getdata",
+            Command::NotFound => "notfound",
+            Command::Block => "block",
+            Command::Tx => "tx",
+            Command::Reject => "reject",
+            Command::Other(bytes) => {
+                let end = bytes.iter().position(|&b| b == 0).unwrap_or(COMMAND_SIZE);
+                // Other commands are checked to be ASCII when built
+                std::str::from_utf8(&bytes[..end]).unwrap_or_default()
+            }
         }
     }
     // Return specific fixed-size bytes array for
-    pub fn as_fixed_length_vec(&self) -> Result<[u8; COMMAND_SIZE], Error> {
+    pub fn as_fixed_length_vec(&self) -> Result<[u8; COMMAND_SIZE], HandshakeError> {
+        if let Command::Other(bytes) = self {
+            return Ok(*bytes);
+        }
         let bytes = self.as_str().as_bytes();
         if bytes.len() > COMMAND_SIZE {
-            return Err(Error::new(
-                ErrorKind::InvalidInput,
-                "Command string is too long",
-            ));
+            return Err(HandshakeError::InvalidMessage("Command string is too long"));
         }
         let mut command_fixed: [u8; COMMAND_SIZE] = [0; COMMAND_SIZE];
         for (i, &byte) in bytes.iter().enumerate() {
@@ -41,27 +148,90 @@ impl Command {
 
         Ok(command_fixed)
     }
+    /// Command of a null-padded command field
+    /// Like Bitcoin Core, only printable ASCII followed by null bytes is accepted
+    pub fn from_fixed_length(bytes: &[u8; COMMAND_SIZE]) -> Result<Command, HandshakeError> {
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(COMMAND_SIZE);
+        if !bytes[..end].iter().all(|b| b.is_ascii_graphic())
+            || bytes[end..].iter().any(|&b| b != 0)
+        {
+            return Err(HandshakeError::InvalidMessage("Invalid command"));
+        }
+        Ok(Self::KNOWN
+            .into_iter()
+            .find(|command| matches!(command.as_fixed_length_vec(), Ok(fixed) if &fixed == bytes))
+            .unwrap_or(Command::Other(*bytes)))
+    }
+    /// Command of a given name, known or not
+    pub fn from_name(name: &str) -> Result<Command, HandshakeError> {
+        if name.len() > COMMAND_SIZE {
+            return Err(HandshakeError::InvalidMessage("Command string is too long"));
+        }
+        let mut bytes = [0u8; COMMAND_SIZE];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        Self::from_fixed_length(&bytes)
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Features both nodes can use at a negotiated protocol version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolFeatures {
+    // Ping and pong carry a nonce
+    pub ping_nonce: bool,
+    // Version messages carry the relay flag
+    pub relay_flag: bool,
+    // New blocks may be announced with headers instead of inventories
+    pub send_headers: bool,
+    // A minimum fee rate may be set for relayed transactions
+    pub fee_filter: bool,
+    // Blocks may be relayed as compact blocks
+    pub compact_blocks: bool,
+    // Transactions may be announced by their wtxid
+    pub wtxid_relay: bool,
+    // Addresses may be relayed with addrv2
+    pub addr_v2: bool,
+}
+
+impl ProtocolFeatures {
+    /// Features available at a given protocol version
+    pub fn for_version(version: i32) -> Self {
+        Self {
+            ping_nonce: version >= BIP0031_VERSION,
+            relay_flag: version >= RELAY_VERSION,
+            send_headers: version >= SENDHEADERS_VERSION,
+            fee_filter: version >= FEEFILTER_VERSION,
+            compact_blocks: version >= SHORT_IDS_BLOCKS_VERSION,
+            wtxid_relay: version >= WTXID_RELAY_VERSION,
+            addr_v2: version >= ADDRV2_VERSION,
+        }
+    }
 }
 
 /// Version message used for a first connection between nodes
 /// Referred to Bitcoin documentation
 /// https://en.bitcoin.it/wiki/Protocol_documentation#version
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct VersionMessage {
     // Highest Bitcoin protocol version the node can use
     version: i32,
-    // Bitmask describing the services supported by the node
-    services: u64,
+    // Services supported by the node
+    services: ServiceFlags,
     // Timestamp recording the message creation
     timestamp: i64,
     // Node's address receiving the version message
-    receiver: SocketAddr,
+    receiver: NetAddr,
     // Node's address initializing the connection
-    sender: SocketAddr,
+    sender: NetAddr,
     // Random nonce to detection connection to self
     nonce: u64,
     // Software running on the node
-    _user_agent: String,
+    user_agent: String,
     // Highest block number
     start_height: i32,
     // Indicated if the node wants to receive relayed transactions
@@ -69,77 +239,151 @@ pub struct VersionMessage {
 }
 
 impl VersionMessage {
+    /// Version message stamped with the system time and an OS random nonce
     pub fn new(
+        version: i32,
         receiver: SocketAddr,
         sender: SocketAddr,
-        _user_agent: String,
+        services: ServiceFlags,
+        user_agent: String,
         start_height: i32,
         relay: bool,
     ) -> Self {
-        Self {
-            version: PROTOCOL_VERSION,
-            services: NODE_NETWORK_SERVICE,
-            timestamp: calculate_timestamp(),
+        Self::with_sources(
+            version,
             receiver,
             sender,
-            nonce: generate_nonce(),
-            _user_agent,
+            services,
+            user_agent,
+            start_height,
+            relay,
+            &SystemClock,
+            &OsNonceSource,
+        )
+    }
+
+    /// Version message whose timestamp and nonce are taken from the given sources
+    #[allow(clippy::too_many_arguments)]
+    pub fn with_sources(
+        version: i32,
+        receiver: SocketAddr,
+        sender: SocketAddr,
+        services: ServiceFlags,
+        user_agent: String,
+        start_height: i32,
+        relay: bool,
+        clock: &dyn Clock,
+        nonces: &dyn NonceSource,
+    ) -> Self {
+        Self {
+            version,
+            services,
+            timestamp: clock.now(),
+            receiver: NetAddr::new(services, receiver),
+            sender: NetAddr::new(services, sender),
+            nonce: nonces.next_nonce(),
+            user_agent,
             start_height,
             relay,
         }
     }
+
+    /// Highest protocol version the node can use
+    pub fn version(&self) -> i32 {
+        self.version
+    }
+
+    /// Services supported by the node
+    pub fn services(&self) -> ServiceFlags {
+        self.services
+    }
+
+    /// UNIX timestamp in seconds of the message creation
+    pub fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    /// Address of the node receiving the message (addr_recv)
+    pub fn receiver(&self) -> NetAddr {
+        self.receiver
+    }
+
+    /// Address of the node emitting the message (addr_from)
+    pub fn sender(&self) -> NetAddr {
+        self.sender
+    }
+
+    /// Random nonce used to detect connections to self
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    /// Software running on the node
+    pub fn user_agent(&self) -> &str {
+        &self.user_agent
+    }
+
+    /// Last block received by the node
+    pub fn start_height(&self) -> i32 {
+        self.start_height
+    }
+
+    /// Whether the node wants to receive relayed transactions
+    pub fn relay(&self) -> bool {
+        self.relay
+    }
 }
 
 impl Serializable for VersionMessage {
     // Serialize VersionMessage to bytes to be send to node
-    fn serialize(&self) -> Result<Vec<u8>, Error> {
+    fn serialize(&self) -> Result<Vec<u8>, HandshakeError> {
         let mut message = Vec::new();
 
         // Constructing the payload adding all version message elements
         message.extend(&self.version.to_le_bytes());
-        message.extend(&self.services.to_le_bytes());
+        message.extend(&self.services.bits().to_le_bytes());
         message.extend(&self.timestamp.to_le_bytes());
 
         // Serialize the receiver node's (remote peer's) network address
-        add_serialize_addr(&mut message, self.services, &self.receiver)?;
+        self.receiver.encode(&mut message)?;
 
         // Serialize this sender node's network address
-        add_serialize_addr(&mut message, self.services, &self.sender)?;
+        self.sender.encode(&mut message)?;
 
         // Add nonce to the payload
         message.write_u64::<LittleEndian>(self.nonce)?;
-        // Allocation for the user agent
-        message.extend(&[0]);
+        // User agent as a variable length string (BIP14)
+        VarStr(self.user_agent.clone()).encode(&mut message)?;
         message.write_i32::<LittleEndian>(self.start_height)?;
-        message.write_u8(self.relay as u8)?;
-        // Allocation for the relay
-        message.extend(&[0]);
+        // Older nodes do not know the relay flag
+        if self.version >= RELAY_VERSION {
+            message.write_u8(self.relay as u8)?;
+        }
         Ok(message)
     }
 
     // Deserialization used to verify the response content
-    fn deserialize(msg: Vec<u8>) -> Result<Box<Self>, Error> {
+    fn deserialize(msg: Vec<u8>) -> Result<Box<Self>, HandshakeError> {
         let mut cursor = Cursor::new(msg);
 
+        // Whether the version is recent enough is up to the handshake
         let version = cursor.read_i32::<LittleEndian>()?;
-        if version < PROTOCOL_VERSION {
-            return Err(Error::new(
-                ErrorKind::InvalidData,
-                "Unsupported protocol version",
-            ));
-        }
-
-        let services = cursor.read_u64::<LittleEndian>()?;
+        let services = ServiceFlags::from_bits_retain(cursor.read_u64::<LittleEndian>()?);
         let timestamp = cursor.read_i64::<LittleEndian>()?;
 
-        let receiver = read_deserialized_add(&mut cursor)?;
-        let sender = read_deserialized_add(&mut cursor)?;
-
-        let user_agent_byte = cursor.read_u8()?;
+        let receiver = NetAddr::decode(&mut cursor)?;
+        let sender = NetAddr::decode(&mut cursor)?;
 
         let nonce = cursor.read_u64::<LittleEndian>()?;
+        let user_agent = VarStr::decode(&mut cursor, MAX_USER_AGENT_LENGTH)?.0;
         let start_height = cursor.read_i32::<LittleEndian>()?;
-        let relay = cursor.read_u8()? > 0;
+
+        // Relay flag is optional (BIP37), nodes leaving it out relay transactions
+        let relay = match cursor.read_u8() {
+            Ok(relay) => relay > 0,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => true,
+            Err(e) => return Err(e.into()),
+        };
 
         Ok(Box::new(VersionMessage {
             version,
@@ -148,83 +392,134 @@ impl Serializable for VersionMessage {
             receiver,
             sender,
             nonce,
-            _user_agent: user_agent_byte.to_string(),
+            user_agent,
             start_height,
             relay,
         }))
     }
 }
 
-/// Verack Message sent in response to a Version message
-/// More information https://en.bitcoin.it/wiki/Protocol_documentation#verack
-#[derive(Debug, PartialEq)]
-pub struct VerackMessage {
-    // Magic Key for the Bitcoin network
-    magic: u32,
-    // ASCII string identifying the packet content - holds the command of the message
-    command: [u8; 12],
-    // Potential argument of verack message
-    length: u32,
-    // Potential argument of verack message
-    checksum: u32,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
 
-impl VerackMessage {
-    // Create Verack Message with Version message parameters
-    pub fn new(network: BitcoinNetwork, resp_command: Command) -> Self {
-        let command = resp_command
-            .as_fixed_length_vec()
-            .expect("Complete and convert command size");
-        Self {
-            magic: network.as_u32(),
-            command,
-            length: 0,
-            checksum: u32::from_ne_bytes(calculate_checksum([].to_vec())),
-        }
+    #[test]
+    fn test_version_message_round_trip_ok() {
+        let add_recv =
+            SocketAddr::from_str("127.0.0.1:18444").expect("Failed to convert to socket address");
+        let add_from =
+            SocketAddr::from_str("127.0.0.1:18445").expect("Failed to convert to socket address");
+        let services = ServiceFlags::NODE_NETWORK | ServiceFlags::NODE_WITNESS;
+        let version_message = VersionMessage::new(
+            PROTOCOL_VERSION,
+            add_recv,
+            add_from,
+            services,
+            "/node-handshake:0.1.0/".to_string(),
+            42,
+            true,
+        );
+
+        let payload = version_message
+            .serialize()
+            .expect("Failed to serialize version message");
+        // Fixed fields, both addresses, 22 bytes of user agent and its length
+        assert_eq!(payload.len(), 4 + 8 + 8 + 26 + 26 + 8 + 1 + 22 + 4 + 1);
+
+        let decoded =
+            VersionMessage::deserialize(payload).expect("Failed to deserialize version message");
+        assert_eq!(decoded.user_agent(), "/node-handshake:0.1.0/");
+        assert_eq!(decoded.nonce(), version_message.nonce());
+        assert_eq!(decoded.start_height(), 42);
+        assert_eq!(decoded.services(), services);
+        assert_eq!(decoded.receiver().services, services);
+        assert_eq!(decoded.receiver().socket_addr(), add_recv);
+        assert_eq!(decoded.sender().socket_addr(), add_from);
+        assert!(decoded.relay());
     }
-    /// Help to deserialize verack message answer
-    /// Veirfy magic number and Command that was originally sent
-    pub fn deserialize_and_verify(
-        msg: Vec<u8>,
-        network: BitcoinNetwork,
-        resp_command: Command,
-    ) -> Result<Self, Error> {
-        let mut cursor = Cursor::new(msg.clone());
-
-        // Check the magic number
-        let magic = cursor.read_u32::<LittleEndian>()?;
-        if magic != network.as_u32() {
-            return Err(Error::new(
-                ErrorKind::InvalidData,
-                "Invalid magic number in verack response",
-            ));
-        }
 
-        // Read and check the command that was sent
-        let mut command = [0u8; COMMAND_SIZE];
-        cursor.read_exact(&mut command)?;
-        let verack_command = resp_command
-            .as_fixed_length_vec()
-            .expect("Complete and convert command size");
-        if command != verack_command {
-            return Err(Error::new(
-                ErrorKind::InvalidData,
-                "Invalid command in verack response",
-            ));
-        };
+    #[test]
+    fn test_version_message_without_relay_ok() {
+        let add =
+            SocketAddr::from_str("127.0.0.1:18444").expect("Failed to convert to socket address");
+        let mut payload = VersionMessage::new(
+            PROTOCOL_VERSION,
+            add,
+            add,
+            ServiceFlags::NONE,
+            String::new(),
+            0,
+            false,
+        )
+        .serialize()
+        .expect("Failed to serialize version message");
+        payload.pop();
 
-        let length = cursor.read_u32::<LittleEndian>()?;
+        let decoded =
+            VersionMessage::deserialize(payload).expect("Failed to deserialize version message");
+        assert!(decoded.relay());
+    }
+
+    #[test]
+    fn test_version_message_old_version_ok() {
+        let add =
+            SocketAddr::from_str("127.0.0.1:18444").expect("Failed to convert to socket address");
+        let payload = VersionMessage::new(
+            60002,
+            add,
+            add,
+            ServiceFlags::NODE_NETWORK,
+            String::new(),
+            0,
+            false,
+        )
+        .serialize()
+        .expect("Failed to serialize version message");
+        // No relay flag before BIP37
+        assert_eq!(payload.len(), 4 + 8 + 8 + 26 + 26 + 8 + 1 + 4);
+
+        let decoded =
+            VersionMessage::deserialize(payload).expect("Failed to deserialize version message");
+        assert_eq!(decoded.version(), 60002);
+    }
+
+    #[test]
+    fn test_protocol_features_for_version_ok() {
+        let features = ProtocolFeatures::for_version(70001);
+        assert!(features.ping_nonce && features.relay_flag);
+        assert!(!features.send_headers && !features.wtxid_relay);
 
-        // Read the checksum
-        // Impossible to check on which payload it was used
-        let mut checksum = [0u8; CHECKSUM_SIZE];
-        cursor.read_exact(&mut checksum)?;
+        let features = ProtocolFeatures::for_version(70015);
+        assert!(features.send_headers && features.fee_filter && features.compact_blocks);
+        assert!(!features.wtxid_relay && !features.addr_v2);
+
+        let features = ProtocolFeatures::for_version(PROTOCOL_VERSION);
+        assert!(features.wtxid_relay && features.addr_v2);
+        assert!(!ProtocolFeatures::for_version(31800).ping_nonce);
+    }
+
+    #[test]
+    fn test_command_open_set_ok() {
+        assert_eq!(Command::from_name("version").ok(), Some(Command::Version));
+        assert_eq!(Command::from_name("ping").ok(), Some(Command::Ping));
+        let mempool = Command::from_name("mempool").expect("Valid command name");
+        assert!(matches!(mempool, Command::Other(_)));
+        assert_eq!(mempool.to_string(), "mempool");
+        assert_eq!(
+            mempool.as_fixed_length_vec().ok(),
+            Some(*b"mempool\0\0\0\0\0")
+        );
+        assert_eq!(
+            Command::from_fixed_length(b"wtxidrelay\0\0").ok(),
+            Command::from_name("wtxidrelay").ok()
+        );
+    }
 
-        Ok(VerackMessage {
-            magic,
-            command,
-            length,
-            checksum: u32::from_ne_bytes(checksum),
-        })
+    #[test]
+    fn test_command_invalid_bytes_error() {
+        assert!(Command::from_fixed_length(b"ping\0\0x\0\0\0\0\0").is_err());
+        assert!(Command::from_fixed_length(b"pi\nng\0\0\0\0\0\0\0").is_err());
+        assert!(Command::from_name("sendaddrv2long").is_err());
     }
 }
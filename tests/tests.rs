@@ -1,20 +1,55 @@
 #[cfg(test)]
 mod tests {
-    use node_handshake::handshake::perform_handshake;
+    use node_handshake::addr::AddrV2Entry;
+    use node_handshake::block::Block;
+    use node_handshake::broadcast::{
+        broadcast_tx, BroadcastReport, BroadcastStatus, REJECT_INVALID,
+    };
+    use node_handshake::chain::HeaderChain;
+    use node_handshake::detection::{detect_network, DetectionConfig, DetectionMethod};
+    use node_handshake::error::HandshakeError;
+    use node_handshake::handshake::{
+        perform_handshake, perform_handshake_with_config, HandshakeConfig, HandshakeState,
+        HandshakeTimeouts,
+    };
+    use node_handshake::keepalive::{Keepalive, KeepaliveConfig};
+    use node_handshake::listener::HandshakeListener;
+    use node_handshake::messages::{NetworkMessage, Serializable, HEADER_SIZE};
     use node_handshake::network::BitcoinNetwork;
-    use std::io::ErrorKind;
-    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use node_handshake::params::NetworkParams;
+    use node_handshake::services::ServiceFlags;
+    use node_handshake::testing::{
+        regtest_block, regtest_headers, MockBehaviour, MockNode, MockNodeConfig, MockTxPolicy,
+    };
+    use node_handshake::transaction::{OutPoint, Transaction, TxIn, TxOut};
+    use std::io::{ErrorKind, Write};
+    use std::net::SocketAddr;
     use std::str::FromStr;
+    use std::time::{Duration, Instant};
 
-    // These tests are supposed to be run in parallel with a bitcoin node
+    // These tests run against an in-process mock node listening on localhost
     // Depending on the network chosen
     // Here Regtest Network is picked
 
+    // Perform a handshake with the mock node with short time limits
+    fn handshake_with(node: &MockNode) -> Result<(), HandshakeError> {
+        let config = HandshakeConfig {
+            timeouts: HandshakeTimeouts {
+                version: Duration::from_millis(500),
+                verack: Duration::from_millis(500),
+                ..HandshakeTimeouts::default()
+            },
+            ..HandshakeConfig::new(BitcoinNetwork::Regtest)
+        };
+        perform_handshake_with_config(&config, node.addr(), node.addr()).map(|_| ())
+    }
+
     #[test]
     // Basically same test than in main
     fn test_perform_handshake_ok() {
-        let sender = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 18444);
-        let receiver = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 18444);
+        let node = MockNode::honest(BitcoinNetwork::Regtest).expect("Failed to start mock node");
+        let sender = node.addr();
+        let receiver = node.addr();
         let user_agent = "/test-bitcoin-client:0.1.0/".to_string();
         let start_height = 0;
         let res_handshake = perform_handshake(
@@ -47,9 +82,935 @@ mod tests {
         );
 
         assert!(
-            result.is_err(),
+            matches!(
+                result,
+                Err(HandshakeError::ConnectionFailed(ref e)) if e.kind() == ErrorKind::ConnectionRefused
+            ),
             "Handshake should fail due to connection error"
         );
-        assert_eq!(result.unwrap_err().kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    // Check two instances of the crate can handshake with each other
+    fn test_perform_handshake_with_listener_ok() {
+        let listener =
+            HandshakeListener::bind("127.0.0.1:0", HandshakeConfig::new(BitcoinNetwork::Regtest))
+                .expect("Failed to bind listener");
+        let add_listener = listener
+            .local_addr()
+            .expect("Failed to get listener address");
+        let server = std::thread::spawn(move || listener.accept());
+
+        let user_agent = "/test-bitcoin-client:0.1.0/".to_string();
+        let outcome = perform_handshake(
+            BitcoinNetwork::Regtest,
+            add_listener,
+            add_listener,
+            user_agent,
+            0,
+        )
+        .expect("Handshake should succeed");
+        let inbound = server
+            .join()
+            .expect("Listener thread panicked")
+            .expect("Listener handshake should succeed");
+
+        assert_eq!(inbound.remote_addr(), outcome.local_addr());
+    }
+
+    #[test]
+    // Check decoded messages flow both ways once the handshake is established
+    fn test_send_receive_after_handshake_ok() {
+        let listener =
+            HandshakeListener::bind("127.0.0.1:0", HandshakeConfig::new(BitcoinNetwork::Regtest))
+                .expect("Failed to bind listener");
+        let add_listener = listener
+            .local_addr()
+            .expect("Failed to get listener address");
+        let server = std::thread::spawn(move || {
+            let mut inbound = listener
+                .accept()
+                .expect("Listener handshake should succeed");
+            let message = inbound.receive().expect("Failed to receive message");
+            inbound.send(&message).expect("Failed to echo message");
+        });
+
+        let mut outcome = perform_handshake_with_config(
+            &HandshakeConfig::new(BitcoinNetwork::Regtest),
+            add_listener,
+            add_listener,
+        )
+        .expect("Handshake should succeed");
+        let mempool = NetworkMessage::Unknown {
+            command: "mempool".to_string(),
+            payload: Vec::new(),
+        };
+        outcome.send(&mempool).expect("Failed to send message");
+        assert_eq!(outcome.receive().expect("Failed to receive echo"), mempool);
+        server.join().expect("Listener thread panicked");
+    }
+
+    #[test]
+    // Check the preferences sent after verack are recorded on the connection
+    fn test_negotiated_features_with_listener_ok() {
+        let config = HandshakeConfig {
+            send_headers: true,
+            fee_filter: Some(1000),
+            ..HandshakeConfig::new(BitcoinNetwork::Regtest)
+        };
+        let listener =
+            HandshakeListener::bind("127.0.0.1:0", config).expect("Failed to bind listener");
+        let add_listener = listener
+            .local_addr()
+            .expect("Failed to get listener address");
+        let server = std::thread::spawn(move || {
+            let mut inbound = listener
+                .accept()
+                .expect("Listener handshake should succeed");
+            assert!(inbound.negotiated().wtxid_relay);
+            // Negotiation messages are no longer allowed
+            inbound
+                .send(&NetworkMessage::WtxidRelay)
+                .expect("Failed to send wtxidrelay");
+        });
+
+        let mut outcome = perform_handshake_with_config(
+            &HandshakeConfig::new(BitcoinNetwork::Regtest),
+            add_listener,
+            add_listener,
+        )
+        .expect("Handshake should succeed");
+        let negotiated = *outcome.negotiated();
+        assert!(negotiated.wtxid_relay && negotiated.addr_v2);
+        assert!(!negotiated.tx_reconciliation);
+
+        assert_eq!(
+            outcome.receive().expect("Failed to receive sendheaders"),
+            NetworkMessage::SendHeaders
+        );
+        outcome.receive().expect("Failed to receive feefilter");
+        assert!(outcome.negotiated().send_headers);
+        assert_eq!(outcome.negotiated().fee_filter, Some(1000));
+        assert!(matches!(
+            outcome.receive(),
+            Err(HandshakeError::UnexpectedCommand {
+                stage: HandshakeState::Established,
+                ..
+            })
+        ));
+        server.join().expect("Listener thread panicked");
+    }
+
+    #[test]
+    // Check two instances of the crate keep each other alive and measure latency
+    fn test_keep_alive_with_listener_ok() {
+        let config = KeepaliveConfig {
+            interval: Duration::from_millis(50),
+            timeout: Duration::from_secs(1),
+        };
+        let listener =
+            HandshakeListener::bind("127.0.0.1:0", HandshakeConfig::new(BitcoinNetwork::Regtest))
+                .expect("Failed to bind listener");
+        let add_listener = listener
+            .local_addr()
+            .expect("Failed to get listener address");
+        let server = std::thread::spawn(move || {
+            let mut inbound = listener
+                .accept()
+                .expect("Listener handshake should succeed");
+            let mut keepalive = Keepalive::new(config, inbound.features());
+            inbound.keep_alive(&mut keepalive, |_, _| true)
+        });
+
+        let mut outcome = perform_handshake_with_config(
+            &HandshakeConfig::new(BitcoinNetwork::Regtest),
+            add_listener,
+            add_listener,
+        )
+        .expect("Handshake should succeed");
+        let mut keepalive = Keepalive::new(config, outcome.features());
+        outcome
+            .keep_alive(&mut keepalive, |_, stats| stats.pongs_received < 3)
+            .expect("Keepalive should succeed");
+        let stats = keepalive.stats();
+        assert_eq!(stats.pongs_received, 3);
+        assert!(stats.min <= stats.last && stats.smoothed.is_some());
+
+        // The listener side notices the connection is gone
+        drop(outcome);
+        let result = server.join().expect("Listener thread panicked");
+        assert!(matches!(
+            result,
+            Err(HandshakeError::PeerDisconnected {
+                stage: HandshakeState::Established
+            })
+        ));
+    }
+
+    #[test]
+    // Check a message still arriving when the deadline passes is read whole
+    fn test_receive_until_slow_message_ok() {
+        let listener =
+            HandshakeListener::bind("127.0.0.1:0", HandshakeConfig::new(BitcoinNetwork::Regtest))
+                .expect("Failed to bind listener");
+        let add_listener = listener
+            .local_addr()
+            .expect("Failed to get listener address");
+        let message = NetworkMessage::Unknown {
+            command: "slow".to_string(),
+            payload: vec![0xab; 64],
+        };
+        let sent = message.clone();
+        let server = std::thread::spawn(move || {
+            let mut inbound = listener
+                .accept()
+                .expect("Listener handshake should succeed");
+            let bytes = sent
+                .to_bitcoin_message(BitcoinNetwork::Regtest)
+                .and_then(|message| message.serialize())
+                .expect("Failed to serialize message");
+            // The payload only follows once the deadline has passed
+            inbound
+                .stream()
+                .write_all(&bytes[..HEADER_SIZE])
+                .expect("Failed to write header");
+            std::thread::sleep(Duration::from_millis(300));
+            inbound
+                .stream()
+                .write_all(&bytes[HEADER_SIZE..])
+                .expect("Failed to write payload");
+            inbound
+        });
+
+        let mut outcome = perform_handshake_with_config(
+            &HandshakeConfig::new(BitcoinNetwork::Regtest),
+            add_listener,
+            add_listener,
+        )
+        .expect("Handshake should succeed");
+        let deadline = Instant::now() + Duration::from_millis(100);
+        assert_eq!(
+            outcome
+                .receive_until(deadline)
+                .expect("Message should be received whole"),
+            message
+        );
+        // A deadline without any message started is a clean timeout
+        let deadline = Instant::now() + Duration::from_millis(100);
+        assert!(matches!(
+            outcome.receive_until(deadline),
+            Err(HandshakeError::Timeout {
+                stage: HandshakeState::Established
+            })
+        ));
+        server.join().expect("Listener thread panicked");
+    }
+
+    #[test]
+    // Check a node leaving our ping unanswered is disconnected
+    fn test_keep_alive_error_ping_timeout() {
+        let node = MockNode::honest(BitcoinNetwork::Regtest).expect("Failed to start mock node");
+        let mut outcome = perform_handshake_with_config(
+            &HandshakeConfig::new(BitcoinNetwork::Regtest),
+            node.addr(),
+            node.addr(),
+        )
+        .expect("Handshake should succeed");
+        let config = KeepaliveConfig {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_millis(200),
+        };
+        let timeout = Some(Duration::from_secs(5));
+        outcome
+            .stream()
+            .set_read_timeout(timeout)
+            .expect("Failed to set timeout");
+        let mut keepalive = Keepalive::new(config, outcome.features());
+        let result = outcome.keep_alive(&mut keepalive, |_, _| true);
+        assert!(matches!(result, Err(HandshakeError::PingTimeout(_))));
+        assert_eq!(keepalive.stats().pings_sent, 1);
+        // The read timeout of the caller is given back
+        assert_eq!(
+            outcome
+                .stream()
+                .read_timeout()
+                .expect("Failed to get timeout"),
+            timeout
+        );
+    }
+
+    // Addresses known by the mock node, one of them only reachable over Tor
+    fn mock_addresses() -> Vec<AddrV2Entry> {
+        [
+            "1.2.3.4",
+            "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion",
+        ]
+        .into_iter()
+        .map(|addr| AddrV2Entry {
+            time: 1_700_000_000,
+            services: ServiceFlags::NODE_NETWORK | ServiceFlags::NODE_WITNESS,
+            addr: addr.parse().expect("Valid address"),
+            port: 8333,
+        })
+        .collect()
+    }
+
+    #[test]
+    // Check addresses come with addrv2 once both nodes sent sendaddrv2
+    fn test_request_addresses_addrv2_ok() {
+        let node = MockNode::start(MockNodeConfig {
+            addresses: mock_addresses(),
+            ..MockNodeConfig::new(BitcoinNetwork::Regtest)
+        })
+        .expect("Failed to start mock node");
+        let mut outcome = perform_handshake_with_config(
+            &HandshakeConfig::new(BitcoinNetwork::Regtest),
+            node.addr(),
+            node.addr(),
+        )
+        .expect("Handshake should succeed");
+        let addresses = outcome
+            .request_addresses(Duration::from_secs(1))
+            .expect("Addresses should be received");
+        assert_eq!(addresses, mock_addresses());
+    }
+
+    #[test]
+    // Check older nodes get no sendaddrv2 and answer with addr, IP addresses only
+    fn test_request_addresses_addr_ok() {
+        let node = MockNode::start(MockNodeConfig {
+            addresses: mock_addresses(),
+            ..MockNodeConfig::new(BitcoinNetwork::Regtest)
+        })
+        .expect("Failed to start mock node");
+        let config = HandshakeConfig {
+            protocol_version: 70015,
+            ..HandshakeConfig::new(BitcoinNetwork::Regtest)
+        };
+        let mut outcome = perform_handshake_with_config(&config, node.addr(), node.addr())
+            .expect("Handshake should succeed");
+        let addresses = outcome
+            .request_addresses(Duration::from_secs(1))
+            .expect("Addresses should be received");
+        assert_eq!(addresses, mock_addresses()[..1]);
+        // The short read timeout is not left behind
+        assert_eq!(
+            outcome
+                .stream()
+                .read_timeout()
+                .expect("Failed to get timeout"),
+            None
+        );
+    }
+
+    #[test]
+    // Check the node announcing its own address is not taken for its answer
+    fn test_request_addresses_self_announcement_ok() {
+        let node = MockNode::start(MockNodeConfig {
+            addresses: mock_addresses()[..1].to_vec(),
+            announce_self: true,
+            ..MockNodeConfig::new(BitcoinNetwork::Regtest)
+        })
+        .expect("Failed to start mock node");
+        let mut outcome = perform_handshake_with_config(
+            &HandshakeConfig::new(BitcoinNetwork::Regtest),
+            node.addr(),
+            node.addr(),
+        )
+        .expect("Handshake should succeed");
+        let addresses = outcome
+            .request_addresses(Duration::from_secs(1))
+            .expect("Addresses should be received");
+        assert_eq!(addresses, mock_addresses()[..1]);
+    }
+
+    #[test]
+    // Check headers are downloaded in batches up to the height the node advertised
+    fn test_sync_headers_ok() {
+        let headers = regtest_headers(2500);
+        let node = MockNode::start(MockNodeConfig {
+            start_height: 2500,
+            headers: headers.clone(),
+            ..MockNodeConfig::new(BitcoinNetwork::Regtest)
+        })
+        .expect("Failed to start mock node");
+        let mut outcome = perform_handshake_with_config(
+            &HandshakeConfig::new(BitcoinNetwork::Regtest),
+            node.addr(),
+            node.addr(),
+        )
+        .expect("Handshake should succeed");
+
+        let mut chain = HeaderChain::new(BitcoinNetwork::Regtest).expect("Failed to create chain");
+        let height = outcome
+            .sync_headers(&mut chain, Duration::from_secs(5))
+            .expect("Headers should be synced");
+        assert_eq!(height, 2500);
+        assert_eq!(chain.tip_hash(), headers[2499].block_hash());
+    }
+
+    #[test]
+    // Check headers that do not meet their target are refused
+    fn test_sync_headers_error_proof_of_work() {
+        let mut headers = regtest_headers(10);
+        // Regtest targets are met by one hash out of two, find a nonce missing it
+        let pow_limit = BitcoinNetwork::Regtest.params().pow_limit;
+        while headers[9].check_proof_of_work(&pow_limit).is_ok() {
+            headers[9].nonce += 1;
+        }
+        let node = MockNode::start(MockNodeConfig {
+            start_height: 10,
+            headers,
+            ..MockNodeConfig::new(BitcoinNetwork::Regtest)
+        })
+        .expect("Failed to start mock node");
+        let mut outcome = perform_handshake_with_config(
+            &HandshakeConfig::new(BitcoinNetwork::Regtest),
+            node.addr(),
+            node.addr(),
+        )
+        .expect("Handshake should succeed");
+
+        let mut chain = HeaderChain::new(BitcoinNetwork::Regtest).expect("Failed to create chain");
+        assert!(matches!(
+            outcome.sync_headers(&mut chain, Duration::from_secs(5)),
+            Err(HandshakeError::InvalidHeader { .. })
+        ));
+        assert_eq!(chain.height(), 0);
+        assert_eq!(
+            outcome
+                .stream()
+                .read_timeout()
+                .expect("Failed to get timeout"),
+            None
+        );
+    }
+
+    // Transaction spending a made-up output, with or without witness data
+    fn mock_transaction(witness: Vec<Vec<u8>>) -> Transaction {
+        Transaction {
+            version: 2,
+            inputs: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: [7u8; 32],
+                    vout: 0,
+                },
+                script_sig: Vec::new(),
+                sequence: u32::MAX,
+                witness,
+            }],
+            outputs: vec![TxOut {
+                value: 1000,
+                script_pubkey: vec![0x51],
+            }],
+            lock_time: 0,
+        }
+    }
+
+    // Regtest block holding a mock transaction
+    fn mock_block(witness: Vec<Vec<u8>>) -> Block {
+        let genesis = BitcoinNetwork::Regtest
+            .params()
+            .genesis_header
+            .expect("Regtest genesis header is known");
+        regtest_block(&genesis, vec![mock_transaction(witness)])
+    }
+
+    #[test]
+    // Check a block is downloaded and its transactions match its merkle root
+    fn test_get_block_ok() {
+        let block = mock_block(Vec::new());
+        let node = MockNode::start(MockNodeConfig {
+            blocks: vec![block.clone()],
+            ..MockNodeConfig::new(BitcoinNetwork::Regtest)
+        })
+        .expect("Failed to start mock node");
+        let mut outcome = perform_handshake_with_config(
+            &HandshakeConfig::new(BitcoinNetwork::Regtest),
+            node.addr(),
+            node.addr(),
+        )
+        .expect("Handshake should succeed");
+
+        let received = outcome
+            .get_block(block.block_hash(), Duration::from_secs(1))
+            .expect("Block should be received");
+        assert_eq!(received, block);
+        assert_eq!(
+            received.transactions[1].txid(),
+            block.transactions[1].txid()
+        );
+    }
+
+    #[test]
+    // Check a block the node does not have is reported as not found
+    fn test_get_block_error_not_found() {
+        let node = MockNode::honest(BitcoinNetwork::Regtest).expect("Failed to start mock node");
+        let mut outcome = perform_handshake_with_config(
+            &HandshakeConfig::new(BitcoinNetwork::Regtest),
+            node.addr(),
+            node.addr(),
+        )
+        .expect("Handshake should succeed");
+
+        assert!(matches!(
+            outcome.get_block([1u8; 32], Duration::from_secs(1)),
+            Err(HandshakeError::NotFound(_))
+        ));
+        assert_eq!(
+            outcome
+                .stream()
+                .read_timeout()
+                .expect("Failed to get timeout"),
+            None
+        );
+    }
+
+    #[test]
+    // Check a block whose transactions were tampered with is refused
+    fn test_get_block_error_merkle_root() {
+        let mut block = mock_block(Vec::new());
+        block.transactions[1].outputs[0].value += 1;
+        let node = MockNode::start(MockNodeConfig {
+            blocks: vec![block.clone()],
+            ..MockNodeConfig::new(BitcoinNetwork::Regtest)
+        })
+        .expect("Failed to start mock node");
+        let mut outcome = perform_handshake_with_config(
+            &HandshakeConfig::new(BitcoinNetwork::Regtest),
+            node.addr(),
+            node.addr(),
+        )
+        .expect("Handshake should succeed");
+
+        assert!(matches!(
+            outcome.get_block(block.block_hash(), Duration::from_secs(1)),
+            Err(HandshakeError::InvalidBlock {
+                reason: "Merkle root mismatch",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    // Check a block with uncommitted witness data is refused when asked with witnesses
+    fn test_get_block_error_witness() {
+        let block = mock_block(vec![vec![0x01; 72]]);
+        let node = MockNode::start(MockNodeConfig {
+            blocks: vec![block.clone()],
+            ..MockNodeConfig::new(BitcoinNetwork::Regtest)
+        })
+        .expect("Failed to start mock node");
+        let mut outcome = perform_handshake_with_config(
+            &HandshakeConfig::new(BitcoinNetwork::Regtest),
+            node.addr(),
+            node.addr(),
+        )
+        .expect("Handshake should succeed");
+
+        assert!(matches!(
+            outcome.get_block(block.block_hash(), Duration::from_secs(1)),
+            Err(HandshakeError::InvalidBlock {
+                reason: "Witness data without commitment",
+                ..
+            })
+        ));
+    }
+
+    // Broadcast a mock transaction to a mock node following the given policy
+    fn broadcast_with(
+        tx_policy: MockTxPolicy,
+        config: &HandshakeConfig,
+    ) -> Result<BroadcastReport, HandshakeError> {
+        let node = MockNode::start(MockNodeConfig {
+            tx_policy,
+            ..MockNodeConfig::new(BitcoinNetwork::Regtest)
+        })
+        .expect("Failed to start mock node");
+        let raw_tx = mock_transaction(vec![vec![0x01; 72]])
+            .serialize()
+            .expect("Failed to serialize");
+        broadcast_tx(config, node.addr(), &raw_tx, Duration::from_millis(300))
+    }
+
+    #[test]
+    // Check a transaction is announced by wtxid, served and announced back
+    fn test_broadcast_tx_relayed_ok() {
+        let transaction = mock_transaction(vec![vec![0x01; 72]]);
+        let report = broadcast_with(
+            MockTxPolicy::Relay,
+            &HandshakeConfig::new(BitcoinNetwork::Regtest),
+        )
+        .expect("Broadcast should succeed");
+        assert_eq!(report.status, BroadcastStatus::Relayed);
+        assert!(report.by_wtxid);
+        assert_eq!(report.txid, transaction.txid());
+        assert_eq!(report.wtxid, transaction.wtxid());
+    }
+
+    #[test]
+    // Check older nodes get the transaction announced by txid
+    fn test_broadcast_tx_by_txid_ok() {
+        let config = HandshakeConfig {
+            protocol_version: 70015,
+            ..HandshakeConfig::new(BitcoinNetwork::Regtest)
+        };
+        let report =
+            broadcast_with(MockTxPolicy::Relay, &config).expect("Broadcast should succeed");
+        assert_eq!(report.status, BroadcastStatus::Relayed);
+        assert!(!report.by_wtxid);
+    }
+
+    #[test]
+    // Check the report tells whether the node asked for the transaction when it stays silent
+    fn test_broadcast_tx_silent_ok() {
+        let config = HandshakeConfig::new(BitcoinNetwork::Regtest);
+        let report =
+            broadcast_with(MockTxPolicy::Accept, &config).expect("Broadcast should succeed");
+        assert_eq!(report.status, BroadcastStatus::Requested);
+        let report =
+            broadcast_with(MockTxPolicy::Ignore, &config).expect("Broadcast should succeed");
+        assert_eq!(report.status, BroadcastStatus::Ignored);
+    }
+
+    #[test]
+    // Check a reject message for the transaction is reported
+    fn test_broadcast_tx_error_rejected() {
+        let report = broadcast_with(
+            MockTxPolicy::Reject,
+            &HandshakeConfig::new(BitcoinNetwork::Regtest),
+        )
+        .expect("Broadcast should succeed");
+        assert_eq!(
+            report.status,
+            BroadcastStatus::Rejected {
+                code: REJECT_INVALID,
+                reason: "bad-txns-inputs-missingorspent".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    // Check a listener sharing our nonces refuses our own outbound connection
+    fn test_perform_handshake_error_self_connection() {
+        let config = HandshakeConfig::new(BitcoinNetwork::Regtest);
+        let listener = HandshakeListener::bind("127.0.0.1:0", config.clone())
+            .expect("Failed to bind listener");
+        let add_listener = listener
+            .local_addr()
+            .expect("Failed to get listener address");
+        let server = std::thread::spawn(move || listener.accept());
+
+        let outbound = perform_handshake_with_config(&config, add_listener, add_listener);
+        let inbound = server.join().expect("Listener thread panicked");
+
+        assert!(matches!(inbound, Err(HandshakeError::SelfConnection)));
+        assert!(outbound.is_err(), "Handshake should fail");
+        assert!(config.nonces.is_empty());
+    }
+
+    #[test]
+    // Check the services advertised by the remote node are kept
+    fn test_perform_handshake_custom_services_ok() {
+        let node = MockNode::start(MockNodeConfig {
+            services: ServiceFlags::from_bits_retain(0x409),
+            ..MockNodeConfig::new(BitcoinNetwork::Regtest)
+        })
+        .expect("Failed to start mock node");
+
+        let outcome = perform_handshake(
+            BitcoinNetwork::Regtest,
+            node.addr(),
+            node.addr(),
+            "/test-bitcoin-client:0.1.0/".to_string(),
+            0,
+        )
+        .expect("Handshake should succeed");
+        assert_eq!(outcome.peer_version().services().bits(), 0x409);
+    }
+
+    #[test]
+    // Check a node offering every required service is accepted
+    fn test_perform_handshake_required_services_ok() {
+        let node = MockNode::honest(BitcoinNetwork::Regtest).expect("Failed to start mock node");
+        let config = HandshakeConfig {
+            required_services: ServiceFlags::NODE_NETWORK | ServiceFlags::NODE_WITNESS,
+            ..HandshakeConfig::new(BitcoinNetwork::Regtest)
+        };
+
+        let result = perform_handshake_with_config(&config, node.addr(), node.addr());
+        assert!(result.is_ok(), "Handshake should succeed");
+    }
+
+    #[test]
+    // Check a node lacking a required service is rejected
+    fn test_perform_handshake_error_missing_services() {
+        let node = MockNode::start(MockNodeConfig {
+            services: ServiceFlags::NODE_NETWORK_LIMITED | ServiceFlags::NODE_WITNESS,
+            ..MockNodeConfig::new(BitcoinNetwork::Regtest)
+        })
+        .expect("Failed to start mock node");
+        let config = HandshakeConfig {
+            required_services: ServiceFlags::NODE_NETWORK | ServiceFlags::NODE_WITNESS,
+            ..HandshakeConfig::new(BitcoinNetwork::Regtest)
+        };
+
+        assert!(matches!(
+            perform_handshake_with_config(&config, node.addr(), node.addr()),
+            Err(HandshakeError::MissingServices { missing }) if missing == ServiceFlags::NODE_NETWORK
+        ));
+    }
+
+    #[test]
+    // Check an older but still supported node is accepted at its own version
+    fn test_perform_handshake_older_version_ok() {
+        let node = MockNode::start(MockNodeConfig {
+            version: 70012,
+            ..MockNodeConfig::new(BitcoinNetwork::Regtest)
+        })
+        .expect("Failed to start mock node");
+        let config = HandshakeConfig {
+            protocol_version: 70015,
+            ..HandshakeConfig::new(BitcoinNetwork::Regtest)
+        };
+
+        let outcome = perform_handshake_with_config(&config, node.addr(), node.addr())
+            .expect("Handshake should succeed");
+        assert_eq!(outcome.negotiated_version(), 70012);
+        assert!(outcome.features().send_headers);
+        assert!(!outcome.features().fee_filter);
+    }
+
+    #[test]
+    // Check a node older than the network's minimum version is rejected
+    fn test_perform_handshake_error_obsolete_version() {
+        let node = MockNode::start(MockNodeConfig {
+            version: 31402,
+            ..MockNodeConfig::new(BitcoinNetwork::Regtest)
+        })
+        .expect("Failed to start mock node");
+
+        assert!(matches!(
+            handshake_with(&node),
+            Err(HandshakeError::UnsupportedVersion(31402))
+        ));
+    }
+
+    #[test]
+    // Check the user agent advertised by the remote node is decoded
+    fn test_perform_handshake_custom_user_agent_ok() {
+        let node = MockNode::start(MockNodeConfig {
+            user_agent: "/Satoshi:26.1.0(sensor)/".to_string(),
+            ..MockNodeConfig::new(BitcoinNetwork::Regtest)
+        })
+        .expect("Failed to start mock node");
+
+        let outcome = perform_handshake(
+            BitcoinNetwork::Regtest,
+            node.addr(),
+            node.addr(),
+            "/test-bitcoin-client:0.1.0/".to_string(),
+            0,
+        )
+        .expect("Handshake should succeed");
+        assert_eq!(
+            outcome.peer_version().user_agent(),
+            "/Satoshi:26.1.0(sensor)/"
+        );
+    }
+
+    #[test]
+    // Check a slow node is still accepted within the time limits
+    fn test_perform_handshake_delayed_reply_ok() {
+        let node = MockNode::with_behaviour(
+            BitcoinNetwork::Regtest,
+            MockBehaviour::Delayed(Duration::from_millis(100)),
+        )
+        .expect("Failed to start mock node");
+
+        assert!(handshake_with(&node).is_ok(), "Handshake should succeed");
+    }
+
+    #[test]
+    // Check a node that never replies makes the handshake time out
+    fn test_perform_handshake_error_no_reply() {
+        let node = MockNode::with_behaviour(BitcoinNetwork::Regtest, MockBehaviour::Silent)
+            .expect("Failed to start mock node");
+
+        assert!(matches!(
+            handshake_with(&node),
+            Err(HandshakeError::Timeout {
+                stage: HandshakeState::SentVersion
+            })
+        ));
+    }
+
+    #[test]
+    // Check a node from another network is rejected
+    fn test_perform_handshake_error_wrong_magic() {
+        let node = MockNode::with_behaviour(
+            BitcoinNetwork::Regtest,
+            MockBehaviour::WrongMagic(BitcoinNetwork::Mainnet),
+        )
+        .expect("Failed to start mock node");
+
+        assert!(matches!(
+            handshake_with(&node),
+            Err(HandshakeError::WrongNetworkMagic { got, .. }) if got == BitcoinNetwork::Mainnet.as_u32()
+        ));
+    }
+
+    #[test]
+    // Check a corrupted reply is rejected
+    fn test_perform_handshake_error_bad_checksum() {
+        let node = MockNode::with_behaviour(BitcoinNetwork::Regtest, MockBehaviour::BadChecksum)
+            .expect("Failed to start mock node");
+
+        assert!(matches!(
+            handshake_with(&node),
+            Err(HandshakeError::BadChecksum)
+        ));
+    }
+
+    #[test]
+    // Check a node closing the connection in the middle of a header is reported
+    fn test_perform_handshake_error_truncated_header() {
+        let node =
+            MockNode::with_behaviour(BitcoinNetwork::Regtest, MockBehaviour::TruncatedHeader)
+                .expect("Failed to start mock node");
+
+        assert!(matches!(
+            handshake_with(&node),
+            Err(HandshakeError::PeerDisconnected {
+                stage: HandshakeState::SentVersion
+            })
+        ));
+    }
+
+    #[test]
+    // Check an oversized payload is refused before being read
+    fn test_perform_handshake_error_oversized_payload() {
+        let node =
+            MockNode::with_behaviour(BitcoinNetwork::Regtest, MockBehaviour::OversizedPayload)
+                .expect("Failed to start mock node");
+
+        assert!(matches!(
+            handshake_with(&node),
+            Err(HandshakeError::PayloadTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    // Check a custom signet handshake uses the magic derived from its challenge
+    fn test_perform_handshake_custom_signet_ok() {
+        let network = BitcoinNetwork::custom_signet(
+            "5121020000000000000000000000000000000000000000000000000000000000000151ae",
+        )
+        .expect("Failed to derive signet magic");
+        let node = MockNode::honest(network).expect("Failed to start mock node");
+
+        let outcome = perform_handshake(
+            network,
+            node.addr(),
+            node.addr(),
+            "/test-bitcoin-client:0.1.0/".to_string(),
+            0,
+        );
+        assert!(outcome.is_ok(), "Handshake should succeed");
+
+        // The default signet magic is rejected by the custom signet node
+        let node = MockNode::honest(network).expect("Failed to start mock node");
+        let result = perform_handshake(
+            BitcoinNetwork::Signet,
+            node.addr(),
+            node.addr(),
+            "/test-bitcoin-client:0.1.0/".to_string(),
+            0,
+        );
+        assert!(result.is_err(), "Handshake should fail");
+    }
+
+    #[test]
+    // Check a chain described in TOML can be used like a built-in network
+    fn test_perform_handshake_custom_params_ok() {
+        let params = NetworkParams::from_toml(
+            r#"
+            name = "litecoin"
+            magic = "fbc0b6db"
+            port = 9333
+            genesis_hash = "12a765e31ffd4059bada1e25190f6e98c99d9714d334efa41a195a7e7e04bfe2"
+            pubkey_address_prefix = 48
+            script_address_prefix = 50
+            "#,
+        )
+        .expect("Failed to read params");
+        let network = BitcoinNetwork::custom(params);
+        let node = MockNode::honest(network).expect("Failed to start mock node");
+
+        let outcome = perform_handshake(
+            network,
+            node.addr(),
+            node.addr(),
+            "/test-bitcoin-client:0.1.0/".to_string(),
+            0,
+        );
+        assert!(outcome.is_ok(), "Handshake should succeed");
+        assert_eq!(network.to_string(), "litecoin");
+    }
+
+    // Detection settings with short time limits
+    fn detection_config() -> DetectionConfig {
+        DetectionConfig {
+            timeouts: HandshakeTimeouts {
+                version: Duration::from_millis(500),
+                verack: Duration::from_millis(500),
+                ..HandshakeTimeouts::default()
+            },
+            ..DetectionConfig::new()
+        }
+    }
+
+    #[test]
+    // Check the network of a node dropping other magics is found by trying each of them
+    fn test_detect_network_after_dropped_attempts_ok() {
+        let node = MockNode::honest(BitcoinNetwork::Testnet4).expect("Failed to start mock node");
+
+        let detection =
+            detect_network(node.addr(), &detection_config()).expect("Detection should succeed");
+        assert_eq!(detection.network, Some(BitcoinNetwork::Testnet4));
+        assert_eq!(detection.method, Some(DetectionMethod::Handshake));
+        assert_eq!(detection.attempts.last(), Some(&BitcoinNetwork::Testnet4));
+        assert!(detection.peer_version.is_some());
+    }
+
+    #[test]
+    // Check the network of a node answering with its own magic is read from its reply
+    fn test_detect_network_from_reply_magic_ok() {
+        let node = MockNode::with_behaviour(
+            BitcoinNetwork::Mainnet,
+            MockBehaviour::WrongMagic(BitcoinNetwork::Signet),
+        )
+        .expect("Failed to start mock node");
+
+        let detection =
+            detect_network(node.addr(), &detection_config()).expect("Detection should succeed");
+        assert_eq!(detection.network, Some(BitcoinNetwork::Signet));
+        assert_eq!(detection.magic, Some(BitcoinNetwork::Signet.magic()));
+        assert_eq!(detection.method, Some(DetectionMethod::ReplyMagic));
+        assert_eq!(detection.attempts, vec![BitcoinNetwork::Mainnet]);
+    }
+
+    #[test]
+    // Check a node of an unknown network is reported as such
+    fn test_detect_network_unknown_ok() {
+        let node =
+            MockNode::honest(BitcoinNetwork::custom_signet("51").expect("Invalid challenge"))
+                .expect("Failed to start mock node");
+
+        let detection =
+            detect_network(node.addr(), &detection_config()).expect("Detection should succeed");
+        assert_eq!(detection.network, None);
+        assert_eq!(detection.method, None);
+        assert_eq!(detection.attempts.len(), 5);
     }
 }